@@ -1,9 +1,193 @@
 import type { Principal } from '@dfinity/principal';
+export type BucketStatus = { 'Installing' : null } |
+  { 'Active' : null } |
+  { 'Full' : null } |
+  { 'Draining' : null } |
+  { 'Retired' : null };
+export interface BucketUpgrade {
+  'canister_id' : Principal,
+  'status' : BucketUpgradeStatus,
+  'attempts' : number,
+  'last_error' : [] | [string],
+  'upgraded_at' : [] | [bigint],
+}
+export type BucketUpgradeStatus = { 'Pending' : null } |
+  { 'Upgrading' : Lease } |
+  { 'Upgraded' : null } |
+  { 'Failed' : null };
+export type CapacityMode = { 'Entries' : null } |
+  { 'Bytes' : bigint };
+export interface DeadTask { 'task' : Task, 'died_at' : bigint }
+export interface GlobalEntryId { 'id' : bigint, 'bucket' : Principal }
+export interface IndexInitArgs {
+  'admins' : Array<Principal>,
+  'bucket_controllers' : Array<Principal>,
+  'desired_free_slots' : [] | [bigint],
+  'reindex_interval' : [] | [bigint],
+  'cycles_per_bucket' : [] | [bigint],
+  'bucket_max_entries' : [] | [bigint],
+  'bucket_capacity_mode' : [] | [CapacityMode],
+  'indexing_strategy' : [] | [IndexingStrategy],
+  'index_sync_mode' : [] | [IndexSyncMode],
+  'pull_batch_size' : [] | [number],
+}
+export type IndexSyncMode = { 'Push' : null } |
+  { 'Pull' : null };
+export type IndexingStrategy = { 'BalancedLoad' : null } |
+  { 'FillFirst' : null } |
+  { 'TagAffinity' : null };
+export interface IndexSettings {
+  'desired_free_slots' : bigint,
+  'reindex_interval' : bigint,
+  'indexing_strategy' : IndexingStrategy,
+  'cycles_per_bucket' : bigint,
+  'bucket_max_entries' : bigint,
+  'bucket_capacity_mode' : CapacityMode,
+  'index_sync_mode' : IndexSyncMode,
+  'pull_batch_size' : number,
+}
+export interface SettingsChange {
+  'changed_at' : bigint,
+  'changed_by' : Principal,
+  'before' : IndexSettings,
+  'after' : IndexSettings,
+}
+export type SettingsResult = { 'Ok' : IndexSettings } |
+  { 'Err' : string };
+export interface SettingsUpdate {
+  'desired_free_slots' : [] | [bigint],
+  'reindex_interval' : [] | [bigint],
+  'indexing_strategy' : [] | [IndexingStrategy],
+  'cycles_per_bucket' : [] | [bigint],
+  'bucket_max_entries' : [] | [bigint],
+  'bucket_capacity_mode' : [] | [CapacityMode],
+  'index_sync_mode' : [] | [IndexSyncMode],
+  'pull_batch_size' : [] | [number],
+}
+export type IndexTask = { 'SpawnBucket' : bigint } |
+  { 'PushRoles' : Principal } |
+  { 'PushReservationKey' : Principal };
+export interface Lease { 'id' : bigint, 'expires_at' : bigint }
+export interface OrphanedCanister {
+  'canister_id' : Principal,
+  'created_at' : bigint,
+  'install_attempts' : number,
+  'resumable' : boolean,
+}
+export type Result = { 'Ok' : null } |
+  { 'Err' : string };
+export type Role = { 'Reader' : null } |
+  { 'Moderator' : null } |
+  { 'Admin' : null } |
+  { 'Owner' : null };
+export interface RoleAssignment {
+  'principal' : Principal,
+  'role' : Role,
+  'scope' : RoleScope,
+}
+export type PostResult = { 'Ok' : GlobalEntryId } |
+  { 'Queued' : bigint } |
+  { 'Err' : string };
+export type QueuedWriteStatus = { 'Pending' : null } |
+  { 'Stored' : GlobalEntryId } |
+  { 'Rejected' : string };
+export interface SlotReservation {
+  'id' : bigint,
+  'bucket' : Principal,
+  'holder' : Principal,
+  'expires_at' : bigint,
+  'signature' : Array<number>,
+}
+export interface SpawnedBucketCanister {
+  'canister_id' : Principal,
+  'created_at' : bigint,
+  'cycles_sent' : bigint,
+  'wasm_version' : [] | [string],
+  'capacity' : bigint,
+  'status' : BucketStatus,
+  'roles_version' : bigint,
+}
+export type RoleScope = { 'Global' : null } |
+  { 'Tag' : string };
+export type RolloutStatus = { 'Running' : null } |
+  { 'Completed' : null } |
+  { 'Halted' : string } |
+  { 'Cancelled' : null };
+export interface Task {
+  'id' : bigint,
+  'kind' : IndexTask,
+  'attempts' : number,
+  'next_attempt_at' : bigint,
+  'lease' : [] | [Lease],
+  'last_error' : [] | [string],
+}
+export interface UpgradeArgs {
+  'wasm_version' : string,
+  'batch_size' : [] | [number],
+  'max_attempts' : [] | [number],
+}
+export interface UpgradeRollout {
+  'wasm_version' : string,
+  'started_at' : bigint,
+  'started_by' : Principal,
+  'finished_at' : [] | [bigint],
+  'batch_size' : number,
+  'max_attempts' : number,
+  'status' : RolloutStatus,
+  'batches_started' : bigint,
+  'buckets' : Array<BucketUpgrade>,
+}
+export type WasmUploadResult = { 'Ok' : bigint } |
+  { 'Err' : string };
+export interface WasmVersion {
+  'name' : string,
+  'sha256' : Array<number>,
+  'size' : bigint,
+  'uploaded_at' : bigint,
+  'uploaded_by' : Principal,
+}
+export type WasmVersionResult = { 'Ok' : WasmVersion } |
+  { 'Err' : string };
 export interface _SERVICE {
   'addContentModerator' : (arg_0: Principal) => Promise<undefined>,
+  'cancelBucketUpgrade' : () => Promise<Result>,
+  'commitBucketWasmUpload' : (arg_0: string) => Promise<WasmVersionResult>,
+  'getActiveBucketWasm' : () => Promise<[] | [WasmVersion]>,
   'getAllIndexes' : () => Promise<Array<Principal>>,
+  'getBucketIndexVersion' : (arg_0: Principal) => Promise<[] | [bigint]>,
+  'getBucketUpgradeStatus' : () => Promise<[] | [UpgradeRollout]>,
+  'getDeadTasks' : () => Promise<Array<DeadTask>>,
   'getGlobalIndex' : () => Promise<Array<Array<string>>>,
   'getIndexByTag' : (arg_0: string) => Promise<Array<Principal>>,
   'getMetrics' : () => Promise<string>,
-  'getUploadOrder' : () => Promise<Array<Principal>>,
+  'getOrphanedCanisters' : () => Promise<Array<OrphanedCanister>>,
+  'getQueuedWrite' : (arg_0: bigint) => Promise<[] | [QueuedWriteStatus]>,
+  'getSettings' : () => Promise<IndexSettings>,
+  'getSettingsHistory' : () => Promise<Array<SettingsChange>>,
+  'getSpawnedBuckets' : () => Promise<Array<SpawnedBucketCanister>>,
+  'getTasks' : () => Promise<Array<Task>>,
+  'getUploadOrder' : (arg_0: [] | [string]) => Promise<Array<Principal>>,
+  'grantRole' : (arg_0: RoleAssignment) => Promise<Result>,
+  'listContentModerators' : () => Promise<Array<Principal>>,
+  'listBucketWasms' : () => Promise<Array<WasmVersion>>,
+  'listRoles' : () => Promise<Array<RoleAssignment>>,
+  'removeContentModerator' : (arg_0: Principal) => Promise<boolean>,
+  'postContent' : (arg_0: string, arg_1: string) => Promise<PostResult>,
+  'reserveUpload' : (arg_0: [] | [string]) => Promise<[] | [SlotReservation]>,
+  'resolveEntry' : (arg_0: GlobalEntryId) => Promise<[] | [Principal]>,
+  'removeBucketWasm' : (arg_0: string) => Promise<Result>,
+  'retryDeadTask' : (arg_0: bigint) => Promise<boolean>,
+  'revokeRole' : (arg_0: RoleAssignment) => Promise<Result>,
+  'setActiveBucketWasm' : (arg_0: string) => Promise<Result>,
+  'setBucketStatus' : (arg_0: Principal, arg_1: BucketStatus) => Promise<
+      Result
+    >,
+  'startBucketUpgrade' : (arg_0: UpgradeArgs) => Promise<Result>,
+  'startBucketWasmUpload' : (arg_0: string, arg_1: Array<number>) => Promise<
+      Result
+    >,
+  'updateSettings' : (arg_0: SettingsUpdate) => Promise<SettingsResult>,
+  'uploadBucketWasmChunk' : (arg_0: string, arg_1: Array<number>) => Promise<
+      WasmUploadResult
+    >,
 }
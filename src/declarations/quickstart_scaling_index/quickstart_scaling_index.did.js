@@ -1,11 +1,227 @@
 export const idlFactory = ({ IDL }) => {
+  const Role = IDL.Variant({
+    'Reader' : IDL.Null,
+    'Moderator' : IDL.Null,
+    'Admin' : IDL.Null,
+    'Owner' : IDL.Null,
+  });
+  const RoleScope = IDL.Variant({ 'Global' : IDL.Null, 'Tag' : IDL.Text });
+  const RoleAssignment = IDL.Record({
+    'principal' : IDL.Principal,
+    'role' : Role,
+    'scope' : RoleScope,
+  });
+  const Result = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : IDL.Text });
+  const IndexingStrategy = IDL.Variant({
+    'BalancedLoad' : IDL.Null,
+    'FillFirst' : IDL.Null,
+    'TagAffinity' : IDL.Null,
+  });
+  const IndexSyncMode = IDL.Variant({ 'Push' : IDL.Null, 'Pull' : IDL.Null });
+  const CapacityMode = IDL.Variant({ 'Entries' : IDL.Null, 'Bytes' : IDL.Nat64 });
+  const IndexSettings = IDL.Record({
+    'desired_free_slots' : IDL.Nat,
+    'reindex_interval' : IDL.Nat64,
+    'indexing_strategy' : IndexingStrategy,
+    'cycles_per_bucket' : IDL.Nat64,
+    'bucket_max_entries' : IDL.Nat64,
+    'bucket_capacity_mode' : CapacityMode,
+    'index_sync_mode' : IndexSyncMode,
+    'pull_batch_size' : IDL.Nat32,
+  });
+  const SettingsUpdate = IDL.Record({
+    'desired_free_slots' : IDL.Opt(IDL.Nat),
+    'reindex_interval' : IDL.Opt(IDL.Nat64),
+    'indexing_strategy' : IDL.Opt(IndexingStrategy),
+    'cycles_per_bucket' : IDL.Opt(IDL.Nat64),
+    'bucket_max_entries' : IDL.Opt(IDL.Nat64),
+    'bucket_capacity_mode' : IDL.Opt(CapacityMode),
+    'index_sync_mode' : IDL.Opt(IndexSyncMode),
+    'pull_batch_size' : IDL.Opt(IDL.Nat32),
+  });
+  const SettingsChange = IDL.Record({
+    'changed_at' : IDL.Nat64,
+    'changed_by' : IDL.Principal,
+    'before' : IndexSettings,
+    'after' : IndexSettings,
+  });
+  const SettingsResult = IDL.Variant({ 'Ok' : IndexSettings, 'Err' : IDL.Text });
+  const GlobalEntryId = IDL.Record({ 'id' : IDL.Nat64, 'bucket' : IDL.Principal });
+  const PostResult = IDL.Variant({
+    'Ok' : GlobalEntryId,
+    'Queued' : IDL.Nat64,
+    'Err' : IDL.Text,
+  });
+  const QueuedWriteStatus = IDL.Variant({
+    'Pending' : IDL.Null,
+    'Stored' : GlobalEntryId,
+    'Rejected' : IDL.Text,
+  });
+  const SlotReservation = IDL.Record({
+    'id' : IDL.Nat64,
+    'bucket' : IDL.Principal,
+    'holder' : IDL.Principal,
+    'expires_at' : IDL.Nat64,
+    'signature' : IDL.Vec(IDL.Nat8),
+  });
+  const WasmVersion = IDL.Record({
+    'name' : IDL.Text,
+    'sha256' : IDL.Vec(IDL.Nat8),
+    'size' : IDL.Nat64,
+    'uploaded_at' : IDL.Nat64,
+    'uploaded_by' : IDL.Principal,
+  });
+  const WasmUploadResult = IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : IDL.Text });
+  const WasmVersionResult = IDL.Variant({ 'Ok' : WasmVersion, 'Err' : IDL.Text });
+  const BucketStatus = IDL.Variant({
+    'Installing' : IDL.Null,
+    'Active' : IDL.Null,
+    'Full' : IDL.Null,
+    'Draining' : IDL.Null,
+    'Retired' : IDL.Null,
+  });
+  const SpawnedBucketCanister = IDL.Record({
+    'canister_id' : IDL.Principal,
+    'created_at' : IDL.Nat64,
+    'cycles_sent' : IDL.Nat64,
+    'wasm_version' : IDL.Opt(IDL.Text),
+    'capacity' : IDL.Nat64,
+    'status' : BucketStatus,
+    'roles_version' : IDL.Nat64,
+  });
+  const OrphanedCanister = IDL.Record({
+    'canister_id' : IDL.Principal,
+    'created_at' : IDL.Nat64,
+    'install_attempts' : IDL.Nat32,
+    'resumable' : IDL.Bool,
+  });
+  const IndexTask = IDL.Variant({
+    'SpawnBucket' : IDL.Nat64,
+    'PushRoles' : IDL.Principal,
+    'PushReservationKey' : IDL.Principal,
+  });
+  const Lease = IDL.Record({ 'id' : IDL.Nat64, 'expires_at' : IDL.Nat64 });
+  const Task = IDL.Record({
+    'id' : IDL.Nat64,
+    'kind' : IndexTask,
+    'attempts' : IDL.Nat32,
+    'next_attempt_at' : IDL.Nat64,
+    'lease' : IDL.Opt(Lease),
+    'last_error' : IDL.Opt(IDL.Text),
+  });
+  const DeadTask = IDL.Record({ 'task' : Task, 'died_at' : IDL.Nat64 });
+  const UpgradeArgs = IDL.Record({
+    'wasm_version' : IDL.Text,
+    'batch_size' : IDL.Opt(IDL.Nat32),
+    'max_attempts' : IDL.Opt(IDL.Nat32),
+  });
+  const RolloutStatus = IDL.Variant({
+    'Running' : IDL.Null,
+    'Completed' : IDL.Null,
+    'Halted' : IDL.Text,
+    'Cancelled' : IDL.Null,
+  });
+  const BucketUpgradeStatus = IDL.Variant({
+    'Pending' : IDL.Null,
+    'Upgrading' : Lease,
+    'Upgraded' : IDL.Null,
+    'Failed' : IDL.Null,
+  });
+  const BucketUpgrade = IDL.Record({
+    'canister_id' : IDL.Principal,
+    'status' : BucketUpgradeStatus,
+    'attempts' : IDL.Nat32,
+    'last_error' : IDL.Opt(IDL.Text),
+    'upgraded_at' : IDL.Opt(IDL.Nat64),
+  });
+  const UpgradeRollout = IDL.Record({
+    'wasm_version' : IDL.Text,
+    'started_at' : IDL.Nat64,
+    'started_by' : IDL.Principal,
+    'finished_at' : IDL.Opt(IDL.Nat64),
+    'batch_size' : IDL.Nat32,
+    'max_attempts' : IDL.Nat32,
+    'status' : RolloutStatus,
+    'batches_started' : IDL.Nat64,
+    'buckets' : IDL.Vec(BucketUpgrade),
+  });
   return IDL.Service({
     'addContentModerator' : IDL.Func([IDL.Principal], [], []),
+    'cancelBucketUpgrade' : IDL.Func([], [Result], []),
+    'commitBucketWasmUpload' : IDL.Func([IDL.Text], [WasmVersionResult], []),
+    'listContentModerators' : IDL.Func([], [IDL.Vec(IDL.Principal)], ['query']),
+    'getActiveBucketWasm' : IDL.Func([], [IDL.Opt(WasmVersion)], ['query']),
     'getAllIndexes' : IDL.Func([], [IDL.Vec(IDL.Principal)], ['query']),
+    'getBucketIndexVersion' : IDL.Func(
+        [IDL.Principal],
+        [IDL.Opt(IDL.Nat64)],
+        ['query'],
+      ),
+    'getBucketUpgradeStatus' : IDL.Func([], [IDL.Opt(UpgradeRollout)], ['query']),
+    'getDeadTasks' : IDL.Func([], [IDL.Vec(DeadTask)], ['query']),
     'getGlobalIndex' : IDL.Func([], [IDL.Vec(IDL.Vec(IDL.Text))], ['query']),
     'getIndexByTag' : IDL.Func([IDL.Text], [IDL.Vec(IDL.Principal)], ['query']),
     'getMetrics' : IDL.Func([], [IDL.Text], ['query']),
-    'getUploadOrder' : IDL.Func([], [IDL.Vec(IDL.Principal)], ['query']),
+    'getOrphanedCanisters' : IDL.Func([], [IDL.Vec(OrphanedCanister)], ['query']),
+    'getSettings' : IDL.Func([], [IndexSettings], ['query']),
+    'getSpawnedBuckets' : IDL.Func([], [IDL.Vec(SpawnedBucketCanister)], ['query']),
+    'getSettingsHistory' : IDL.Func([], [IDL.Vec(SettingsChange)], ['query']),
+    'getTasks' : IDL.Func([], [IDL.Vec(Task)], ['query']),
+    'getUploadOrder' : IDL.Func(
+        [IDL.Opt(IDL.Text)],
+        [IDL.Vec(IDL.Principal)],
+        ['query'],
+      ),
+    'grantRole' : IDL.Func([RoleAssignment], [Result], []),
+    'listBucketWasms' : IDL.Func([], [IDL.Vec(WasmVersion)], ['query']),
+    'listRoles' : IDL.Func([], [IDL.Vec(RoleAssignment)], ['query']),
+    'postContent' : IDL.Func([IDL.Text, IDL.Text], [PostResult], []),
+    'getQueuedWrite' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Opt(QueuedWriteStatus)],
+        ['query'],
+      ),
+    'reserveUpload' : IDL.Func([IDL.Opt(IDL.Text)], [IDL.Opt(SlotReservation)], []),
+    'resolveEntry' : IDL.Func([GlobalEntryId], [IDL.Opt(IDL.Principal)], ['query']),
+    'removeContentModerator' : IDL.Func([IDL.Principal], [IDL.Bool], []),
+    'removeBucketWasm' : IDL.Func([IDL.Text], [Result], []),
+    'retryDeadTask' : IDL.Func([IDL.Nat64], [IDL.Bool], []),
+    'revokeRole' : IDL.Func([RoleAssignment], [Result], []),
+    'setActiveBucketWasm' : IDL.Func([IDL.Text], [Result], []),
+    'setBucketStatus' : IDL.Func([IDL.Principal, BucketStatus], [Result], []),
+    'startBucketUpgrade' : IDL.Func([UpgradeArgs], [Result], []),
+    'startBucketWasmUpload' : IDL.Func(
+        [IDL.Text, IDL.Vec(IDL.Nat8)],
+        [Result],
+        [],
+      ),
+    'updateSettings' : IDL.Func([SettingsUpdate], [SettingsResult], []),
+    'uploadBucketWasmChunk' : IDL.Func(
+        [IDL.Text, IDL.Vec(IDL.Nat8)],
+        [WasmUploadResult],
+        [],
+      ),
+  });
+};
+export const init = ({ IDL }) => {
+  const IndexingStrategy = IDL.Variant({
+    'BalancedLoad' : IDL.Null,
+    'FillFirst' : IDL.Null,
+    'TagAffinity' : IDL.Null,
+  });
+  const IndexSyncMode = IDL.Variant({ 'Push' : IDL.Null, 'Pull' : IDL.Null });
+  const CapacityMode = IDL.Variant({ 'Entries' : IDL.Null, 'Bytes' : IDL.Nat64 });
+  const IndexInitArgs = IDL.Record({
+    'admins' : IDL.Vec(IDL.Principal),
+    'bucket_controllers' : IDL.Vec(IDL.Principal),
+    'desired_free_slots' : IDL.Opt(IDL.Nat),
+    'reindex_interval' : IDL.Opt(IDL.Nat64),
+    'cycles_per_bucket' : IDL.Opt(IDL.Nat64),
+    'bucket_max_entries' : IDL.Opt(IDL.Nat64),
+    'bucket_capacity_mode' : IDL.Opt(CapacityMode),
+    'indexing_strategy' : IDL.Opt(IndexingStrategy),
+    'index_sync_mode' : IDL.Opt(IndexSyncMode),
+    'pull_batch_size' : IDL.Opt(IDL.Nat32),
   });
+  return [IDL.Opt(IndexInitArgs)];
 };
-export const init = ({ IDL }) => { return []; };
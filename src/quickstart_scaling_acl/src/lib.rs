@@ -0,0 +1,152 @@
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+// Roles are ordered by privilege, a higher role includes everything a lower
+// one can do (e.g. a Moderator is also a Reader).
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Role {
+    Reader,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+// A role either applies to everything or only to entries with a given tag
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum RoleScope {
+    Global,
+    Tag(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct RoleAssignment {
+    pub principal: Principal,
+    pub role: Role,
+    pub scope: RoleScope,
+}
+
+// What the Index pushes to its buckets. The version goes up with every change
+// on the Index, so a bucket can tell an old list from the current one.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct RoleList {
+    pub version: u64,
+    pub assignments: Vec<RoleAssignment>,
+}
+
+// The Index canister is the source of truth for role assignments and pushes
+// the full list to every bucket, which enforces it in its guards.
+#[derive(CandidType, Deserialize, Debug, Default, Clone)]
+pub struct AccessControl {
+    assignments: Vec<RoleAssignment>,
+}
+
+impl AccessControl {
+    // Returns false if the assignment already existed
+    pub fn grant(&mut self, assignment: RoleAssignment) -> bool {
+        if self.assignments.contains(&assignment) {
+            return false;
+        }
+        self.assignments.push(assignment);
+        true
+    }
+
+    // Returns false if there was nothing to revoke
+    pub fn revoke(&mut self, assignment: &RoleAssignment) -> bool {
+        let before = self.assignments.len();
+        self.assignments.retain(|a| a != assignment);
+        before != self.assignments.len()
+    }
+
+    pub fn set_assignments(&mut self, assignments: Vec<RoleAssignment>) {
+        self.assignments = assignments;
+    }
+
+    pub fn assignments(&self) -> Vec<RoleAssignment> {
+        self.assignments.clone()
+    }
+
+    // True if the principal holds at least `role`, either globally or for `tag`.
+    // Passing None for the tag only matches global assignments.
+    pub fn has_role(&self, principal: &Principal, role: Role, tag: Option<&str>) -> bool {
+        self.assignments.iter().any(|a| {
+            a.principal == *principal
+                && a.role >= role
+                && match &a.scope {
+                    RoleScope::Global => true,
+                    RoleScope::Tag(t) => Some(t.as_str()) == tag,
+                }
+        })
+    }
+
+    // True if the principal holds at least `role` in any scope
+    pub fn has_role_anywhere(&self, principal: &Principal, role: Role) -> bool {
+        self.assignments
+            .iter()
+            .any(|a| a.principal == *principal && a.role >= role)
+    }
+
+    // Principals holding at least `role` in any scope, without duplicates
+    pub fn principals_with_role(&self, role: Role) -> Vec<Principal> {
+        let mut principals: Vec<Principal> = vec![];
+
+        for a in self.assignments.iter().filter(|a| a.role >= role) {
+            if !principals.contains(&a.principal) {
+                principals.push(a.principal);
+            }
+        }
+
+        principals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn role_hierarchy_and_scopes() {
+        let user1: Principal = Principal::from_slice(&[1]);
+        let user2: Principal = Principal::from_slice(&[2]);
+
+        let mut acl = AccessControl::default();
+
+        assert!(acl.grant(RoleAssignment {
+            principal: user1,
+            role: Role::Admin,
+            scope: RoleScope::Global,
+        }));
+        assert!(acl.grant(RoleAssignment {
+            principal: user2,
+            role: Role::Moderator,
+            scope: RoleScope::Tag("#rabbit".to_string()),
+        }));
+
+        // Admins are moderators and readers everywhere
+        assert!(acl.has_role(&user1, Role::Moderator, Some("#fox")));
+        assert!(acl.has_role(&user1, Role::Reader, None));
+        assert!(!acl.has_role(&user1, Role::Owner, None));
+
+        // Tag scoped roles only apply to that tag
+        assert!(acl.has_role(&user2, Role::Moderator, Some("#rabbit")));
+        assert!(!acl.has_role(&user2, Role::Moderator, Some("#fox")));
+        assert!(!acl.has_role(&user2, Role::Moderator, None));
+        assert!(acl.has_role_anywhere(&user2, Role::Reader));
+        assert!(!acl.has_role_anywhere(&user2, Role::Admin));
+
+        assert_eq!(
+            acl.principals_with_role(Role::Moderator),
+            vec![user1, user2]
+        );
+        assert_eq!(acl.principals_with_role(Role::Admin), vec![user1]);
+
+        let assignment = RoleAssignment {
+            principal: user2,
+            role: Role::Moderator,
+            scope: RoleScope::Tag("#rabbit".to_string()),
+        };
+        assert!(!acl.grant(assignment.clone()));
+        assert!(acl.revoke(&assignment));
+        assert!(!acl.revoke(&assignment));
+        assert!(!acl.has_role_anywhere(&user2, Role::Reader));
+    }
+}
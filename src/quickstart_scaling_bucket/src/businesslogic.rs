@@ -1,23 +1,190 @@
+use crate::store::EntryStore;
 use crate::{Principal, TimestampMillis};
 use candid::CandidType;
+use quickstart_scaling_acl::{AccessControl, Role, RoleAssignment, RoleList, RoleScope};
+use quickstart_scaling_reservation::SlotReservation;
+use quickstart_scaling_tasks::{RetryPolicy, Task, TaskKind};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// Rough cost of an entry in the store besides its tag and body: the fixed size
+// fields, its encoding and its keys in the store's maps
+pub const ENTRY_OVERHEAD_BYTES: u64 = 128;
+pub const REVISION_OVERHEAD_BYTES: u64 = 48;
+// Entries are in stable memory, the heap only holds the rest of the state.
+// pre_upgrade serializes that rest, which needs about as much heap again, so
+// we stop taking entries well below the 4GiB of a wasm32 canister.
+pub const MAX_MEMORY_USED: u64 = 1536 * 1024 * 1024;
+// Whatever the capacity mode, the store stays within the stable memory a
+// canister can have. Chunks are rounded up to a power of two, so entries can
+// take up to twice their size.
+pub const MAX_STORE_BYTES: u64 = 16 * 1024 * 1024 * 1024;
+
+// Per-bucket sequence number of an entry. Ids are handed out in insertion
+// order and are never reused.
+pub type EntryId = u64;
 
 //Business State
 #[derive(CandidType, Deserialize, Debug)]
 pub struct BusinessState {
-    entries: HashMap<String, Vec<BucketEntry>>,
+    // The entries and their history, the moderation log, the bans and the
+    // roles, in stable memory
+    store: EntryStore,
+    next_entry_id: EntryId,
     current_entries: u64,
     bucket_max_entries: u64,
-    content_moderators: Vec<Principal>,
+    capacity_mode: CapacityMode,
+    // Size of the entries and their history, see entry_bytes
+    bytes_used: u64,
+    // The canister's memory use when we last looked, see set_memory_used
+    memory_used: u64,
+    // Version of the role list the Index pushed last (0 until the Index
+    // sends one)
+    roles_version: u64,
+    // Shared with the Index, to check the upload reservations it signs
+    reservation_key: Option<Vec<u8>>,
+    // Reservations that were used, by id, kept until they expire so they
+    // can't be used twice
+    used_reservations: BTreeMap<u64, TimestampMillis>,
+    // Records in the moderation log, which is append only. The next record
+    // gets this as its id.
+    moderation_log_len: u64,
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct BucketEntry {
+    pub(crate) id: EntryId,
     pub(crate) tag: String,
     pub(crate) body: String,
     pub(crate) submitted_at: TimestampMillis,
     pub(crate) submitted_by: Principal,
+    // Starts at 0 and goes up by one with every edit
+    pub(crate) version: u32,
+    pub(crate) edited_at: Option<TimestampMillis>,
+    // Hidden entries are only visible to moderators
+    pub(crate) hidden: bool,
+}
+
+// A body that an entry had before it was edited. created_at is the time this
+// version became current.
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct EntryRevision {
+    pub(crate) version: u32,
+    pub(crate) body: String,
+    pub(crate) created_at: TimestampMillis,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EntryError {
+    NotFound,
+    NotAuthor,
+    // The caller doesn't hold a role that covers the entry's tag
+    Forbidden,
+    // The edit would take the bucket past its capacity
+    Full,
+}
+
+// How the bucket decides it's full. Entries caps the number of entries at
+// bucket_max_entries, Bytes caps the size of the entries whatever their number.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CapacityMode {
+    Entries,
+    Bytes(u64),
+}
+
+// Why an entry forwarded by the Index wasn't stored. The Index tries another
+// bucket when we're full, but not when the author is banned.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PostError {
+    Full,
+    Banned,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ModerationAction {
+    Hide,
+    Unhide,
+    Remove,
+    Ban,
+    Unban,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ModerationTarget {
+    Entry(EntryId),
+    Principal(Principal),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ModerationRecord {
+    pub(crate) id: u64,
+    pub(crate) actor: Principal,
+    pub(crate) action: ModerationAction,
+    pub(crate) target: ModerationTarget,
+    pub(crate) reason: String,
+    pub(crate) timestamp: TimestampMillis,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ModerationLogPage {
+    pub(crate) records: Vec<ModerationRecord>,
+    pub(crate) next_cursor: Option<u64>,
+}
+
+// An entry id that is unique across all buckets: the bucket that holds
+// the entry plus the entry's sequence id inside that bucket.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GlobalEntryId {
+    pub(crate) bucket: Principal,
+    pub(crate) id: EntryId,
+}
+
+// Upper bound for the number of entries returned in a single page, so that
+// responses stay well below the message size limit
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+// A page of entries in submission order. Pass next_cursor back to get the
+// next page; None means there is nothing left to read. The cursor is an
+// entry id, so it stays valid while new entries are posted.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct EntriesPage {
+    pub(crate) entries: Vec<BucketEntry>,
+    pub(crate) next_cursor: Option<EntryId>,
+}
+
+// Keeps a sync part well below the 2MiB inter-canister message limit
+pub const MAX_INDEX_PART_BYTES: usize = 1_000_000;
+
+// The heartbeat goes back to pushing our index if the Index hasn't pulled it
+// for this long. 1 minute.
+pub const INDEX_PULL_TIMEOUT: TimestampMillis = 60_000_000_000;
+
+// A sync attempt that holds the task for longer than this (e.g. it trapped
+// after a call) is counted as failed. 1 minute.
+pub const INDEX_SYNC_LEASE: TimestampMillis = 60_000_000_000;
+// Failed syncs are retried after 1s, 2s, 4s... up to 10 minutes
+pub const INDEX_SYNC_BACKOFF: TimestampMillis = 1_000_000_000;
+pub const MAX_INDEX_SYNC_BACKOFF: TimestampMillis = 600_000_000_000;
+
+// The async work of the bucket, run from the heartbeat
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum BucketTask {
+    // Sends the current index to the Index canister
+    SyncIndex,
+}
+
+impl TaskKind for BucketTask {
+    fn retry_policy(&self) -> RetryPolicy {
+        match self {
+            // The Index has to get our index eventually, never given up
+            BucketTask::SyncIndex => RetryPolicy {
+                lease: INDEX_SYNC_LEASE,
+                base_backoff: INDEX_SYNC_BACKOFF,
+                max_backoff: MAX_INDEX_SYNC_BACKOFF,
+                max_attempts: None,
+            },
+        }
+    }
 }
 
 #[derive(CandidType, Default, Deserialize, Clone, Debug)]
@@ -25,22 +192,223 @@ pub struct BucketIndex {
     pub(crate) effective_index: EffectiveIndex,
     pub(crate) index_state: IndexState,
     pub(crate) last_updated: TimestampMillis,
+    // The last index the Index canister applied, deltas are computed against it.
+    // Version 0 means the Index has nothing from us and needs a full update.
+    pub(crate) acked_version: u64,
+    pub(crate) acked_tags: Vec<String>,
+    // Pull mode: the last pull and the index it was served
+    pub(crate) last_pulled_at: TimestampMillis,
+    pub(crate) pulled_index: EffectiveIndex,
+    pub(crate) sync_status: IndexSyncStatus,
+}
+
+// How sending our index to the Index canister is going, shown in getMetrics
+#[derive(CandidType, Default, Deserialize, Clone, Debug)]
+pub struct IndexSyncStatus {
+    pub(crate) last_success: Option<TimestampMillis>,
+    pub(crate) last_error: Option<String>,
+    pub(crate) last_error_at: Option<TimestampMillis>,
+    // Failed attempts since the last success, the backoff grows with them
+    pub(crate) attempts: u32,
+    pub(crate) total_failures: u64,
+    pub(crate) next_attempt_at: TimestampMillis,
+}
+
+// Mirrors the Index's IndexUpdate. Either the full list of tags or the tags
+// added and removed since base_version. Big updates are split in parts that
+// the Index applies once the last one arrives.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct IndexUpdate {
+    pub(crate) version: u64,
+    pub(crate) base_version: u64,
+    pub(crate) full: bool,
+    pub(crate) added_tags: Vec<String>,
+    pub(crate) removed_tags: Vec<String>,
+    pub(crate) current_entries: u64,
+    pub(crate) bucket_max_entries: u64,
+    pub(crate) bytes_used: u64,
+    pub(crate) bytes_capacity: u64,
+    pub(crate) part: u32,
+    pub(crate) last_part: bool,
+}
+
+// Mirrors the Index's IndexSyncResult
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum IndexSyncResult {
+    // Send the next part
+    PartAccepted,
+    // The Index is now at this version
+    Applied(u64),
+    // The Index doesn't have our base version, a full update is needed
+    ResyncNeeded,
+    // The Index doesn't know this bucket
+    Rejected,
 }
 
 #[derive(CandidType, Default, Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct EffectiveIndex {
+    // Increases with every new index, so the Index canister can drop pushes
+    // that arrive out of order
+    pub(crate) version: u64,
     tags: Vec<String>,
     current_entries: u64,
     bucket_max_entries: u64,
+    bytes_used: u64,
+    bytes_capacity: u64,
+}
+
+impl EffectiveIndex {
+    // Same tags and counts, whatever the version
+    pub fn same_content(&self, other: &EffectiveIndex) -> bool {
+        self.tags == other.tags
+            && self.current_entries == other.current_entries
+            && self.bucket_max_entries == other.bucket_max_entries
+            && self.bytes_used == other.bytes_used
+            && self.bytes_capacity == other.bytes_capacity
+    }
+}
+
+impl BucketIndex {
+    // Splits the update for the current index in parts. It's a delta against
+    // the acknowledged index if there is one, the full tag list otherwise.
+    pub fn prepare_sync(&self) -> Vec<IndexUpdate> {
+        let index = &self.effective_index;
+        let full = self.acked_version == 0;
+
+        let (added, removed): (Vec<&String>, Vec<&String>) = if full {
+            (index.tags.iter().collect(), vec![])
+        } else {
+            let acked: HashSet<&String> = self.acked_tags.iter().collect();
+            let current: HashSet<&String> = index.tags.iter().collect();
+            (
+                index.tags.iter().filter(|t| !acked.contains(t)).collect(),
+                self.acked_tags
+                    .iter()
+                    .filter(|t| !current.contains(t))
+                    .collect(),
+            )
+        };
+
+        let new_part = |part: u32| IndexUpdate {
+            version: index.version,
+            base_version: self.acked_version,
+            full,
+            current_entries: index.current_entries,
+            bucket_max_entries: index.bucket_max_entries,
+            bytes_used: index.bytes_used,
+            bytes_capacity: index.bytes_capacity,
+            part,
+            ..Default::default()
+        };
+
+        let mut parts = vec![new_part(0)];
+        let mut part_bytes = 0;
+
+        for (tag, is_added) in added
+            .into_iter()
+            .map(|t| (t, true))
+            .chain(removed.into_iter().map(|t| (t, false)))
+        {
+            // A few bytes of candid overhead per tag
+            let tag_bytes = tag.len() + 8;
+            if part_bytes + tag_bytes > MAX_INDEX_PART_BYTES && part_bytes > 0 {
+                parts.push(new_part(parts.len() as u32));
+                part_bytes = 0;
+            }
+            part_bytes += tag_bytes;
+
+            let part = parts.last_mut().unwrap();
+            if is_added {
+                part.added_tags.push(tag.clone());
+            } else {
+                part.removed_tags.push(tag.clone());
+            }
+        }
+
+        parts.last_mut().unwrap().last_part = true;
+        parts
+    }
+
+    // The Index pulls with the version it has, which acknowledges the index
+    // served by an earlier pull. Returns the parts of the update if we have
+    // something newer.
+    pub fn prepare_pull(&mut self, known_version: u64, now: TimestampMillis) -> Vec<IndexUpdate> {
+        self.last_pulled_at = now;
+
+        if known_version > 0 && known_version == self.pulled_index.version {
+            let pulled_index = self.pulled_index.clone();
+            self.acknowledge(&pulled_index);
+        }
+        if known_version >= self.effective_index.version {
+            return vec![];
+        }
+
+        // A delta has to be against the version the Index has
+        if known_version != self.acked_version {
+            self.acked_version = 0;
+            self.acked_tags = vec![];
+        }
+
+        self.pulled_index = self.effective_index.clone();
+        self.prepare_sync()
+    }
+
+    pub fn pulled_recently(&self, now: TimestampMillis) -> bool {
+        self.last_pulled_at > 0 && now < self.last_pulled_at + INDEX_PULL_TIMEOUT
+    }
+
+    // Ok only once the Index applied the version we sent. A newer index may
+    // have been generated while we were sending, its own sync task is queued.
+    pub fn record_sync_result(
+        &mut self,
+        version: u64,
+        result: &Result<(), String>,
+        now: TimestampMillis,
+    ) {
+        match result {
+            Ok(()) => {
+                self.sync_status.last_success = Some(now);
+                if self.effective_index.version == version {
+                    self.index_state = IndexState::Synced;
+                }
+            }
+            Err(error) => {
+                self.sync_status.total_failures += 1;
+                self.sync_status.last_error = Some(error.clone());
+                self.sync_status.last_error_at = Some(now);
+            }
+        }
+    }
+
+    // The attempts and the next attempt come from the queued sync task, if any
+    pub fn sync_status(&self, task: Option<&Task<BucketTask>>) -> IndexSyncStatus {
+        IndexSyncStatus {
+            attempts: task.map_or(0, |t| t.attempts),
+            next_attempt_at: task.map_or(0, |t| t.next_attempt_at),
+            ..self.sync_status.clone()
+        }
+    }
+
+    // Called once the Index applied the update for `index`
+    pub fn acknowledge(&mut self, index: &EffectiveIndex) {
+        if index.version > self.acked_version {
+            self.acked_version = index.version;
+            self.acked_tags = index.tags.clone();
+        }
+    }
 }
 
 impl Default for BucketEntry {
     fn default() -> Self {
         BucketEntry {
+            id: 0,
             tag: "".to_string(),
             body: "".to_string(),
             submitted_at: 0,
             submitted_by: Principal::anonymous(),
+            version: 0,
+            edited_at: None,
+            hidden: false,
         }
     }
 }
@@ -48,10 +416,17 @@ impl Default for BucketEntry {
 impl Default for BusinessState {
     fn default() -> Self {
         BusinessState {
-            entries: Default::default(),
+            store: Default::default(),
+            next_entry_id: 0,
             current_entries: 0,
             bucket_max_entries: 20,
-            content_moderators: vec![],
+            capacity_mode: CapacityMode::Entries,
+            bytes_used: 0,
+            memory_used: 0,
+            roles_version: 0,
+            reservation_key: None,
+            used_reservations: Default::default(),
+            moderation_log_len: 0,
         }
     }
 }
@@ -59,7 +434,6 @@ impl Default for BusinessState {
 #[derive(CandidType, Deserialize, Clone, Copy, Debug)]
 pub enum IndexState {
     New,
-    InSync(u32),
     Synced,
 }
 
@@ -79,6 +453,10 @@ pub struct BucketMetrics {
     pub(crate) max_entries: u64,
     pub(crate) current_entries: u64,
     pub(crate) memory_used: u64,
+    pub(crate) bytes_used: u64,
+    pub(crate) bytes_capacity: u64,
+    pub(crate) index_sync: IndexSyncStatus,
+    pub(crate) roles_version: u64,
 }
 
 // This is the section that implements all our business logic, on top
@@ -93,6 +471,98 @@ impl BusinessState {
         self.bucket_max_entries = max_entries;
     }
 
+    pub fn capacity_mode(&self) -> CapacityMode {
+        self.capacity_mode
+    }
+
+    pub fn set_capacity_mode(&mut self, capacity_mode: CapacityMode) {
+        self.capacity_mode = capacity_mode;
+    }
+
+    // Called with Environment::memory_used before inserting and indexing, so
+    // bytes_capacity keeps us under MAX_MEMORY_USED
+    pub fn set_memory_used(&mut self, memory_used: u64) {
+        self.memory_used = memory_used;
+    }
+
+    pub fn bytes_used(&self) -> u64 {
+        self.bytes_used
+    }
+
+    // The configured maximum in Bytes mode, capped by MAX_STORE_BYTES. A heap
+    // that reached MAX_MEMORY_USED leaves no room at all.
+    pub fn bytes_capacity(&self) -> u64 {
+        if self.memory_used >= MAX_MEMORY_USED {
+            return self.bytes_used;
+        }
+
+        match self.capacity_mode {
+            CapacityMode::Entries => MAX_STORE_BYTES,
+            CapacityMode::Bytes(max_bytes) => max_bytes.min(MAX_STORE_BYTES),
+        }
+    }
+
+    // Called when the canister starts, with the store in its stable memory
+    pub fn set_store(&mut self, store: EntryStore) {
+        self.store = store;
+    }
+
+    pub fn store_mut(&mut self) -> &mut EntryStore {
+        &mut self.store
+    }
+
+    // Moves the entries of a bucket from before the store, that kept them in
+    // the heap, to the store. The entries keep their ids, and the ids up to
+    // `next_entry_id` aren't handed out again.
+    pub fn migrate_entries(
+        &mut self,
+        entries: Vec<BucketEntry>,
+        mut history: HashMap<EntryId, Vec<EntryRevision>>,
+        next_entry_id: EntryId,
+    ) {
+        self.next_entry_id = self.next_entry_id.max(next_entry_id);
+        self.bytes_used = 0;
+        self.current_entries = entries.len() as u64;
+        for entry in entries {
+            self.next_entry_id = self.next_entry_id.max(entry.id + 1);
+            let entry_history = history.remove(&entry.id).unwrap_or_default();
+            self.bytes_used += entry_bytes(&entry)
+                + entry_history
+                    .iter()
+                    .map(|r| r.body.len() as u64 + REVISION_OVERHEAD_BYTES)
+                    .sum::<u64>();
+            self.store.insert(entry, entry_history);
+        }
+    }
+
+    // Moves the moderation log and the bans of a bucket from before the store
+    // to the store
+    pub fn migrate_moderation(&mut self, log: Vec<ModerationRecord>, banned: Vec<Principal>) {
+        for record in log {
+            self.moderation_log_len = self.moderation_log_len.max(record.id + 1);
+            self.store.append_moderation_record(&record);
+        }
+        for principal in banned {
+            self.store.set_banned(&principal, true);
+        }
+    }
+
+    // There's no entry limit in Bytes mode
+    fn entry_capacity(&self) -> u64 {
+        match self.capacity_mode {
+            CapacityMode::Entries => self.bucket_max_entries,
+            CapacityMode::Bytes(_) => u64::MAX,
+        }
+    }
+
+    fn has_room_for(&self, entry: &BucketEntry) -> bool {
+        self.entries_count() < self.entry_capacity() && self.has_bytes_for(entry)
+    }
+
+    fn has_bytes_for(&self, entry: &BucketEntry) -> bool {
+        self.bytes_used + entry_bytes(entry) <= self.bytes_capacity()
+    }
+
     pub fn entries_count(&self) -> u64 {
         self.current_entries
     }
@@ -102,75 +572,433 @@ impl BusinessState {
     }
 
     pub fn add_entry(&mut self, entry: BucketEntry) -> bool {
-        if self.entries_count() < self.max_entries() {
-            let key = entry.tag.clone();
-            self.entries.entry(key).or_default().push(entry);
-
-            //Don't forget to increase the entries counter
-            //This bug was caught with the unit tests in "fn test_capacity()"
-            //Comment the next line to see the test fail
-            self.current_entries += 1;
+        self.insert_entry(entry).is_some()
+    }
+
+    // Stores the entry under a fresh id and returns that id, or None if the
+    // bucket is full or the author is banned. Any id already set on the entry
+    // is overwritten.
+    pub fn insert_entry(&mut self, entry: BucketEntry) -> Option<EntryId> {
+        if self.is_banned(&entry.submitted_by) {
+            return None;
+        }
+
+        if self.has_room_for(&entry) {
+            return Some(self.store_entry(entry));
+        }
+        None
+    }
+
+    // Entries posted with a valid reservation are stored even if the bucket
+    // reached its entry limit since the Index handed it out. The Index only
+    // hands out as many reservations as we had free slots, so we can't go over
+    // by much. The byte and memory limits still apply, a reservation doesn't
+    // say how big the entry is. An invalid, expired or already used
+    // reservation is ignored and the entry is inserted like any other.
+    pub fn insert_reserved_entry(
+        &mut self,
+        entry: BucketEntry,
+        reservation: &SlotReservation,
+        bucket: Principal,
+        now: TimestampMillis,
+    ) -> Option<EntryId> {
+        if self.is_banned(&entry.submitted_by) {
+            return None;
+        }
+
+        // Forget the used reservations that can't be presented anymore
+        self.used_reservations
+            .retain(|_, expires_at| *expires_at > now);
+
+        let valid = reservation.bucket == bucket
+            && reservation.holder == entry.submitted_by
+            && reservation.expires_at > now
+            && !self.used_reservations.contains_key(&reservation.id)
+            && self
+                .reservation_key
+                .as_ref()
+                .is_some_and(|key| reservation.verify(key));
+
+        if !valid {
+            return self.insert_entry(entry);
+        }
+        if !self.has_bytes_for(&entry) {
+            return None;
+        }
+
+        self.used_reservations
+            .insert(reservation.id, reservation.expires_at);
+        Some(self.store_entry(entry))
+    }
+
+    pub fn insert_entry_for_index(&mut self, entry: BucketEntry) -> Result<EntryId, PostError> {
+        if self.is_banned(&entry.submitted_by) {
+            return Err(PostError::Banned);
+        }
+        self.insert_entry(entry).ok_or(PostError::Full)
+    }
+
+    pub fn set_reservation_key(&mut self, key: Vec<u8>) {
+        self.reservation_key = Some(key);
+    }
+
+    // Keeps the reservations a bucket from before the store had used
+    pub fn migrate_used_reservations(&mut self, used: BTreeMap<u64, TimestampMillis>) {
+        self.used_reservations.extend(used);
+    }
+
+    fn store_entry(&mut self, mut entry: BucketEntry) -> EntryId {
+        let id = self.next_entry_id;
+        self.next_entry_id += 1;
+
+        entry.id = id;
+        self.bytes_used += entry_bytes(&entry);
+        self.store.insert(entry, vec![]);
+
+        //Don't forget to increase the entries counter
+        //This bug was caught with the unit tests in "fn test_capacity()"
+        //Comment the next line to see the test fail
+        self.current_entries += 1;
+        id
+    }
+
+    fn find_entry(&self, id: EntryId) -> Option<BucketEntry> {
+        self.store.get(id)
+    }
+
+    // Only the author can change an entry. Anonymous entries can't be edited
+    // or deleted since anyone can call as anonymous.
+    fn check_author(&self, id: EntryId, caller: Principal) -> Result<(), EntryError> {
+        let entry = self.find_entry(id).ok_or(EntryError::NotFound)?;
+
+        if caller == Principal::anonymous() || entry.submitted_by != caller {
+            return Err(EntryError::NotAuthor);
+        }
+        Ok(())
+    }
+
+    pub fn edit_entry(
+        &mut self,
+        id: EntryId,
+        body: String,
+        caller: Principal,
+        now: TimestampMillis,
+    ) -> Result<(), EntryError> {
+        self.check_author(id, caller)?;
+
+        // The entry now holds the new body, and the old one is still counted
+        // as part of the history
+        let added_bytes = body.len() as u64 + REVISION_OVERHEAD_BYTES;
+        if self.bytes_used + added_bytes > self.bytes_capacity() {
+            return Err(EntryError::Full);
+        }
+
+        // check_author made sure the entry exists
+        let mut entry = self.find_entry(id).unwrap();
+        let mut history = self.store.get_history(id);
+        let previous = EntryRevision {
+            version: entry.version,
+            body: std::mem::replace(&mut entry.body, body),
+            created_at: entry.edited_at.unwrap_or(entry.submitted_at),
+        };
+        entry.version += 1;
+        entry.edited_at = Some(now);
+        self.bytes_used += added_bytes;
+
+        history.push(previous);
+        self.store.insert(entry, history);
+        Ok(())
+    }
+
+    pub fn delete_entry(&mut self, id: EntryId, caller: Principal) -> Result<(), EntryError> {
+        self.check_author(id, caller)?;
+        self.remove_entry(id);
+        Ok(())
+    }
+
+    // Drops the entry and its history and frees its slot. A tag goes away
+    // with its last entry, so the next bucket index won't report it anymore.
+    fn remove_entry(&mut self, id: EntryId) -> Option<BucketEntry> {
+        let (entry, history) = self.store.remove(id)?;
+
+        let history_bytes: u64 = history
+            .iter()
+            .map(|r| r.body.len() as u64 + REVISION_OVERHEAD_BYTES)
+            .sum();
+        self.bytes_used -= entry_bytes(&entry) + history_bytes;
+        self.current_entries -= 1;
+
+        Some(entry)
+    }
+
+    // Same visibility rules as get_entry
+    pub fn get_entry_history(&self, id: EntryId, caller: Principal) -> Vec<EntryRevision> {
+        if self.get_entry(id, caller).is_none() {
+            return vec![];
+        }
+
+        self.store.get_history(id)
+    }
+
+    // Users see their own and anonymous entries, readers of a tag see all of
+    // its entries. Hidden entries are only visible to moderators of the tag.
+    fn is_visible(&self, entry: &BucketEntry, caller: Principal) -> bool {
+        let tag = Some(entry.tag.as_str());
+
+        if self.store.roles().has_role(&caller, Role::Moderator, tag) {
             return true;
         }
-        false
+
+        !entry.hidden
+            && (entry.submitted_by == caller
+                || entry.submitted_by == Principal::anonymous()
+                || self.store.roles().has_role(&caller, Role::Reader, tag))
+    }
+
+    pub fn get_entry(&self, id: EntryId, caller: Principal) -> Option<BucketEntry> {
+        self.find_entry(id).filter(|e| self.is_visible(e, caller))
     }
 
     //List entries if they were submitted by a principal or by anonymous
     pub fn list_entries(&self, tag: &str, submitted_by: Principal) -> Vec<BucketEntry> {
-        let mut filtered_entries: Vec<BucketEntry> = Vec::new();
+        self.store
+            .tag_entries_from(tag, 0)
+            .filter(|e| self.is_visible(e, submitted_by))
+            .collect()
+    }
 
-        for v in self.entries.get(tag) {
-            for i in v.into_iter() {
-                if i.submitted_by == submitted_by || i.submitted_by == Principal::anonymous() {
-                    filtered_entries.push(i.clone())
-                }
-            }
-        }
+    pub fn list_all_entries(&self) -> Vec<BucketEntry> {
+        self.store.entries_from(0).collect()
+    }
 
-        filtered_entries
+    // Ids grow with every insert, so id order is also submitted_at order
+    pub fn list_entries_page(
+        &self,
+        tag: &str,
+        submitted_by: Principal,
+        cursor: Option<EntryId>,
+        limit: u64,
+    ) -> EntriesPage {
+        let visible = self
+            .store
+            .tag_entries_from(tag, cursor.unwrap_or(0))
+            .filter(|e| self.is_visible(e, submitted_by));
+
+        Self::paginate(visible, limit)
     }
 
-    pub fn list_all_entries(&self) -> Vec<BucketEntry> {
-        let mut all_entries: Vec<BucketEntry> = Vec::new();
-        let all_keys = self.entries.keys().into_iter();
+    pub fn list_all_entries_page(&self, cursor: Option<EntryId>, limit: u64) -> EntriesPage {
+        Self::paginate(self.store.entries_from(cursor.unwrap_or(0)), limit)
+    }
 
-        for key in all_keys {
-            for v in self.entries.get(key) {
-                for i in v.into_iter() {
-                    all_entries.push(i.clone())
-                }
-            }
-        }
+    fn paginate(mut entries: impl Iterator<Item = BucketEntry>, limit: u64) -> EntriesPage {
+        let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+        let page: Vec<BucketEntry> = entries.by_ref().take(limit).collect();
 
-        all_entries
+        EntriesPage {
+            entries: page,
+            next_cursor: entries.next().map(|e| e.id),
+        }
     }
 
-    pub fn create_bucket_index(&self) -> EffectiveIndex {
-        let all_keys = self.entries.keys().map(|s| s.clone()).collect();
+    pub fn create_bucket_index(&self, version: u64) -> EffectiveIndex {
+        // Sorted, so indexes with the same tags compare equal
+        let mut all_keys: Vec<String> = self.store.tags();
+        all_keys.sort();
 
         EffectiveIndex {
+            version,
             tags: all_keys,
             current_entries: self.current_entries,
-            bucket_max_entries: self.bucket_max_entries,
+            bucket_max_entries: self.entry_capacity(),
+            bytes_used: self.bytes_used,
+            bytes_capacity: self.bytes_capacity(),
         }
     }
 
     pub fn add_content_moderator(&mut self, moderator: Principal) {
-        self.content_moderators.push(moderator);
+        let mut roles = self.store.roles().clone();
+        if roles.grant(RoleAssignment {
+            principal: moderator,
+            role: Role::Moderator,
+            scope: RoleScope::Global,
+        }) {
+            self.store.set_roles(roles);
+        }
     }
 
-    pub fn add_content_moderators(&mut self, moderators: Vec<Principal>) {
-        self.content_moderators = moderators;
+    // Everyone that moderates at least one tag
+    pub fn get_content_moderators(&self) -> Vec<Principal> {
+        self.store.roles().principals_with_role(Role::Moderator)
     }
 
-    pub fn get_content_moderators(&self) -> Vec<Principal> {
-        self.content_moderators.clone()
+    // Pushes can arrive out of order, a list older than the one we have is
+    // ignored. Returns the version we have now, so the Index knows whether
+    // we're up to date.
+    pub fn set_roles(&mut self, roles: RoleList) -> u64 {
+        if roles.version >= self.roles_version {
+            self.roles_version = roles.version;
+            let mut access_control = AccessControl::default();
+            access_control.set_assignments(roles.assignments);
+            self.store.set_roles(access_control);
+        }
+        self.roles_version
+    }
+
+    pub fn roles_version(&self) -> u64 {
+        self.roles_version
+    }
+
+    pub fn get_roles(&self) -> Vec<RoleAssignment> {
+        self.store.roles().assignments()
+    }
+
+    pub fn has_role(&self, principal: &Principal, role: Role, tag: Option<&str>) -> bool {
+        self.store.roles().has_role(principal, role, tag)
+    }
+
+    pub fn has_role_anywhere(&self, principal: &Principal, role: Role) -> bool {
+        self.store.roles().has_role_anywhere(principal, role)
+    }
+
+    // Moderators can act on an entry if their role covers its tag
+    fn check_moderator(&self, id: EntryId, actor: Principal) -> Result<(), EntryError> {
+        let entry = self.find_entry(id).ok_or(EntryError::NotFound)?;
+
+        if !self.has_role(&actor, Role::Moderator, Some(&entry.tag)) {
+            return Err(EntryError::Forbidden);
+        }
+        Ok(())
+    }
+
+    pub fn is_banned(&self, principal: &Principal) -> bool {
+        self.store.is_banned(principal)
+    }
+
+    // Moderation actions. Entry actions check the actor's role against the
+    // entry's tag, bans are guarded in lib.rs. Every successful action ends up
+    // in the moderation log.
+    pub fn set_entry_hidden(
+        &mut self,
+        id: EntryId,
+        hidden: bool,
+        actor: Principal,
+        reason: String,
+        now: TimestampMillis,
+    ) -> Result<(), EntryError> {
+        self.check_moderator(id, actor)?;
+
+        // check_moderator made sure the entry exists
+        let mut entry = self.find_entry(id).unwrap();
+        let history = self.store.get_history(id);
+        entry.hidden = hidden;
+        self.store.insert(entry, history);
+
+        let action = if hidden {
+            ModerationAction::Hide
+        } else {
+            ModerationAction::Unhide
+        };
+        self.log_moderation(actor, action, ModerationTarget::Entry(id), reason, now);
+        Ok(())
+    }
+
+    pub fn moderator_remove_entry(
+        &mut self,
+        id: EntryId,
+        actor: Principal,
+        reason: String,
+        now: TimestampMillis,
+    ) -> Result<(), EntryError> {
+        self.check_moderator(id, actor)?;
+        self.remove_entry(id);
+
+        self.log_moderation(
+            actor,
+            ModerationAction::Remove,
+            ModerationTarget::Entry(id),
+            reason,
+            now,
+        );
+        Ok(())
+    }
+
+    pub fn set_banned(
+        &mut self,
+        principal: Principal,
+        banned: bool,
+        actor: Principal,
+        reason: String,
+        now: TimestampMillis,
+    ) {
+        self.store.set_banned(&principal, banned);
+        let action = if banned {
+            ModerationAction::Ban
+        } else {
+            ModerationAction::Unban
+        };
+
+        self.log_moderation(
+            actor,
+            action,
+            ModerationTarget::Principal(principal),
+            reason,
+            now,
+        );
     }
+
+    fn log_moderation(
+        &mut self,
+        actor: Principal,
+        action: ModerationAction,
+        target: ModerationTarget,
+        reason: String,
+        timestamp: TimestampMillis,
+    ) {
+        let record = ModerationRecord {
+            id: self.moderation_log_len,
+            actor,
+            action,
+            target,
+            reason,
+            timestamp,
+        };
+
+        self.store.append_moderation_record(&record);
+        self.moderation_log_len += 1;
+    }
+
+    // Oldest first, cursor is the id of the first record to return
+    pub fn list_moderation_log(&self, cursor: Option<u64>, limit: u64) -> ModerationLogPage {
+        let start = cursor.unwrap_or(0).min(self.moderation_log_len);
+        let limit = limit.clamp(1, MAX_PAGE_SIZE);
+        let end = (start + limit).min(self.moderation_log_len);
+
+        ModerationLogPage {
+            records: self
+                .store
+                .moderation_records_from(start)
+                .take((end - start) as usize)
+                .collect(),
+            next_cursor: if end < self.moderation_log_len {
+                Some(end)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+// The store's maps key tags by their hash, so the tag is only stored with
+// the entry
+fn entry_bytes(entry: &BucketEntry) -> u64 {
+    ENTRY_OVERHEAD_BYTES + entry.tag.len() as u64 + entry.body.len() as u64
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use quickstart_scaling_tasks::TaskQueue;
 
     #[test]
     fn default_state() {
@@ -194,6 +1022,7 @@ mod tests {
             body: "Rabbits are fluffy animals".to_string(),
             submitted_at: 0,
             submitted_by: Principal::anonymous(),
+            ..Default::default()
         };
 
         let res = business_state.add_entry(entry.clone());
@@ -214,6 +1043,7 @@ mod tests {
             body: "Rabbits are fluffy animals".to_string(),
             submitted_at: 0,
             submitted_by: Principal::anonymous(),
+            ..Default::default()
         };
 
         business_state.set_max_entries(3);
@@ -230,6 +1060,63 @@ mod tests {
         assert_eq!(business_state.current_entries, 3);
     }
 
+    #[test]
+    fn byte_capacity() {
+        let mut business_state = BusinessState::default();
+        let author = Principal::from_slice(&[1]);
+        let entry = |body: &str| BucketEntry {
+            tag: "#rabbit".to_string(),
+            body: body.to_string(),
+            submitted_by: author,
+            ..Default::default()
+        };
+        let small = entry_bytes(&entry("x"));
+
+        // The entry count doesn't matter anymore, only the bytes do
+        business_state.set_max_entries(1);
+        let max_bytes = 3 * small + 1 + REVISION_OVERHEAD_BYTES;
+        business_state.set_capacity_mode(CapacityMode::Bytes(max_bytes));
+        assert!(business_state.add_entry(entry("x")));
+        assert!(business_state.add_entry(entry("x")));
+        assert!(!business_state.add_entry(entry(&"x".repeat(100))));
+        let id = business_state.insert_entry(entry("x")).unwrap();
+        assert_eq!(business_state.bytes_used(), 3 * small);
+
+        // History counts too, and is freed with the entry
+        business_state
+            .edit_entry(id, "y".to_string(), author, 1)
+            .unwrap();
+        assert_eq!(business_state.bytes_used(), max_bytes);
+
+        // Edits can't take the bucket past its capacity either
+        assert_eq!(
+            business_state.edit_entry(id, "z".to_string(), author, 2),
+            Err(EntryError::Full)
+        );
+        assert_eq!(business_state.bytes_used(), max_bytes);
+        assert_eq!(business_state.find_entry(id).unwrap().body, "y");
+
+        business_state.delete_entry(id, author).unwrap();
+        assert_eq!(business_state.bytes_used(), 2 * small);
+
+        let index = business_state.create_bucket_index(1);
+        assert_eq!(index.bucket_max_entries, u64::MAX);
+        assert_eq!(index.bytes_used, 2 * small);
+        assert_eq!(index.bytes_capacity, max_bytes);
+
+        // The store and heap limits apply in every mode
+        business_state.set_capacity_mode(CapacityMode::Bytes(u64::MAX));
+        assert_eq!(business_state.bytes_capacity(), MAX_STORE_BYTES);
+        business_state.set_capacity_mode(CapacityMode::Entries);
+        business_state.set_max_entries(10);
+        assert_eq!(business_state.bytes_capacity(), MAX_STORE_BYTES);
+        business_state.set_memory_used(MAX_MEMORY_USED);
+        assert_eq!(business_state.bytes_capacity(), 2 * small);
+        assert!(!business_state.add_entry(entry("x")));
+        business_state.set_memory_used(0);
+        assert!(business_state.add_entry(entry("x")));
+    }
+
     #[test]
     fn test_filter() {
         let user1: Principal = Principal::from_slice(&[1]);
@@ -243,6 +1130,7 @@ mod tests {
             body: "Rabbits are fluffy animals".to_string(),
             submitted_at: 0,
             submitted_by: user1,
+            ..Default::default()
         };
 
         let _res = business_state.add_entry(entry.clone());
@@ -252,6 +1140,7 @@ mod tests {
             body: "Rabbits are fluffy animals".to_string(),
             submitted_at: 0,
             submitted_by: user2,
+            ..Default::default()
         };
 
         let _res = business_state.add_entry(entry.clone());
@@ -264,6 +1153,631 @@ mod tests {
         assert_eq!(business_state.list_entries("#fox", user2).len(), 0);
     }
 
+    #[test]
+    fn test_entry_ids() {
+        let user1: Principal = Principal::from_slice(&[1]);
+        let user2: Principal = Principal::from_slice(&[2]);
+        let moderator: Principal = Principal::from_slice(&[3]);
+
+        let mut business_state = BusinessState::default();
+        business_state.set_max_entries(3);
+        business_state.add_content_moderator(moderator);
+
+        let mut entry = BucketEntry {
+            tag: "#rabbit".to_string(),
+            body: "Rabbits are fluffy animals".to_string(),
+            submitted_by: user1,
+            ..Default::default()
+        };
+
+        assert_eq!(business_state.insert_entry(entry.clone()), Some(0));
+
+        entry.tag = "#fox".to_string();
+        assert_eq!(business_state.insert_entry(entry.clone()), Some(1));
+
+        entry.submitted_by = Principal::anonymous();
+        assert_eq!(business_state.insert_entry(entry.clone()), Some(2));
+        assert_eq!(business_state.insert_entry(entry.clone()), None);
+
+        let found = business_state.get_entry(1, user1).unwrap();
+        assert_eq!(found.id, 1);
+        assert_eq!(found.tag, "#fox");
+
+        // Not the author, but moderators and anonymous entries are visible
+        assert!(business_state.get_entry(1, user2).is_none());
+        assert!(business_state.get_entry(1, moderator).is_some());
+        assert!(business_state.get_entry(2, user2).is_some());
+
+        assert!(business_state.get_entry(3, user1).is_none());
+    }
+
+    #[test]
+    fn reserved_entries() {
+        let bucket = Principal::from_slice(&[10]);
+        let user1 = Principal::from_slice(&[1]);
+        let user2 = Principal::from_slice(&[2]);
+        let key = b"bucket key".to_vec();
+
+        let mut business_state = BusinessState::default();
+        business_state.set_max_entries(1);
+
+        let entry = BucketEntry {
+            tag: "#rabbit".to_string(),
+            submitted_by: user1,
+            ..Default::default()
+        };
+        assert_eq!(business_state.insert_entry(entry.clone()), Some(0));
+        assert_eq!(business_state.insert_entry(entry.clone()), None);
+
+        // Until the Index sends the key, reservations can't be checked
+        let reservation = SlotReservation::new(1, bucket, user1, 100, &key);
+        assert_eq!(
+            business_state.insert_reserved_entry(entry.clone(), &reservation, bucket, 0),
+            None
+        );
+
+        // The reservation gets the entry in although the bucket is full, once
+        business_state.set_reservation_key(key.clone());
+        assert_eq!(
+            business_state.insert_reserved_entry(entry.clone(), &reservation, bucket, 0),
+            Some(1)
+        );
+        assert_eq!(
+            business_state.insert_reserved_entry(entry.clone(), &reservation, bucket, 0),
+            None
+        );
+
+        // Reservations for another bucket, another caller, or that expired
+        // aren't honoured
+        let other_bucket = SlotReservation::new(2, user2, user1, 100, &key);
+        let other_holder = SlotReservation::new(3, bucket, user2, 100, &key);
+        let expired = SlotReservation::new(4, bucket, user1, 100, &key);
+        for reservation in [other_bucket, other_holder].iter() {
+            assert_eq!(
+                business_state.insert_reserved_entry(entry.clone(), reservation, bucket, 0),
+                None
+            );
+        }
+        assert_eq!(
+            business_state.insert_reserved_entry(entry, &expired, bucket, 100),
+            None
+        );
+        assert_eq!(business_state.entries_count(), 2);
+
+        assert_eq!(
+            business_state.insert_entry_for_index(BucketEntry::default()),
+            Err(PostError::Full)
+        );
+        business_state.set_max_entries(3);
+        business_state.set_banned(user1, true, user2, "".to_string(), 0);
+        let entry = BucketEntry {
+            submitted_by: user1,
+            ..Default::default()
+        };
+        assert_eq!(
+            business_state.insert_entry_for_index(entry),
+            Err(PostError::Banned)
+        );
+        assert_eq!(
+            business_state.insert_entry_for_index(BucketEntry::default()),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn reserved_entries_within_bytes() {
+        let bucket = Principal::from_slice(&[10]);
+        let user = Principal::from_slice(&[1]);
+        let key = b"bucket key".to_vec();
+        let entry = |body: &str| BucketEntry {
+            tag: "#rabbit".to_string(),
+            body: body.to_string(),
+            submitted_by: user,
+            ..Default::default()
+        };
+
+        let mut business_state = BusinessState::default();
+        business_state.set_reservation_key(key.clone());
+        let small = entry_bytes(&entry("x"));
+        business_state.set_capacity_mode(CapacityMode::Bytes(2 * small));
+        business_state.add_entry(entry("x"));
+
+        // A body bigger than the bytes left is rejected, and the reservation
+        // can still be used for an entry that fits
+        let reservation = SlotReservation::new(1, bucket, user, 100, &key);
+        assert_eq!(
+            business_state.insert_reserved_entry(entry(&"x".repeat(100)), &reservation, bucket, 0),
+            None
+        );
+        assert_eq!(business_state.bytes_used(), small);
+
+        // No room at all once the heap reached its limit
+        business_state.set_memory_used(MAX_MEMORY_USED);
+        assert_eq!(
+            business_state.insert_reserved_entry(entry("x"), &reservation, bucket, 0),
+            None
+        );
+
+        business_state.set_memory_used(0);
+        assert_eq!(
+            business_state.insert_reserved_entry(entry("x"), &reservation, bucket, 0),
+            Some(1)
+        );
+        assert_eq!(business_state.bytes_used(), 2 * small);
+    }
+
+    #[test]
+    fn test_edit_and_delete() {
+        let user1: Principal = Principal::from_slice(&[1]);
+        let user2: Principal = Principal::from_slice(&[2]);
+
+        let mut business_state = BusinessState::default();
+        business_state.set_max_entries(2);
+
+        let entry = BucketEntry {
+            tag: "#rabbit".to_string(),
+            body: "Rabbits are fluffy animals".to_string(),
+            submitted_at: 10,
+            submitted_by: user1,
+            ..Default::default()
+        };
+        let id = business_state.insert_entry(entry.clone()).unwrap();
+
+        let entry = BucketEntry {
+            tag: "#fox".to_string(),
+            ..entry
+        };
+        let fox_id = business_state.insert_entry(entry).unwrap();
+
+        assert_eq!(
+            business_state.edit_entry(id, "Hacked".to_string(), user2, 20),
+            Err(EntryError::NotAuthor)
+        );
+        assert_eq!(
+            business_state.edit_entry(42, "Rabbits".to_string(), user1, 20),
+            Err(EntryError::NotFound)
+        );
+
+        business_state
+            .edit_entry(id, "Rabbits are cute".to_string(), user1, 20)
+            .unwrap();
+        business_state
+            .edit_entry(id, "Rabbits are cute and fluffy".to_string(), user1, 30)
+            .unwrap();
+
+        let edited = business_state.get_entry(id, user1).unwrap();
+        assert_eq!(edited.body, "Rabbits are cute and fluffy");
+        assert_eq!(edited.version, 2);
+        assert_eq!(edited.edited_at, Some(30));
+
+        let history = business_state.get_entry_history(id, user1);
+        assert_eq!(
+            history,
+            vec![
+                EntryRevision {
+                    version: 0,
+                    body: "Rabbits are fluffy animals".to_string(),
+                    created_at: 10,
+                },
+                EntryRevision {
+                    version: 1,
+                    body: "Rabbits are cute".to_string(),
+                    created_at: 20,
+                },
+            ]
+        );
+        assert!(business_state.get_entry_history(id, user2).is_empty());
+
+        // The bucket is full until something gets deleted
+        assert_eq!(business_state.insert_entry(BucketEntry::default()), None);
+
+        assert_eq!(
+            business_state.delete_entry(fox_id, user2),
+            Err(EntryError::NotAuthor)
+        );
+        business_state.delete_entry(fox_id, user1).unwrap();
+
+        assert_eq!(business_state.entries_count(), 1);
+        assert!(business_state.get_entry(fox_id, user1).is_none());
+        assert_eq!(
+            business_state.delete_entry(fox_id, user1),
+            Err(EntryError::NotFound)
+        );
+        assert_eq!(business_state.create_bucket_index(1).tags, vec!["#rabbit"]);
+
+        assert!(business_state
+            .insert_entry(BucketEntry::default())
+            .is_some());
+    }
+
+    #[test]
+    fn anonymous_entries_are_read_only() {
+        let mut business_state = BusinessState::default();
+
+        let id = business_state.insert_entry(BucketEntry::default()).unwrap();
+
+        assert_eq!(
+            business_state.edit_entry(id, "".to_string(), Principal::anonymous(), 0),
+            Err(EntryError::NotAuthor)
+        );
+        assert_eq!(
+            business_state.delete_entry(id, Principal::anonymous()),
+            Err(EntryError::NotAuthor)
+        );
+    }
+
+    #[test]
+    fn test_moderation() {
+        let user1: Principal = Principal::from_slice(&[1]);
+        let moderator: Principal = Principal::from_slice(&[3]);
+
+        let mut business_state = BusinessState::default();
+        business_state.add_content_moderator(moderator);
+
+        let entry = BucketEntry {
+            tag: "#rabbit".to_string(),
+            body: "Rabbits are fluffy animals".to_string(),
+            submitted_by: user1,
+            ..Default::default()
+        };
+        let id = business_state.insert_entry(entry.clone()).unwrap();
+        let other_id = business_state.insert_entry(entry.clone()).unwrap();
+
+        business_state
+            .set_entry_hidden(id, true, moderator, "spam".to_string(), 1)
+            .unwrap();
+        assert!(business_state.get_entry(id, user1).is_none());
+        assert!(business_state.get_entry(id, moderator).is_some());
+        assert_eq!(business_state.list_entries("#rabbit", user1).len(), 1);
+        assert_eq!(business_state.list_all_entries().len(), 2);
+
+        business_state
+            .set_entry_hidden(id, false, moderator, "not spam".to_string(), 2)
+            .unwrap();
+        assert!(business_state.get_entry(id, user1).is_some());
+
+        business_state
+            .moderator_remove_entry(other_id, moderator, "off topic".to_string(), 3)
+            .unwrap();
+        assert_eq!(business_state.entries_count(), 1);
+        assert_eq!(
+            business_state.moderator_remove_entry(other_id, moderator, "".to_string(), 4),
+            Err(EntryError::NotFound)
+        );
+
+        business_state.set_banned(user1, true, moderator, "troll".to_string(), 5);
+        assert_eq!(business_state.insert_entry(entry.clone()), None);
+        business_state.set_banned(user1, false, moderator, "".to_string(), 6);
+        assert!(business_state.insert_entry(entry).is_some());
+
+        let page = business_state.list_moderation_log(None, 3);
+        assert_eq!(
+            page.records.iter().map(|r| r.action).collect::<Vec<_>>(),
+            vec![
+                ModerationAction::Hide,
+                ModerationAction::Unhide,
+                ModerationAction::Remove
+            ]
+        );
+        assert_eq!(page.records[0].target, ModerationTarget::Entry(id));
+        assert_eq!(page.records[0].reason, "spam");
+        assert_eq!(page.next_cursor, Some(3));
+
+        let page = business_state.list_moderation_log(page.next_cursor, 3);
+        assert_eq!(page.records.len(), 2);
+        assert_eq!(page.records[0].target, ModerationTarget::Principal(user1));
+        assert_eq!(page.records[1].timestamp, 6);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn tag_scoped_roles() {
+        let user1: Principal = Principal::from_slice(&[1]);
+        let reader: Principal = Principal::from_slice(&[2]);
+        let moderator: Principal = Principal::from_slice(&[3]);
+
+        let mut business_state = BusinessState::default();
+        let roles = RoleList {
+            version: 2,
+            assignments: vec![
+                RoleAssignment {
+                    principal: reader,
+                    role: Role::Reader,
+                    scope: RoleScope::Tag("#rabbit".to_string()),
+                },
+                RoleAssignment {
+                    principal: moderator,
+                    role: Role::Moderator,
+                    scope: RoleScope::Tag("#rabbit".to_string()),
+                },
+            ],
+        };
+        assert_eq!(business_state.set_roles(roles.clone()), 2);
+
+        // A push that was overtaken by a newer one doesn't undo it
+        let stale = RoleList {
+            version: 1,
+            assignments: vec![],
+        };
+        assert_eq!(business_state.set_roles(stale), 2);
+        assert_eq!(business_state.get_roles(), roles.assignments);
+
+        let entry = BucketEntry {
+            tag: "#rabbit".to_string(),
+            submitted_by: user1,
+            ..Default::default()
+        };
+        let rabbit_id = business_state.insert_entry(entry.clone()).unwrap();
+
+        let entry = BucketEntry {
+            tag: "#fox".to_string(),
+            ..entry
+        };
+        let fox_id = business_state.insert_entry(entry).unwrap();
+
+        // Readers see other users' entries, but only for their tag
+        assert!(business_state.get_entry(rabbit_id, reader).is_some());
+        assert!(business_state.get_entry(fox_id, reader).is_none());
+        assert_eq!(business_state.list_entries("#rabbit", reader).len(), 1);
+
+        assert_eq!(
+            business_state.set_entry_hidden(fox_id, true, moderator, "".to_string(), 0),
+            Err(EntryError::Forbidden)
+        );
+        business_state
+            .set_entry_hidden(rabbit_id, true, moderator, "".to_string(), 0)
+            .unwrap();
+
+        assert!(business_state.get_entry(rabbit_id, reader).is_none());
+        assert!(business_state.get_entry(rabbit_id, moderator).is_some());
+
+        assert_eq!(
+            business_state.moderator_remove_entry(fox_id, reader, "".to_string(), 0),
+            Err(EntryError::Forbidden)
+        );
+        assert_eq!(business_state.get_content_moderators(), vec![moderator]);
+    }
+
+    #[test]
+    fn test_pagination() {
+        let user1: Principal = Principal::from_slice(&[1]);
+        let user2: Principal = Principal::from_slice(&[2]);
+
+        let mut business_state = BusinessState::default();
+        business_state.set_max_entries(10);
+
+        for i in 0..5 {
+            let entry = BucketEntry {
+                tag: "#rabbit".to_string(),
+                body: format!("Rabbit #{}", i),
+                submitted_at: i,
+                submitted_by: if i % 2 == 0 { user1 } else { user2 },
+                ..Default::default()
+            };
+            business_state.add_entry(entry);
+        }
+
+        // user1 can see entries 0, 2 and 4
+        let page = business_state.list_entries_page("#rabbit", user1, None, 2);
+        assert_eq!(
+            page.entries.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert_eq!(page.next_cursor, Some(4));
+
+        // New entries don't move the cursor
+        let entry = BucketEntry {
+            tag: "#rabbit".to_string(),
+            submitted_at: 5,
+            submitted_by: user1,
+            ..Default::default()
+        };
+        business_state.add_entry(entry);
+
+        let page = business_state.list_entries_page("#rabbit", user1, page.next_cursor, 2);
+        assert_eq!(
+            page.entries.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![4, 5]
+        );
+        assert_eq!(page.next_cursor, None);
+
+        let page = business_state.list_entries_page("#dog", user1, None, 2);
+        assert!(page.entries.is_empty());
+        assert_eq!(page.next_cursor, None);
+
+        let page = business_state.list_all_entries_page(Some(3), 100);
+        assert_eq!(
+            page.entries.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![3, 4, 5]
+        );
+        assert_eq!(page.next_cursor, None);
+
+        // Limits are clamped to at least one entry
+        let page = business_state.list_all_entries_page(None, 0);
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.next_cursor, Some(1));
+    }
+
+    #[test]
+    fn index_sync_parts() {
+        let tags = |range: std::ops::Range<u32>| -> Vec<String> {
+            range.map(|i| format!("#tag{}", i)).collect()
+        };
+
+        let mut bucket_index = BucketIndex {
+            effective_index: EffectiveIndex {
+                version: 1,
+                tags: tags(0..10),
+                current_entries: 10,
+                bucket_max_entries: 20,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Nothing acknowledged yet, everything is sent
+        let parts = bucket_index.prepare_sync();
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].full && parts[0].last_part);
+        assert_eq!(parts[0].added_tags.len(), 10);
+
+        let sent = bucket_index.effective_index.clone();
+        bucket_index.acknowledge(&sent);
+
+        // Only the difference is sent afterwards
+        bucket_index.effective_index = EffectiveIndex {
+            version: 2,
+            tags: tags(5..12),
+            current_entries: 12,
+            bucket_max_entries: 20,
+            ..Default::default()
+        };
+        let parts = bucket_index.prepare_sync();
+        assert_eq!(parts.len(), 1);
+        assert!(!parts[0].full);
+        assert_eq!(parts[0].base_version, 1);
+        assert_eq!(parts[0].added_tags, tags(10..12));
+        assert_eq!(parts[0].removed_tags, tags(0..5));
+
+        // Big updates are split
+        bucket_index.effective_index.tags = vec!["#".repeat(400_000); 5];
+        let parts = bucket_index.prepare_sync();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[2].part, 2);
+        assert!(parts[2].last_part && !parts[1].last_part);
+        // Still a delta against version 1
+        assert_eq!(parts[2].removed_tags, tags(0..10));
+        assert!(parts.iter().all(|p| p.added_tags.len() <= 2));
+    }
+
+    #[test]
+    fn index_pull() {
+        let index = |version: u64, tags: &[&str]| EffectiveIndex {
+            version,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            current_entries: 1,
+            bucket_max_entries: 20,
+            ..Default::default()
+        };
+
+        let mut bucket_index = BucketIndex {
+            effective_index: index(1, &["#fox", "#rabbit"]),
+            ..Default::default()
+        };
+        assert!(!bucket_index.pulled_recently(10));
+
+        let parts = bucket_index.prepare_pull(0, 10);
+        assert!(parts[0].full);
+        assert!(bucket_index.pulled_recently(10 + INDEX_PULL_TIMEOUT - 1));
+        assert!(!bucket_index.pulled_recently(10 + INDEX_PULL_TIMEOUT));
+
+        // Pulling with the served version acknowledges it
+        bucket_index.effective_index = index(2, &["#fox", "#cat"]);
+        let parts = bucket_index.prepare_pull(1, 20);
+        assert_eq!(bucket_index.acked_version, 1);
+        assert!(!parts[0].full);
+        assert_eq!(parts[0].added_tags, vec!["#cat".to_string()]);
+        assert_eq!(parts[0].removed_tags, vec!["#rabbit".to_string()]);
+
+        // Nothing new
+        assert!(bucket_index.prepare_pull(2, 30).is_empty());
+        assert_eq!(bucket_index.acked_version, 2);
+
+        // The Index lost track of our index, it gets the full one
+        let parts = bucket_index.prepare_pull(0, 40);
+        assert!(parts[0].full);
+        assert_eq!(parts[0].added_tags.len(), 2);
+    }
+
+    #[test]
+    fn index_sync_retries() {
+        let mut tasks: TaskQueue<BucketTask> = TaskQueue::default();
+        let mut bucket_index = BucketIndex {
+            effective_index: EffectiveIndex {
+                version: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        tasks.push(BucketTask::SyncIndex);
+        let leased = tasks.lease_next(100, |_| true).unwrap();
+        // One sync at a time
+        assert!(tasks.lease_next(100, |_| true).is_none());
+
+        let result = Err("Call failed".to_string());
+        assert!(tasks.fail(&leased, "Call failed".to_string(), 110));
+        bucket_index.record_sync_result(1, &result, 110);
+
+        let status = bucket_index.sync_status(tasks.find(&BucketTask::SyncIndex));
+        assert_eq!(status.attempts, 1);
+        assert_eq!(status.total_failures, 1);
+        assert_eq!(status.last_error, Some("Call failed".to_string()));
+        assert_eq!(status.next_attempt_at, 110 + INDEX_SYNC_BACKOFF);
+
+        // Backoff
+        assert!(tasks.lease_next(111, |_| true).is_none());
+        let leased = tasks
+            .lease_next(110 + INDEX_SYNC_BACKOFF, |_| true)
+            .unwrap();
+        tasks.fail(&leased, "Call failed".to_string(), 200);
+        assert_eq!(
+            tasks.find(&BucketTask::SyncIndex).unwrap().next_attempt_at,
+            200 + 2 * INDEX_SYNC_BACKOFF
+        );
+
+        // A stuck sync is given up once its lease expires, and its late result
+        // is ignored
+        let now = 200 + 2 * INDEX_SYNC_BACKOFF;
+        let stuck = tasks.lease_next(now, |_| true).unwrap();
+        assert!(tasks
+            .lease_next(now + INDEX_SYNC_LEASE - 1, |_| true)
+            .is_none());
+
+        let now = now + INDEX_SYNC_LEASE;
+        assert!(tasks.lease_next(now, |_| true).is_none());
+        let status = bucket_index.sync_status(tasks.find(&BucketTask::SyncIndex));
+        assert_eq!(status.attempts, 3);
+        assert_eq!(status.next_attempt_at, now + 4 * INDEX_SYNC_BACKOFF);
+        assert!(!tasks.complete(&stuck));
+
+        let leased = tasks
+            .lease_next(now + 4 * INDEX_SYNC_BACKOFF, |_| true)
+            .unwrap();
+        assert!(tasks.complete(&leased));
+        bucket_index.record_sync_result(1, &Ok(()), 1_000_000_000_000);
+
+        assert!(matches!(bucket_index.index_state, IndexState::Synced));
+        let status = bucket_index.sync_status(tasks.find(&BucketTask::SyncIndex));
+        assert_eq!(status.attempts, 0);
+        assert_eq!(status.last_success, Some(1_000_000_000_000));
+        assert_eq!(status.total_failures, 1);
+        assert!(tasks.lease_next(1_000_000_000_000, |_| true).is_none());
+    }
+
+    #[test]
+    fn one_index_sync_at_a_time() {
+        let mut tasks: TaskQueue<BucketTask> = TaskQueue::default();
+
+        tasks.push(BucketTask::SyncIndex);
+        let in_flight = tasks.lease_next(0, |_| true).unwrap();
+
+        // The index changes while the sync is in flight, the next heartbeats
+        // don't start a second one
+        tasks.push(BucketTask::SyncIndex);
+        assert!(tasks.lease_next(1, |_| true).is_none());
+        tasks.push(BucketTask::SyncIndex);
+        assert!(tasks.lease_next(2, |_| true).is_none());
+        assert_eq!(tasks.tasks().len(), 2);
+
+        // The queued sync follows once the first one is done
+        assert!(tasks.complete(&in_flight));
+        let next = tasks.lease_next(3, |_| true).unwrap();
+        assert_ne!(next.task_id, in_flight.task_id);
+        assert!(tasks.complete(&next));
+        assert!(tasks.tasks().is_empty());
+    }
+
     #[test]
     fn test_print() {
         let mut business_state = BusinessState::default();
@@ -275,6 +1789,7 @@ mod tests {
             body: "Rabbits are fluffy animals".to_string(),
             submitted_at: 0,
             submitted_by: Principal::anonymous(),
+            ..Default::default()
         };
 
         let _res = business_state.add_entry(entry.clone());
@@ -284,6 +1799,7 @@ mod tests {
             body: "Rabbits are cute animals".to_string(),
             submitted_at: 0,
             submitted_by: Principal::anonymous(),
+            ..Default::default()
         };
         let _res = business_state.add_entry(entry.clone());
 
@@ -292,6 +1808,7 @@ mod tests {
             body: "Rabbits are cute and fluffy animals".to_string(),
             submitted_at: 0,
             submitted_by: Principal::anonymous(),
+            ..Default::default()
         };
         let _res = business_state.add_entry(entry.clone());
 
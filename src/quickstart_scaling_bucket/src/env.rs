@@ -1,6 +1,4 @@
 use candid::Principal;
-use rand::rngs::StdRng;
-use rand::{RngCore, SeedableRng};
 
 pub type TimestampMillis = u64;
 type Cycles = u128;
@@ -9,29 +7,15 @@ pub trait Environment {
     fn now(&self) -> TimestampMillis;
     fn caller(&self) -> Principal;
     fn canister_id(&self) -> Principal;
-    fn random_u32(&mut self) -> u32;
     fn cycles_balance(&self) -> Cycles;
     fn memory_used(&self) -> u64;
 }
 
-pub struct CanisterEnv {
-    rng: StdRng,
-}
+pub struct CanisterEnv {}
 
 impl CanisterEnv {
     pub fn new() -> Self {
-        CanisterEnv {
-            // Seed the PRNG with the current time.
-            rng: {
-                let now_millis = ic_cdk::api::time();
-                let mut seed = [0u8; 32];
-                seed[..8].copy_from_slice(&now_millis.to_be_bytes());
-                seed[8..16].copy_from_slice(&now_millis.to_be_bytes());
-                seed[16..24].copy_from_slice(&now_millis.to_be_bytes());
-                seed[24..32].copy_from_slice(&now_millis.to_be_bytes());
-                StdRng::from_seed(seed)
-            },
-        }
+        CanisterEnv {}
     }
 }
 
@@ -48,10 +32,6 @@ impl Environment for CanisterEnv {
         ic_cdk::id()
     }
 
-    fn random_u32(&mut self) -> u32 {
-        self.rng.next_u32()
-    }
-
     fn cycles_balance(&self) -> Cycles {
         ic_cdk::api::canister_balance().into()
     }
@@ -75,7 +55,6 @@ pub struct TestEnv {
     pub now: u64,
     pub caller: Principal,
     pub canister_id: Principal,
-    pub random_u32: u32,
     pub cycles_balance: Cycles,
     pub memory_used: u64,
 }
@@ -93,10 +72,6 @@ impl Environment for TestEnv {
         self.canister_id
     }
 
-    fn random_u32(&mut self) -> u32 {
-        self.random_u32
-    }
-
     fn cycles_balance(&self) -> Cycles {
         self.cycles_balance
     }
@@ -121,10 +96,6 @@ impl Environment for EmptyEnv {
         Principal::anonymous()
     }
 
-    fn random_u32(&mut self) -> u32 {
-        0
-    }
-
     fn cycles_balance(&self) -> Cycles {
         0
     }
@@ -1,15 +1,22 @@
 mod businesslogic;
 mod env;
 mod lifetime;
+mod store;
 
 use crate::env::{CanisterEnv, EmptyEnv, Environment, TimestampMillis};
 use candid::{CandidType, Principal};
 use ic_cdk::print;
 use ic_cdk_macros::*;
+use quickstart_scaling_acl::{Role, RoleAssignment, RoleList};
+use quickstart_scaling_reservation::SlotReservation;
 use serde::Deserialize;
 
-use crate::businesslogic::{BucketEntry, BucketIndex, BucketMetrics, EffectiveIndex};
+use crate::businesslogic::{
+    BucketEntry, BucketIndex, BucketMetrics, BucketTask, EffectiveIndex, EntriesPage, EntryError,
+    EntryId, EntryRevision, GlobalEntryId, IndexUpdate, ModerationLogPage, PostError,
+};
 use businesslogic::BusinessState;
+use quickstart_scaling_tasks::TaskQueue;
 use std::cell::{Ref, RefCell, RefMut};
 
 thread_local! {
@@ -53,25 +60,153 @@ struct Data {
     canister_settings: BucketCanisterSettings,
     business_state: BusinessState,
     bucket_index: BucketIndex,
+    tasks: TaskQueue<BucketTask>,
 }
 
 // MAIN FUNCTIONALITY
 
 // Client facing functions are named using camelCase and are pretty self explanatory.
+// Returns the global id of the new entry, or None if the bucket is full.
+// The reservation is optional, it's what the Index's reserveUpload returned.
+// With a valid one the post is accepted even if the bucket filled up in the
+// meantime.
 #[update(name = "postContent")]
-fn post_content(tag: String, body: String) -> bool {
-    RUNTIME_STATE.with(|state| post_content_impl(tag, body, &mut state.borrow_mut()))
+fn post_content(
+    tag: String,
+    body: String,
+    reservation: Option<SlotReservation>,
+) -> Option<GlobalEntryId> {
+    RUNTIME_STATE.with(|state| post_content_impl(tag, body, reservation, &mut state.borrow_mut()))
 }
 
-fn post_content_impl(tag: String, body: String, runtime_state: &mut RefMut<RuntimeState>) -> bool {
+fn post_content_impl(
+    tag: String,
+    body: String,
+    reservation: Option<SlotReservation>,
+    runtime_state: &mut RefMut<RuntimeState>,
+) -> Option<GlobalEntryId> {
+    let now = runtime_state.env.now();
+    let canister_id = runtime_state.env.canister_id();
+    let memory_used = runtime_state.env.memory_used();
     let entry = BucketEntry {
         tag,
         body,
-        submitted_at: runtime_state.env.now(),
+        submitted_at: now,
         submitted_by: runtime_state.env.caller(),
+        ..Default::default()
+    };
+
+    let business_state = &mut runtime_state.data.business_state;
+    business_state.set_memory_used(memory_used);
+    let id = match reservation {
+        Some(reservation) => {
+            business_state.insert_reserved_entry(entry, &reservation, canister_id, now)?
+        }
+        None => business_state.insert_entry(entry)?,
     };
 
-    runtime_state.data.business_state.add_entry(entry)
+    Some(GlobalEntryId {
+        bucket: runtime_state.env.canister_id(),
+        id,
+    })
+}
+
+// The Index's postContent forwards entries with this call. The entry is
+// stored under the original caller, so bans and authorship work as if they
+// had posted here themselves.
+#[update(name = "post_content_for", guard = "is_index_canister")]
+fn post_content_for(
+    author: Principal,
+    tag: String,
+    body: String,
+) -> Result<GlobalEntryId, PostError> {
+    RUNTIME_STATE.with(|state| post_content_for_impl(author, tag, body, state.borrow_mut()))
+}
+
+fn post_content_for_impl(
+    author: Principal,
+    tag: String,
+    body: String,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Result<GlobalEntryId, PostError> {
+    let entry = BucketEntry {
+        tag,
+        body,
+        submitted_at: runtime_state.env.now(),
+        submitted_by: author,
+        ..Default::default()
+    };
+
+    let memory_used = runtime_state.env.memory_used();
+    let business_state = &mut runtime_state.data.business_state;
+    business_state.set_memory_used(memory_used);
+    let id = business_state.insert_entry_for_index(entry)?;
+
+    Ok(GlobalEntryId {
+        bucket: runtime_state.env.canister_id(),
+        id,
+    })
+}
+
+#[query(name = "getEntry")]
+fn get_entry(id: EntryId) -> Option<BucketEntry> {
+    RUNTIME_STATE.with(|state| get_entry_impl(id, state.borrow()))
+}
+
+fn get_entry_impl(id: EntryId, runtime_state: Ref<RuntimeState>) -> Option<BucketEntry> {
+    let caller = runtime_state.env.caller();
+
+    runtime_state.data.business_state.get_entry(id, caller)
+}
+
+// Only the author of an entry can edit or delete it. The previous body is kept
+// in the entry's history.
+#[update(name = "editContent")]
+fn edit_content(id: EntryId, body: String) -> Result<(), EntryError> {
+    RUNTIME_STATE.with(|state| edit_content_impl(id, body, state.borrow_mut()))
+}
+
+fn edit_content_impl(
+    id: EntryId,
+    body: String,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Result<(), EntryError> {
+    let caller = runtime_state.env.caller();
+    let now = runtime_state.env.now();
+    let memory_used = runtime_state.env.memory_used();
+
+    let business_state = &mut runtime_state.data.business_state;
+    business_state.set_memory_used(memory_used);
+    business_state.edit_entry(id, body, caller, now)
+}
+
+// Deleting frees the slot; the index learns about it with the next bucket index push
+#[update(name = "deleteContent")]
+fn delete_content(id: EntryId) -> Result<(), EntryError> {
+    RUNTIME_STATE.with(|state| delete_content_impl(id, state.borrow_mut()))
+}
+
+fn delete_content_impl(
+    id: EntryId,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Result<(), EntryError> {
+    let caller = runtime_state.env.caller();
+
+    runtime_state.data.business_state.delete_entry(id, caller)
+}
+
+#[query(name = "getEntryHistory")]
+fn get_entry_history(id: EntryId) -> Vec<EntryRevision> {
+    RUNTIME_STATE.with(|state| get_entry_history_impl(id, state.borrow()))
+}
+
+fn get_entry_history_impl(id: EntryId, runtime_state: Ref<RuntimeState>) -> Vec<EntryRevision> {
+    let caller = runtime_state.env.caller();
+
+    runtime_state
+        .data
+        .business_state
+        .get_entry_history(id, caller)
 }
 
 // This gets all entries that were uploaded by the user or by an anonymous user.
@@ -86,8 +221,28 @@ fn get_by_tag_impl(tag: String, runtime_state: Ref<RuntimeState>) -> Vec<BucketE
     runtime_state.data.business_state.list_entries(&tag, caller)
 }
 
-// used for demoing the "moderator" ACL functionality
-// A proper ACL implementation would be needed for production
+// Paginated getByTag. Entries are returned oldest first, pass the returned
+// next_cursor to fetch the following page.
+#[query(name = "getByTagPaged")]
+fn get_by_tag_paged(tag: String, cursor: Option<EntryId>, limit: u64) -> EntriesPage {
+    RUNTIME_STATE.with(|state| get_by_tag_paged_impl(tag, cursor, limit, state.borrow()))
+}
+
+fn get_by_tag_paged_impl(
+    tag: String,
+    cursor: Option<EntryId>,
+    limit: u64,
+    runtime_state: Ref<RuntimeState>,
+) -> EntriesPage {
+    let caller = runtime_state.env.caller();
+
+    runtime_state
+        .data
+        .business_state
+        .list_entries_page(&tag, caller, cursor, limit)
+}
+
+// Moderators with a global role can read every entry in the bucket
 #[query(name = "getAll", guard = "is_content_moderator")]
 fn get_all() -> Vec<BucketEntry> {
     RUNTIME_STATE.with(|state| get_all_impl(state.borrow()))
@@ -97,10 +252,115 @@ fn get_all_impl(runtime_state: Ref<RuntimeState>) -> Vec<BucketEntry> {
     runtime_state.data.business_state.list_all_entries()
 }
 
+#[query(name = "getAllPaged", guard = "is_content_moderator")]
+fn get_all_paged(cursor: Option<EntryId>, limit: u64) -> EntriesPage {
+    RUNTIME_STATE.with(|state| get_all_paged_impl(cursor, limit, state.borrow()))
+}
+
+fn get_all_paged_impl(
+    cursor: Option<EntryId>,
+    limit: u64,
+    runtime_state: Ref<RuntimeState>,
+) -> EntriesPage {
+    runtime_state
+        .data
+        .business_state
+        .list_all_entries_page(cursor, limit)
+}
+
+// MODERATION
+// Every action is recorded in the moderation log together with the moderator
+// and the reason they gave. Entry actions are open to tag moderators, the
+// business logic checks that their role covers the entry's tag.
+#[update(name = "hideContent", guard = "is_tag_moderator")]
+fn hide_content(id: EntryId, reason: String) -> Result<(), EntryError> {
+    RUNTIME_STATE.with(|state| set_content_hidden_impl(id, true, reason, state.borrow_mut()))
+}
+
+#[update(name = "unhideContent", guard = "is_tag_moderator")]
+fn unhide_content(id: EntryId, reason: String) -> Result<(), EntryError> {
+    RUNTIME_STATE.with(|state| set_content_hidden_impl(id, false, reason, state.borrow_mut()))
+}
+
+fn set_content_hidden_impl(
+    id: EntryId,
+    hidden: bool,
+    reason: String,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Result<(), EntryError> {
+    let actor = runtime_state.env.caller();
+    let now = runtime_state.env.now();
+
+    runtime_state
+        .data
+        .business_state
+        .set_entry_hidden(id, hidden, actor, reason, now)
+}
+
+// Permanently removes the entry and frees its slot
+#[update(name = "removeContent", guard = "is_tag_moderator")]
+fn remove_content(id: EntryId, reason: String) -> Result<(), EntryError> {
+    RUNTIME_STATE.with(|state| remove_content_impl(id, reason, state.borrow_mut()))
+}
+
+fn remove_content_impl(
+    id: EntryId,
+    reason: String,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Result<(), EntryError> {
+    let actor = runtime_state.env.caller();
+    let now = runtime_state.env.now();
+
+    runtime_state
+        .data
+        .business_state
+        .moderator_remove_entry(id, actor, reason, now)
+}
+
+// Banned principals can't post to this bucket anymore
+#[update(name = "banPrincipal", guard = "is_content_moderator")]
+fn ban_principal(principal: Principal, reason: String) {
+    RUNTIME_STATE.with(|state| set_banned_impl(principal, true, reason, state.borrow_mut()))
+}
+
+#[update(name = "unbanPrincipal", guard = "is_content_moderator")]
+fn unban_principal(principal: Principal, reason: String) {
+    RUNTIME_STATE.with(|state| set_banned_impl(principal, false, reason, state.borrow_mut()))
+}
+
+fn set_banned_impl(
+    principal: Principal,
+    banned: bool,
+    reason: String,
+    mut runtime_state: RefMut<RuntimeState>,
+) {
+    let actor = runtime_state.env.caller();
+    let now = runtime_state.env.now();
+
+    runtime_state
+        .data
+        .business_state
+        .set_banned(principal, banned, actor, reason, now)
+}
+
+#[query(name = "getModerationLog", guard = "is_content_moderator")]
+fn get_moderation_log(cursor: Option<u64>, limit: u64) -> ModerationLogPage {
+    RUNTIME_STATE.with(|state| get_moderation_log_impl(cursor, limit, state.borrow()))
+}
+
+fn get_moderation_log_impl(
+    cursor: Option<u64>,
+    limit: u64,
+    runtime_state: Ref<RuntimeState>,
+) -> ModerationLogPage {
+    runtime_state
+        .data
+        .business_state
+        .list_moderation_log(cursor, limit)
+}
+
 // Used for debug and demo purposes. Doesn't serve a business logic purpose.
-// Could be changed to an "update" if the app needs to move to a pull index architecture
-// (i.e. the Index canister would pull bucket index info). This would remove the
-// need for heartbeat functionality on the bucket canister.
+// In Pull mode the Index canister uses pull_bucket_index instead.
 #[query(name = "getBucketIndex")]
 fn get_bucket_index() -> EffectiveIndex {
     RUNTIME_STATE.with(|state| get_bucket_index_impl(state.borrow()))
@@ -113,20 +373,61 @@ fn get_bucket_index_impl(runtime_state: Ref<RuntimeState>) -> EffectiveIndex {
     runtime_state.data.bucket_index.effective_index.clone()
 }
 
-// The Index canister will update the moderators list using this update call
-#[update(name = "add_content_moderators", guard = "is_controller")]
-fn add_content_moderators(moderators: Vec<Principal>) {
-    RUNTIME_STATE.with(|state| add_content_moderators_impl(moderators, state.borrow_mut()))
+// Pull mode: the Index canister fetches our index instead of us pushing it
+// from the heartbeat. known_version is the last version it applied, later
+// parts of an update are served from the index generated for part 0.
+#[update(name = "pull_bucket_index", guard = "is_index_canister")]
+fn pull_bucket_index(known_version: u64, part: u32) -> Option<IndexUpdate> {
+    RUNTIME_STATE.with(|state| pull_bucket_index_impl(known_version, part, &mut state.borrow_mut()))
 }
 
-fn add_content_moderators_impl(
-    moderators: Vec<Principal>,
-    mut runtime_state: RefMut<RuntimeState>,
-) {
+fn pull_bucket_index_impl(
+    known_version: u64,
+    part: u32,
+    runtime_state: &mut RefMut<RuntimeState>,
+) -> Option<IndexUpdate> {
+    if part == 0 {
+        lifetime::generate_bucket_index(runtime_state);
+    }
+    let now = runtime_state.env.now();
+
     runtime_state
         .data
-        .business_state
-        .add_content_moderators(moderators)
+        .bucket_index
+        .prepare_pull(known_version, now)
+        .into_iter()
+        .nth(part as usize)
+}
+
+// The key the Index signs our upload reservations with
+#[update(name = "set_reservation_key", guard = "is_index_canister")]
+fn set_reservation_key(key: Vec<u8>) -> bool {
+    RUNTIME_STATE.with(|state| {
+        state
+            .borrow_mut()
+            .data
+            .business_state
+            .set_reservation_key(key)
+    });
+
+    true
+}
+
+// The Index canister owns the role assignments and pushes the full list
+// using this update call. Returns the version of the list we have, which the
+// Index records as acknowledged.
+#[update(name = "set_roles", guard = "is_index_canister")]
+fn set_roles(roles: RoleList) -> u64 {
+    RUNTIME_STATE.with(|state| set_roles_impl(roles, state.borrow_mut()))
+}
+
+fn set_roles_impl(roles: RoleList, mut runtime_state: RefMut<RuntimeState>) -> u64 {
+    runtime_state.data.business_state.set_roles(roles)
+}
+
+#[query(name = "getRoles", guard = "is_content_moderator")]
+fn get_roles() -> Vec<RoleAssignment> {
+    RUNTIME_STATE.with(|state| state.borrow().data.business_state.get_roles())
 }
 
 // CANISTER LOGISTICS
@@ -150,6 +451,13 @@ fn get_metrics_impl(runtime_state: Ref<RuntimeState>) -> BucketMetrics {
         max_entries: runtime_state.data.business_state.max_entries(),
         current_entries: runtime_state.data.business_state.entries_count(),
         memory_used: runtime_state.env.memory_used(),
+        bytes_used: runtime_state.data.business_state.bytes_used(),
+        bytes_capacity: runtime_state.data.business_state.bytes_capacity(),
+        index_sync: runtime_state
+            .data
+            .bucket_index
+            .sync_status(runtime_state.data.tasks.find(&BucketTask::SyncIndex)),
+        roles_version: runtime_state.data.business_state.roles_version(),
     }
 }
 
@@ -208,16 +516,113 @@ fn __get_candid_interface_tmp_hack() -> String {
     let did = r#"
     
     type BucketEntry = record {
+        id: nat64;
         tag: text;
         body: text; 
         submitted_at: nat64;
         submitted_by: principal;
+        version: nat32;
+        edited_at: opt nat64;
+        hidden: bool;
+    };
+
+    type EntryRevision = record {
+        version: nat32;
+        body: text;
+        created_at: nat64;
+    };
+
+    type EntryError = variant {
+        NotFound;
+        NotAuthor;
+        Forbidden;
+        Full;
+    };
+
+    type EntryResult = variant {
+        Ok;
+        Err: EntryError;
+    };
+
+    type ModerationAction = variant {
+        Hide;
+        Unhide;
+        Remove;
+        Ban;
+        Unban;
+    };
+
+    type ModerationTarget = variant {
+        Entry: nat64;
+        Principal: principal;
+    };
+
+    type ModerationRecord = record {
+        id: nat64;
+        actor: principal;
+        action: ModerationAction;
+        target: ModerationTarget;
+        reason: text;
+        timestamp: nat64;
+    };
+
+    type ModerationLogPage = record {
+        records: vec ModerationRecord;
+        next_cursor: opt nat64;
     };
     
+    type Role = variant {
+        Reader;
+        Moderator;
+        Admin;
+        Owner;
+    };
+
+    type RoleScope = variant {
+        Global;
+        Tag: text;
+    };
+
+    type RoleAssignment = record {
+        principal: principal;
+        role: Role;
+        scope: RoleScope;
+    };
+
+    type GlobalEntryId = record {
+        bucket: principal;
+        id: nat64;
+    };
+
+    type SlotReservation = record {
+        id: nat64;
+        bucket: principal;
+        holder: principal;
+        expires_at: nat64;
+        signature: blob;
+    };
+
+    type EntriesPage = record {
+        entries: vec BucketEntry;
+        next_cursor: opt nat64;
+    };
+
     type EffectiveIndex = record {
+        version: nat64;
         tags: vec text;
         current_entries: nat64;
         bucket_max_entries: nat64;
+        bytes_used: nat64;
+        bytes_capacity: nat64;
+    };
+
+    type IndexSyncStatus = record {
+        last_success: opt nat64;
+        last_error: opt text;
+        last_error_at: opt nat64;
+        attempts: nat32;
+        total_failures: nat64;
+        next_attempt_at: nat64;
     };
 
     type BucketMetrics = record {
@@ -229,13 +634,30 @@ fn __get_candid_interface_tmp_hack() -> String {
         max_entries: nat64;
         current_entries: nat64;
         memory_used: nat64;
+        bytes_used: nat64;
+        bytes_capacity: nat64;
+        index_sync: IndexSyncStatus;
+        roles_version: nat64;
     };
     
     service : {
     "getMetrics" : () -> (BucketMetrics) query;
-    "postContent" : (text, text) -> (bool);
+    "postContent" : (text, text, opt SlotReservation) -> (opt GlobalEntryId);
+    "getEntry" : (nat64) -> (opt BucketEntry) query;
+    "getEntryHistory" : (nat64) -> (vec EntryRevision) query;
+    "editContent" : (nat64, text) -> (EntryResult);
+    "deleteContent" : (nat64) -> (EntryResult);
     "getAll" : () -> (vec BucketEntry) query;
     "getByTag" : (text) -> (vec BucketEntry) query;
+    "getAllPaged" : (opt nat64, nat64) -> (EntriesPage) query;
+    "getByTagPaged" : (text, opt nat64, nat64) -> (EntriesPage) query;
+    "hideContent" : (nat64, text) -> (EntryResult);
+    "unhideContent" : (nat64, text) -> (EntryResult);
+    "removeContent" : (nat64, text) -> (EntryResult);
+    "banPrincipal" : (principal, text) -> ();
+    "unbanPrincipal" : (principal, text) -> ();
+    "getModerationLog" : (opt nat64, nat64) -> (ModerationLogPage) query;
+    "getRoles" : () -> (vec RoleAssignment) query;
     "getBucketIndex" : () -> (EffectiveIndex) query;
     }
     "#;
@@ -260,14 +682,43 @@ fn is_controller() -> Result<(), String> {
     })
 }
 
+// Only the Index canister that spawned us can push role assignments. This
+// doesn't depend on the controllers list, which can be changed outside the app.
+fn is_index_canister() -> Result<(), String> {
+    RUNTIME_STATE.with(|state| {
+        if state.borrow().data.canister_settings.index_canister_id
+            == Some(state.borrow().env.caller())
+        {
+            Ok(())
+        } else {
+            Err("You are not the index canister".to_string())
+        }
+    })
+}
+
+// Moderator (or higher) for the whole system
 fn is_content_moderator() -> Result<(), String> {
+    RUNTIME_STATE.with(|state| {
+        if state.borrow().data.business_state.has_role(
+            &state.borrow().env.caller(),
+            Role::Moderator,
+            None,
+        ) {
+            Ok(())
+        } else {
+            Err("You are not a moderator".to_string())
+        }
+    })
+}
+
+// Moderator (or higher) for at least one tag
+fn is_tag_moderator() -> Result<(), String> {
     RUNTIME_STATE.with(|state| {
         if state
             .borrow()
             .data
             .business_state
-            .get_content_moderators()
-            .contains(&state.borrow().env.caller())
+            .has_role_anywhere(&state.borrow().env.caller(), Role::Moderator)
         {
             Ok(())
         } else {
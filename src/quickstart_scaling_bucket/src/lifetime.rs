@@ -1,17 +1,29 @@
-use crate::businesslogic::IndexState;
-use crate::{BucketIndex, CanisterEnv, Data, RuntimeState, RUNTIME_STATE};
-use candid::Deserialize;
+use crate::businesslogic::{
+    BucketEntry, BucketTask, CapacityMode, EntryId, EntryRevision, IndexState, IndexSyncResult,
+    ModerationRecord,
+};
+use crate::store::{CanisterMemory, EntryStore};
+use crate::{
+    BucketCanisterSettings, CanisterEnv, Data, RuntimeState, TimestampMillis, RUNTIME_STATE,
+};
+use candid::parser::value::IDLValue;
+use candid::{Decode, Deserialize, Encode};
 use ic_cdk::api::call::CallResult;
 use ic_cdk::export::candid::CandidType;
 use ic_cdk::export::Principal;
 use ic_cdk::print;
 use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade};
+use quickstart_scaling_acl::{AccessControl, RoleList};
+use quickstart_scaling_tasks::LeasedTask;
 use std::cell::RefMut;
+use std::collections::{BTreeMap, HashMap};
 
 #[init]
 fn init() {
     let env = Box::new(CanisterEnv::new());
-    let data = Data::default();
+    let mut data = Data::default();
+    data.business_state
+        .set_store(EntryStore::open(Box::new(CanisterMemory)));
     let mut runtime_state = RuntimeState { env, data };
 
     let caller_id = ic_cdk::api::caller();
@@ -29,104 +41,395 @@ fn init() {
     struct SendArgs {
         greet: String,
         controllers: Vec<Principal>,
+        bucket_max_entries: u64,
+        // None when installed by an Index that only counts entries
+        bucket_capacity_mode: Option<CapacityMode>,
+        // None when installed by an Index that doesn't send the roles
+        roles: Option<RoleList>,
+        // Checks the upload reservations the Index signs for us
+        reservation_key: Option<Vec<u8>>,
     }
 
     let call_arg = ic_cdk::api::call::arg_data::<(Option<SendArgs>,)>().0;
 
     ic_cdk::print(format!("{:?}", call_arg));
 
-    // Add the additional controllers received from the Index canister
-    for controller in call_arg.unwrap_or(SendArgs::default()).controllers.iter() {
+    if let Some(send_args) = call_arg {
+        // Add the additional controllers received from the Index canister
+        for controller in send_args.controllers.iter() {
+            runtime_state
+                .data
+                .canister_settings
+                .controllers
+                .push(controller.clone());
+        }
+
+        // The Index canister decides how big its buckets are
         runtime_state
             .data
-            .canister_settings
-            .controllers
-            .push(controller.clone());
+            .business_state
+            .set_max_entries(send_args.bucket_max_entries);
+        if let Some(capacity_mode) = send_args.bucket_capacity_mode {
+            runtime_state
+                .data
+                .business_state
+                .set_capacity_mode(capacity_mode);
+        }
+
+        // So we enforce the roles from the start instead of waiting for the
+        // first push
+        if let Some(roles) = send_args.roles {
+            runtime_state.data.business_state.set_roles(roles);
+        }
+        if let Some(key) = send_args.reservation_key {
+            runtime_state.data.business_state.set_reservation_key(key);
+        }
     }
 
     RUNTIME_STATE.with(|state| *state.borrow_mut() = runtime_state);
 }
 
+// The entries stay where they are in the store. The rest of the state is
+// small, it's saved in the store next to them.
 #[pre_upgrade]
 fn pre_upgrade() {
-    RUNTIME_STATE.with(|state| ic_cdk::storage::stable_save((&state.borrow().data,)).unwrap());
+    RUNTIME_STATE.with(|state| {
+        let data = &mut state.borrow_mut().data;
+        let bytes = Encode!(&*data).unwrap();
+        data.business_state.store_mut().save_upgrade_data(&bytes);
+    });
+}
+
+// What a bucket from before the store saved with stable_save. Only the fields
+// every version had are required, so the original layout and the later ones
+// all decode.
+#[derive(CandidType, Deserialize)]
+struct LegacyData {
+    canister_settings: BucketCanisterSettings,
+    business_state: LegacyBusinessState,
+    bucket_index: LegacyBucketIndex,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyBusinessState {
+    entries: HashMap<String, Vec<LegacyEntry>>,
+    bucket_max_entries: u64,
+    // Came with the entry ids and the edits
+    next_entry_id: Option<EntryId>,
+    entry_history: Option<HashMap<EntryId, Vec<EntryRevision>>>,
+    // Replaced by the roles the Index pushes
+    content_moderators: Option<Vec<Principal>>,
+    // Came with the moderation actions and the roles, now in the store
+    access_control: Option<AccessControl>,
+    banned_principals: Option<Vec<Principal>>,
+    moderation_log: Option<Vec<ModerationRecord>>,
+    roles_version: Option<u64>,
+    // Came with the byte capacity and the upload reservations
+    capacity_mode: Option<CapacityMode>,
+    reservation_key: Option<Vec<u8>>,
+    used_reservations: Option<BTreeMap<u64, TimestampMillis>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyBucketIndex {
+    effective_index: LegacyEffectiveIndex,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyEffectiveIndex {
+    // Came with the index versions
+    version: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyEntry {
+    id: Option<EntryId>,
+    tag: String,
+    body: String,
+    submitted_at: TimestampMillis,
+    submitted_by: Principal,
+    version: Option<u32>,
+    edited_at: Option<TimestampMillis>,
+    hidden: Option<bool>,
 }
 
 #[post_upgrade]
 fn post_upgrade() {
     let env = Box::new(CanisterEnv::new());
-    let (data,): (Data,) = ic_cdk::storage::stable_restore().unwrap();
+
+    let data = if EntryStore::is_initialized(&CanisterMemory) {
+        let store = EntryStore::open(Box::new(CanisterMemory));
+        let bytes = store.upgrade_data().expect("pre_upgrade saves the state");
+        let mut data = Decode!(&bytes, Data).unwrap();
+        data.business_state.set_store(store);
+        data
+    } else {
+        // Buckets from before the store saved everything with stable_save.
+        // It's read before the store overwrites the memory.
+        let bytes = ic_cdk::api::stable::stable_bytes();
+        restore_legacy_data(&bytes, EntryStore::new(Box::new(CanisterMemory)))
+    };
     let runtime_state = RuntimeState { env, data };
 
     RUNTIME_STATE.with(|state| *state.borrow_mut() = runtime_state);
 }
 
+// Moves the state saved by a bucket from before the store to `store`. Every
+// field a version saved is kept, a field that doesn't decode traps and the
+// upgrade is rolled back. Entries from before the entry ids get ids in the
+// order they were posted. The index is sent again in full, with a version
+// above the last one the Index has.
+fn restore_legacy_data(bytes: &[u8], store: EntryStore) -> Data {
+    let legacy: LegacyData = decode_saved(bytes).unwrap();
+    let legacy_state = legacy.business_state;
+
+    let saved: IDLValue = decode_saved(bytes).unwrap();
+    let saved_state = saved_field(Some(&saved), "business_state");
+    check_read(
+        saved_state,
+        &[
+            ("next_entry_id", legacy_state.next_entry_id.is_some()),
+            ("entry_history", legacy_state.entry_history.is_some()),
+            (
+                "content_moderators",
+                legacy_state.content_moderators.is_some(),
+            ),
+            ("access_control", legacy_state.access_control.is_some()),
+            (
+                "banned_principals",
+                legacy_state.banned_principals.is_some(),
+            ),
+            ("moderation_log", legacy_state.moderation_log.is_some()),
+            ("roles_version", legacy_state.roles_version.is_some()),
+            ("capacity_mode", legacy_state.capacity_mode.is_some()),
+            ("reservation_key", legacy_state.reservation_key.is_some()),
+            (
+                "used_reservations",
+                legacy_state.used_reservations.is_some(),
+            ),
+        ],
+    );
+    check_read(
+        saved_field(saved_field(Some(&saved), "bucket_index"), "effective_index"),
+        &[(
+            "version",
+            legacy.bucket_index.effective_index.version.is_some(),
+        )],
+    );
+
+    let mut data = Data {
+        canister_settings: legacy.canister_settings,
+        ..Default::default()
+    };
+    data.bucket_index.effective_index.version =
+        legacy.bucket_index.effective_index.version.unwrap_or(0);
+
+    let business_state = &mut data.business_state;
+    business_state.set_store(store);
+    business_state.set_max_entries(legacy_state.bucket_max_entries);
+    if let Some(capacity_mode) = legacy_state.capacity_mode {
+        business_state.set_capacity_mode(capacity_mode);
+    }
+    if let Some(key) = legacy_state.reservation_key {
+        business_state.set_reservation_key(key);
+    }
+    business_state.migrate_used_reservations(legacy_state.used_reservations.unwrap_or_default());
+
+    for moderator in legacy_state.content_moderators.unwrap_or_default() {
+        business_state.add_content_moderator(moderator);
+    }
+    if let Some(access_control) = legacy_state.access_control {
+        business_state.set_roles(RoleList {
+            version: legacy_state.roles_version.unwrap_or(0),
+            assignments: access_control.assignments(),
+        });
+    }
+    business_state.migrate_moderation(
+        legacy_state.moderation_log.unwrap_or_default(),
+        legacy_state.banned_principals.unwrap_or_default(),
+    );
+
+    let mut entries: Vec<LegacyEntry> = legacy_state.entries.into_values().flatten().collect();
+    entries.sort_by_key(|e| (e.id, e.submitted_at));
+
+    let mut next_id = entries
+        .iter()
+        .filter_map(|e| e.id)
+        .map(|id| id + 1)
+        .chain(legacy_state.next_entry_id)
+        .max()
+        .unwrap_or(0);
+    let entries = entries
+        .into_iter()
+        .map(|e| BucketEntry {
+            id: e.id.unwrap_or_else(|| {
+                next_id += 1;
+                next_id - 1
+            }),
+            tag: e.tag,
+            body: e.body,
+            submitted_at: e.submitted_at,
+            submitted_by: e.submitted_by,
+            version: e.version.unwrap_or(0),
+            edited_at: e.edited_at,
+            hidden: e.hidden.unwrap_or(false),
+        })
+        .collect();
+
+    data.business_state.migrate_entries(
+        entries,
+        legacy_state.entry_history.unwrap_or_default(),
+        next_id,
+    );
+    data
+}
+
+// Candid reads an opt field of the wrong type as null. Every field of `record`
+// that was saved with a value must have been read, or it would be dropped.
+fn check_read(record: Option<&IDLValue>, fields: &[(&str, bool)]) {
+    for (name, read) in fields {
+        let saved = saved_field(record, name)
+            .is_some_and(|value| !matches!(value, IDLValue::Null | IDLValue::None));
+        assert!(*read || !saved, "The saved {} can't be read", name);
+    }
+}
+
+fn saved_field<'a>(record: Option<&'a IDLValue>, name: &str) -> Option<&'a IDLValue> {
+    match record {
+        Some(IDLValue::Record(fields)) => fields
+            .iter()
+            .find(|field| field.id.get_id() == candid::idl_hash(name))
+            .map(|field| &field.val),
+        _ => None,
+    }
+}
+
+// Reads a value saved with stable_save. Stable memory comes in pages, the
+// bytes after the value are ignored.
+fn decode_saved<T: for<'de> Deserialize<'de> + CandidType>(bytes: &[u8]) -> candid::Result<T> {
+    candid::de::IDLDeserialize::new(bytes)?.get_value()
+}
+
 #[heartbeat]
 async fn heartbeat() {
+    // The Index pulls our index in Pull mode, nothing to do here
+    if RUNTIME_STATE.with(|state| {
+        let runtime_state = state.borrow();
+        runtime_state
+            .data
+            .bucket_index
+            .pulled_recently(runtime_state.env.now())
+    }) {
+        return;
+    }
+
     // re-index
     RUNTIME_STATE.with(|state| generate_bucket_index(&mut state.borrow_mut()));
 
-    // send index
+    // send index, retried with a backoff by the task queue if it fails
+    run_tasks().await
+}
 
-    // Send index only if it's in the New state (this prevents multiple attempts
-    // at sending the same index if one attempt lasts longer and another
-    // heartbeat is triggered.
-    if let IndexState::New =
-        RUNTIME_STATE.with(|state| state.borrow().data.bucket_index.index_state)
-    {
-        send_index().await
+async fn run_tasks() {
+    // Take ownership of the next task that is due. Only one attempt at a time
+    // holds a task (this prevents multiple attempts at sending the same index
+    // if one attempt lasts longer and another heartbeat is triggered).
+    let leased = RUNTIME_STATE.with(|state| {
+        let runtime_state = &mut *state.borrow_mut();
+        let now = runtime_state.env.now();
+        runtime_state.data.tasks.lease_next(now, |_| true)
+    });
+
+    if let Some(leased) = leased {
+        match leased.kind {
+            BucketTask::SyncIndex => send_index(leased).await,
+        }
     }
 }
 
-async fn send_index() {
-    // Take ownership of the task
-    let rand_id = RUNTIME_STATE.with(|state| {
-        let some_rand = state.borrow_mut().env.random_u32();
-        state.borrow_mut().data.bucket_index.index_state = IndexState::InSync(some_rand);
-        some_rand
+async fn send_index(leased: LeasedTask<BucketTask>) {
+    let (effective_index, parts) = RUNTIME_STATE.with(|state| {
+        let bucket_index = &state.borrow().data.bucket_index;
+        (
+            bucket_index.effective_index.clone(),
+            bucket_index.prepare_sync(),
+        )
     });
-
-    let effective_index =
-        RUNTIME_STATE.with(|state| state.borrow().data.bucket_index.effective_index.clone());
+    let version = effective_index.version;
 
     let index_canister_id = RUNTIME_STATE
         .with(|state| state.borrow().data.canister_settings.index_canister_id)
         .unwrap();
 
-    // Actually send the index
-    let call_succeeded: CallResult<(bool,)> =
-        ic_cdk::api::call::call(index_canister_id, "add_bucket_index", (effective_index,)).await;
+    // Actually send the index, one part at a time. The Index applies the
+    // update when it gets the last part.
+    let mut result = Err("The Index didn't apply the update".to_string());
+    for part in parts {
+        let call_succeeded: CallResult<(IndexSyncResult,)> =
+            ic_cdk::api::call::call(index_canister_id, "sync_bucket_index", (part,)).await;
 
-    if let Err((code, msg)) = call_succeeded {
-        print(format!("Error! Code:{:?} Msg:{:?}", code, msg));
-        // Set the task to new so the next iteration of heartbeat can work on it
-        RUNTIME_STATE
-            .with(|state| state.borrow_mut().data.bucket_index.index_state = IndexState::New);
-    } else {
-        // Set the task to completed
-        // Check if it is still the task we took ownership of before await.
-        if let IndexState::InSync(lock) =
-            RUNTIME_STATE.with(|state| state.borrow().data.bucket_index.index_state)
-        {
-            if lock == rand_id {
-                // Actually set the task to completed
-                RUNTIME_STATE.with(|state| {
-                    state.borrow_mut().data.bucket_index.index_state = IndexState::Synced
-                });
+        match call_succeeded {
+            Ok((IndexSyncResult::PartAccepted,)) => continue,
+            Ok((IndexSyncResult::Applied(accepted_version),)) => {
+                // A newer version means another sync got there first and
+                // acknowledges its own index
+                if accepted_version == version {
+                    RUNTIME_STATE.with(|state| {
+                        state
+                            .borrow_mut()
+                            .data
+                            .bucket_index
+                            .acknowledge(&effective_index)
+                    });
+                }
+                if accepted_version >= version {
+                    result = Ok(());
+                }
             }
-            // Else? What should we do if the state got rolled back while awaiting?
-            // Set the task to new and pretend nothing happened?
-            // Let the task stuck in InSync and add a module that deals with stuck tasks?
-            else {
+            Ok((IndexSyncResult::ResyncNeeded,)) => {
+                // The Index doesn't have the version our delta is based on,
+                // the next attempt sends the full index
                 RUNTIME_STATE.with(|state| {
-                    state.borrow_mut().data.bucket_index.index_state = IndexState::New
+                    let bucket_index = &mut state.borrow_mut().data.bucket_index;
+                    bucket_index.acked_version = 0;
+                    bucket_index.acked_tags = vec![];
                 });
+                result = Err("The Index needs our full index".to_string());
+            }
+            Ok((IndexSyncResult::Rejected,)) => {
+                result = Err("The Index canister rejected our index".to_string());
+            }
+            Err((code, msg)) => {
+                result = Err(format!("Error! Code:{:?} Msg:{:?}", code, msg));
             }
         }
+        break;
     }
+
+    if let Err(error) = &result {
+        print(error);
+    }
+
+    RUNTIME_STATE.with(|state| {
+        let runtime_state = &mut *state.borrow_mut();
+        let now = runtime_state.env.now();
+        let held = match &result {
+            Ok(()) => runtime_state.data.tasks.complete(&leased),
+            Err(error) => runtime_state.data.tasks.fail(&leased, error.clone(), now),
+        };
+
+        // The result of an attempt that lost its lease is ignored, the attempt
+        // that took over reports its own
+        if held {
+            runtime_state
+                .data
+                .bucket_index
+                .record_sync_result(version, &result, now);
+        }
+    });
 }
 
-fn generate_bucket_index(runtime_state: &mut RefMut<RuntimeState>) {
+pub(crate) fn generate_bucket_index(runtime_state: &mut RefMut<RuntimeState>) {
     //Only re-index if the last index is older than canister_settings.reindex_interval
     if runtime_state.env.now() - runtime_state.data.bucket_index.last_updated
         > runtime_state.data.canister_settings.reindex_interval
@@ -138,12 +441,346 @@ fn generate_bucket_index(runtime_state: &mut RefMut<RuntimeState>) {
             runtime_state.data.canister_settings.reindex_interval,
             runtime_state.env.now() - runtime_state.data.bucket_index.last_updated
         ));
-        let effective_index = runtime_state.data.business_state.create_bucket_index();
+        let now = runtime_state.env.now();
+        let version = runtime_state.data.bucket_index.effective_index.version + 1;
+        let memory_used = runtime_state.env.memory_used();
+        let business_state = &mut runtime_state.data.business_state;
+        business_state.set_memory_used(memory_used);
+        let effective_index = business_state.create_bucket_index(version);
+
+        let bucket_index = &mut runtime_state.data.bucket_index;
+        bucket_index.last_updated = now;
+
+        // Nothing changed, nothing to send
+        if effective_index.same_content(&bucket_index.effective_index) {
+            return;
+        }
+        bucket_index.effective_index = effective_index;
+        bucket_index.index_state = IndexState::New;
+
+        // A sync that is waiting sends the new version, one that is in flight
+        // gets a new sync queued after it
+        runtime_state.data.tasks.push(BucketTask::SyncIndex);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::businesslogic::{ModerationAction, ModerationTarget};
+    use quickstart_scaling_acl::{Role, RoleAssignment, RoleScope};
+    use quickstart_scaling_reservation::SlotReservation;
+    use quickstart_scaling_stable::VectorMemory;
+
+    // The state of the original bucket, before the entry ids
+    #[derive(CandidType)]
+    struct BaselineData {
+        canister_settings: BucketCanisterSettings,
+        business_state: BaselineBusinessState,
+        bucket_index: BaselineBucketIndex,
+    }
+
+    #[derive(CandidType)]
+    struct BaselineBusinessState {
+        entries: HashMap<String, Vec<BaselineEntry>>,
+        current_entries: u64,
+        bucket_max_entries: u64,
+        content_moderators: Vec<Principal>,
+    }
 
-        runtime_state.data.bucket_index = BucketIndex {
-            effective_index,
-            index_state: IndexState::New,
-            last_updated: runtime_state.env.now(),
+    #[derive(CandidType)]
+    struct BaselineEntry {
+        tag: String,
+        body: String,
+        submitted_at: TimestampMillis,
+        submitted_by: Principal,
+    }
+
+    #[derive(CandidType)]
+    struct BaselineBucketIndex {
+        effective_index: BaselineEffectiveIndex,
+        index_state: BaselineIndexState,
+        last_updated: TimestampMillis,
+    }
+
+    #[derive(CandidType)]
+    struct BaselineEffectiveIndex {
+        tags: Vec<String>,
+        current_entries: u64,
+        bucket_max_entries: u64,
+    }
+
+    #[derive(CandidType)]
+    enum BaselineIndexState {
+        Synced,
+    }
+
+    fn baseline_entry(tag: &str, body: &str, submitted_at: TimestampMillis) -> BaselineEntry {
+        BaselineEntry {
+            tag: tag.to_string(),
+            body: body.to_string(),
+            submitted_at,
+            submitted_by: Principal::anonymous(),
+        }
+    }
+
+    // stable_save leaves the rest of the page empty
+    fn saved(mut bytes: Vec<u8>) -> Vec<u8> {
+        bytes.resize(65536, 0);
+        bytes
+    }
+
+    fn open_store() -> EntryStore {
+        EntryStore::open(Box::new(VectorMemory::default()))
+    }
+
+    #[test]
+    fn migrates_baseline_bucket() {
+        let index_id = Principal::from_slice(&[1]);
+        let moderator = Principal::from_slice(&[2]);
+        let mut entries = HashMap::new();
+        entries.insert(
+            "#rabbit".to_string(),
+            vec![
+                baseline_entry("#rabbit", "first", 1),
+                baseline_entry("#rabbit", "third", 3),
+            ],
+        );
+        entries.insert(
+            "#fox".to_string(),
+            vec![baseline_entry("#fox", "second", 2)],
+        );
+        let baseline = BaselineData {
+            canister_settings: BucketCanisterSettings {
+                controllers: vec![index_id],
+                index_canister_id: Some(index_id),
+                reindex_interval: 5_000_000_000,
+            },
+            business_state: BaselineBusinessState {
+                entries,
+                current_entries: 3,
+                bucket_max_entries: 10,
+                content_moderators: vec![moderator],
+            },
+            bucket_index: BaselineBucketIndex {
+                effective_index: BaselineEffectiveIndex {
+                    tags: vec!["#rabbit".to_string(), "#fox".to_string()],
+                    current_entries: 3,
+                    bucket_max_entries: 10,
+                },
+                index_state: BaselineIndexState::Synced,
+                last_updated: 3,
+            },
+        };
+
+        let data = restore_legacy_data(&saved(Encode!(&baseline).unwrap()), open_store());
+
+        assert_eq!(data.canister_settings.controllers, vec![index_id]);
+        assert_eq!(data.canister_settings.index_canister_id, Some(index_id));
+        let mut business_state = data.business_state;
+        assert_eq!(business_state.max_entries(), 10);
+        assert_eq!(business_state.entries_count(), 3);
+        assert!(business_state.bytes_used() > 0);
+        assert_eq!(business_state.get_content_moderators(), vec![moderator]);
+
+        // The ids follow the order the entries were posted in
+        let caller = Principal::anonymous();
+        let bodies: Vec<String> = (0..3)
+            .map(|id| business_state.get_entry(id, caller).unwrap().body)
+            .collect();
+        assert_eq!(bodies, vec!["first", "second", "third"]);
+
+        let id = business_state.insert_entry(BucketEntry {
+            tag: "#fox".to_string(),
+            body: "fourth".to_string(),
+            submitted_by: caller,
+            ..Default::default()
+        });
+        assert_eq!(id, Some(3));
+    }
+
+    #[test]
+    fn migrates_bucket_with_entry_ids() {
+        let caller = Principal::anonymous();
+        let entry = |id, body: &str| LegacyEntry {
+            id: Some(id),
+            tag: "#rabbit".to_string(),
+            body: body.to_string(),
+            submitted_at: id,
+            submitted_by: caller,
+            version: Some(1),
+            edited_at: Some(id),
+            hidden: None,
+        };
+        let mut entries = HashMap::new();
+        entries.insert(
+            "#rabbit".to_string(),
+            vec![entry(4, "four"), entry(7, "seven")],
+        );
+        let mut entry_history = HashMap::new();
+        entry_history.insert(
+            7,
+            vec![EntryRevision {
+                version: 0,
+                body: "old".to_string(),
+                created_at: 6,
+            }],
+        );
+        let moderator = Principal::from_slice(&[2]);
+        let troll = Principal::from_slice(&[3]);
+        let mut access_control = AccessControl::default();
+        access_control.grant(RoleAssignment {
+            principal: moderator,
+            role: Role::Moderator,
+            scope: RoleScope::Tag("#rabbit".to_string()),
+        });
+        let ban = ModerationRecord {
+            id: 0,
+            actor: moderator,
+            action: ModerationAction::Ban,
+            target: ModerationTarget::Principal(troll),
+            reason: "troll".to_string(),
+            timestamp: 5,
+        };
+        let legacy = LegacyData {
+            canister_settings: BucketCanisterSettings::default(),
+            business_state: LegacyBusinessState {
+                entries,
+                bucket_max_entries: 10,
+                next_entry_id: Some(9),
+                entry_history: Some(entry_history),
+                content_moderators: None,
+                access_control: Some(access_control),
+                banned_principals: Some(vec![troll]),
+                moderation_log: Some(vec![ban.clone()]),
+                roles_version: Some(4),
+                capacity_mode: Some(CapacityMode::Bytes(1_000_000)),
+                reservation_key: None,
+                used_reservations: None,
+            },
+            bucket_index: LegacyBucketIndex {
+                effective_index: LegacyEffectiveIndex { version: Some(12) },
+            },
+        };
+
+        let data = restore_legacy_data(&saved(Encode!(&legacy).unwrap()), open_store());
+
+        // The next index the Index gets is newer than the last one
+        assert_eq!(data.bucket_index.effective_index.version, 12);
+        let mut business_state = data.business_state;
+        assert_eq!(
+            business_state.capacity_mode(),
+            CapacityMode::Bytes(1_000_000)
+        );
+        assert_eq!(business_state.roles_version(), 4);
+        assert_eq!(business_state.entries_count(), 2);
+        assert_eq!(business_state.get_entry(4, caller).unwrap().body, "four");
+        assert_eq!(business_state.get_entry(7, caller).unwrap().version, 1);
+        let history = business_state.get_entry_history(7, caller);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].body, "old");
+
+        // The roles, bans and moderation log move to the store
+        assert!(business_state.has_role(&moderator, Role::Moderator, Some("#rabbit")));
+        assert!(business_state.is_banned(&troll));
+        assert_eq!(
+            business_state.list_moderation_log(None, 10).records,
+            vec![ban]
+        );
+        business_state.set_banned(troll, false, moderator, "".to_string(), 6);
+        let log = business_state.list_moderation_log(None, 10).records;
+        assert_eq!(log.iter().map(|r| r.id).collect::<Vec<_>>(), vec![0, 1]);
+
+        // Ids that were handed out before aren't reused
+        let id = business_state.insert_entry(BucketEntry {
+            tag: "#rabbit".to_string(),
+            body: "nine".to_string(),
+            submitted_by: caller,
+            ..Default::default()
+        });
+        assert_eq!(id, Some(9));
+    }
+
+    fn full_legacy_bucket() -> LegacyData {
+        LegacyData {
+            canister_settings: BucketCanisterSettings::default(),
+            business_state: LegacyBusinessState {
+                entries: HashMap::new(),
+                bucket_max_entries: 0,
+                next_entry_id: None,
+                entry_history: None,
+                content_moderators: None,
+                access_control: None,
+                banned_principals: None,
+                moderation_log: None,
+                roles_version: None,
+                capacity_mode: None,
+                reservation_key: None,
+                used_reservations: None,
+            },
+            bucket_index: LegacyBucketIndex {
+                effective_index: LegacyEffectiveIndex { version: None },
+            },
+        }
+    }
+
+    #[test]
+    fn migrates_reservations() {
+        let bucket = Principal::from_slice(&[10]);
+        let caller = Principal::from_slice(&[1]);
+        let key = b"bucket key".to_vec();
+        let mut legacy = full_legacy_bucket();
+        let mut used_reservations = BTreeMap::new();
+        used_reservations.insert(1, 100);
+        legacy.business_state.reservation_key = Some(key.clone());
+        legacy.business_state.used_reservations = Some(used_reservations);
+
+        let data = restore_legacy_data(&saved(Encode!(&legacy).unwrap()), open_store());
+
+        // The key still checks the reservations, and the used ones stay used
+        let mut business_state = data.business_state;
+        let entry = BucketEntry {
+            submitted_by: caller,
+            ..Default::default()
         };
+        let used = SlotReservation::new(1, bucket, caller, 100, &key);
+        let fresh = SlotReservation::new(2, bucket, caller, 100, &key);
+        assert_eq!(
+            business_state.insert_reserved_entry(entry.clone(), &used, bucket, 0),
+            None
+        );
+        assert_eq!(
+            business_state.insert_reserved_entry(entry, &fresh, bucket, 0),
+            Some(0)
+        );
+    }
+
+    // The upgrade is rolled back rather than dropping a field it can't read
+    #[test]
+    #[should_panic(expected = "The saved banned_principals can't be read")]
+    fn traps_on_unreadable_state() {
+        #[derive(CandidType)]
+        struct BadBusinessState {
+            entries: HashMap<String, Vec<LegacyEntry>>,
+            bucket_max_entries: u64,
+            banned_principals: Vec<String>,
+        }
+        #[derive(CandidType)]
+        struct BadData {
+            canister_settings: BucketCanisterSettings,
+            business_state: BadBusinessState,
+            bucket_index: LegacyBucketIndex,
+        }
+        let bad = BadData {
+            canister_settings: BucketCanisterSettings::default(),
+            business_state: BadBusinessState {
+                entries: HashMap::new(),
+                bucket_max_entries: 10,
+                banned_principals: vec!["troll".to_string()],
+            },
+            bucket_index: full_legacy_bucket().bucket_index,
+        };
+
+        restore_legacy_data(&saved(Encode!(&bad).unwrap()), open_store());
     }
 }
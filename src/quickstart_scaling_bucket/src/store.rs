@@ -0,0 +1,470 @@
+use crate::businesslogic::{BucketEntry, EntryId, EntryRevision, ModerationRecord};
+use candid::types::{Serializer, Type};
+use candid::{CandidType, Decode, Encode, Principal};
+use quickstart_scaling_acl::AccessControl;
+use quickstart_scaling_stable::{BTreeMap, Heap, Memory, VectorMemory};
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::fmt;
+
+// Where the maps keep their root in the heap header
+// id -> EntryRecord
+const ENTRIES: BTreeMap = BTreeMap::new(0, 8);
+// hash(tag) + id -> nothing, to go through a tag's entries in id order
+const TAG_ENTRIES: BTreeMap = BTreeMap::new(1, 40);
+// hash(tag) -> TagRecord
+const TAGS: BTreeMap = BTreeMap::new(2, 32);
+// The rest of the canister state, written by pre_upgrade
+const UPGRADE_DATA_SLOT: usize = 3;
+// record id -> ModerationRecord
+const MODERATION_LOG: BTreeMap = BTreeMap::new(4, 8);
+// principal -> nothing
+const BANNED: BTreeMap = BTreeMap::new(5, PRINCIPAL_KEY_SIZE);
+// The role assignments pushed by the Index
+const ROLES_SLOT: usize = 6;
+
+#[derive(CandidType, Deserialize)]
+struct EntryRecord {
+    entry: BucketEntry,
+    // Previous versions of the entry, oldest first
+    history: Vec<EntryRevision>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TagRecord {
+    tag: String,
+    entries: u64,
+}
+
+// The bucket's entries, and the moderation log, bans and roles that come with
+// them, kept in stable memory. Upgrades don't touch them, so they cost the
+// same whatever the number of entries, and the entries aren't limited by the
+// size of the heap.
+//
+// The store isn't part of the serialized state, see the CandidType impl. init
+// and post_upgrade attach it to the canister's stable memory.
+pub struct EntryStore {
+    heap: Heap,
+    // Checked on every read, so they're kept on the heap too
+    roles: AccessControl,
+}
+
+impl EntryStore {
+    // Opens the store in `memory`, a new one if the memory is empty
+    pub fn open(memory: Box<dyn Memory>) -> Self {
+        let heap = Heap::open(memory);
+        let roles = match heap.root(ROLES_SLOT) {
+            0 => AccessControl::default(),
+            ptr => Decode!(&heap.read(ptr), AccessControl).unwrap(),
+        };
+        EntryStore { heap, roles }
+    }
+
+    // Sets up an empty store over whatever the memory held before
+    pub fn new(memory: Box<dyn Memory>) -> Self {
+        EntryStore {
+            heap: Heap::new(memory),
+            roles: AccessControl::default(),
+        }
+    }
+
+    // False for a new canister and for buckets from before the store, that
+    // saved everything with stable_save
+    pub fn is_initialized(memory: &dyn Memory) -> bool {
+        Heap::is_initialized(memory)
+    }
+
+    pub fn get(&self, id: EntryId) -> Option<BucketEntry> {
+        self.get_record(id).map(|r| r.entry)
+    }
+
+    pub fn get_history(&self, id: EntryId) -> Vec<EntryRevision> {
+        self.get_record(id).map(|r| r.history).unwrap_or_default()
+    }
+
+    // Stores a new entry, or replaces the stored entry with the same id. Tags
+    // don't change, so the tag maps only change for new entries.
+    pub fn insert(&mut self, entry: BucketEntry, history: Vec<EntryRevision>) {
+        let id = entry.id;
+        let tag_hash = tag_hash(&entry.tag);
+        let tag = entry.tag.clone();
+
+        let record = Encode!(&EntryRecord { entry, history }).unwrap();
+        let ptr = self.heap.alloc(&record);
+
+        if let Some(previous) = ENTRIES.insert(&mut self.heap, &id.to_be_bytes(), ptr) {
+            self.heap.free(previous);
+            return;
+        }
+
+        TAG_ENTRIES.insert(&mut self.heap, &tag_entry_key(&tag_hash, id), 0);
+        let entries = self.tag_record(&tag_hash).map_or(0, |t| t.entries);
+        self.set_tag_record(
+            &tag_hash,
+            TagRecord {
+                tag,
+                entries: entries + 1,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, id: EntryId) -> Option<(BucketEntry, Vec<EntryRevision>)> {
+        let ptr = ENTRIES.remove(&mut self.heap, &id.to_be_bytes())?;
+        let record = decode_entry(&self.heap.read(ptr));
+        self.heap.free(ptr);
+
+        let tag_hash = tag_hash(&record.entry.tag);
+        TAG_ENTRIES.remove(&mut self.heap, &tag_entry_key(&tag_hash, id));
+
+        // A tag goes away with its last entry
+        let mut tag_record = self.tag_record(&tag_hash)?;
+        tag_record.entries -= 1;
+        if tag_record.entries == 0 {
+            if let Some(ptr) = TAGS.remove(&mut self.heap, &tag_hash) {
+                self.heap.free(ptr);
+            }
+        } else {
+            self.set_tag_record(&tag_hash, tag_record);
+        }
+
+        Some((record.entry, record.history))
+    }
+
+    // In the order of their hashes
+    pub fn tags(&self) -> Vec<String> {
+        TAGS.iter(&self.heap)
+            .map(|(_, ptr)| decode_tag(&self.heap.read(ptr)).tag)
+            .collect()
+    }
+
+    // Entries with an id of at least `from`, in id order
+    pub fn entries_from(&self, from: EntryId) -> impl Iterator<Item = BucketEntry> + '_ {
+        ENTRIES
+            .range(&self.heap, &from.to_be_bytes())
+            .map(move |(_, ptr)| decode_entry(&self.heap.read(ptr)).entry)
+    }
+
+    pub fn tag_entries_from(
+        &self,
+        tag: &str,
+        from: EntryId,
+    ) -> impl Iterator<Item = BucketEntry> + '_ {
+        let tag_hash = tag_hash(tag);
+
+        TAG_ENTRIES
+            .range(&self.heap, &tag_entry_key(&tag_hash, from))
+            .take_while(move |(key, _)| key[..32] == tag_hash)
+            .filter_map(move |(key, _)| {
+                let id = EntryId::from_be_bytes(key[32..].try_into().unwrap());
+                self.get(id)
+            })
+    }
+
+    // Records are appended with the next id, starting at 0
+    pub fn append_moderation_record(&mut self, record: &ModerationRecord) {
+        let ptr = self.heap.alloc(&Encode!(record).unwrap());
+        MODERATION_LOG.insert(&mut self.heap, &record.id.to_be_bytes(), ptr);
+    }
+
+    // Records with an id of at least `from`, oldest first
+    pub fn moderation_records_from(
+        &self,
+        from: u64,
+    ) -> impl Iterator<Item = ModerationRecord> + '_ {
+        MODERATION_LOG
+            .range(&self.heap, &from.to_be_bytes())
+            .map(move |(_, ptr)| Decode!(&self.heap.read(ptr), ModerationRecord).unwrap())
+    }
+
+    pub fn set_banned(&mut self, principal: &Principal, banned: bool) {
+        let key = principal_key(principal);
+        if banned {
+            BANNED.insert(&mut self.heap, &key, 0);
+        } else {
+            BANNED.remove(&mut self.heap, &key);
+        }
+    }
+
+    pub fn is_banned(&self, principal: &Principal) -> bool {
+        BANNED.contains_key(&self.heap, &principal_key(principal))
+    }
+
+    pub fn roles(&self) -> &AccessControl {
+        &self.roles
+    }
+
+    pub fn set_roles(&mut self, roles: AccessControl) {
+        self.replace_root(ROLES_SLOT, &Encode!(&roles).unwrap());
+        self.roles = roles;
+    }
+
+    pub fn save_upgrade_data(&mut self, data: &[u8]) {
+        self.replace_root(UPGRADE_DATA_SLOT, data);
+    }
+
+    pub fn upgrade_data(&self) -> Option<Vec<u8>> {
+        match self.heap.root(UPGRADE_DATA_SLOT) {
+            0 => None,
+            ptr => Some(self.heap.read(ptr)),
+        }
+    }
+
+    // Stable memory taken by the store, including freed chunks
+    pub fn size(&self) -> u64 {
+        self.heap.size()
+    }
+
+    fn get_record(&self, id: EntryId) -> Option<EntryRecord> {
+        let ptr = ENTRIES.get(&self.heap, &id.to_be_bytes())?;
+        Some(decode_entry(&self.heap.read(ptr)))
+    }
+
+    fn tag_record(&self, tag_hash: &[u8; 32]) -> Option<TagRecord> {
+        let ptr = TAGS.get(&self.heap, tag_hash)?;
+        Some(decode_tag(&self.heap.read(ptr)))
+    }
+
+    fn replace_root(&mut self, slot: usize, data: &[u8]) {
+        let previous = self.heap.root(slot);
+        if previous != 0 {
+            self.heap.free(previous);
+        }
+
+        let ptr = self.heap.alloc(data);
+        self.heap.set_root(slot, ptr);
+    }
+
+    fn set_tag_record(&mut self, tag_hash: &[u8; 32], record: TagRecord) {
+        let ptr = self.heap.alloc(&Encode!(&record).unwrap());
+        if let Some(previous) = TAGS.insert(&mut self.heap, tag_hash, ptr) {
+            self.heap.free(previous);
+        }
+    }
+}
+
+// Tags can be of any length, the maps use their hash as key
+fn tag_hash(tag: &str) -> [u8; 32] {
+    Sha256::digest(tag.as_bytes()).into()
+}
+
+fn tag_entry_key(tag_hash: &[u8; 32], id: EntryId) -> Vec<u8> {
+    let mut key = tag_hash.to_vec();
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+// Principals are up to 29 bytes, the key starts with the length so it has a
+// fixed size
+const PRINCIPAL_KEY_SIZE: usize = 30;
+
+fn principal_key(principal: &Principal) -> Vec<u8> {
+    let bytes = principal.as_slice();
+    let mut key = vec![0; PRINCIPAL_KEY_SIZE];
+    key[0] = bytes.len() as u8;
+    key[1..=bytes.len()].copy_from_slice(bytes);
+    key
+}
+
+fn decode_entry(bytes: &[u8]) -> EntryRecord {
+    Decode!(bytes, EntryRecord).unwrap()
+}
+
+fn decode_tag(bytes: &[u8]) -> TagRecord {
+    Decode!(bytes, TagRecord).unwrap()
+}
+
+// A store on the heap, for tests and until init or post_upgrade attaches the
+// canister's stable memory
+impl Default for EntryStore {
+    fn default() -> Self {
+        EntryStore::open(Box::new(VectorMemory::default()))
+    }
+}
+
+impl fmt::Debug for EntryStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EntryStore")
+            .field("size", &self.size())
+            .finish()
+    }
+}
+
+// Serialized as an opt null, so state saved before the store existed still
+// decodes
+impl CandidType for EntryStore {
+    fn _ty() -> Type {
+        <Option<()>>::ty()
+    }
+
+    fn idl_serialize<S: Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        None::<()>.idl_serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for EntryStore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <Option<()>>::deserialize(deserializer)?;
+        Ok(EntryStore::default())
+    }
+}
+
+// The canister's stable memory
+pub struct CanisterMemory;
+
+impl Memory for CanisterMemory {
+    fn size(&self) -> u64 {
+        ic_cdk::api::stable::stable64_size()
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        match ic_cdk::api::stable::stable64_grow(pages) {
+            Ok(previous) => previous as i64,
+            Err(_) => -1,
+        }
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        ic_cdk::api::stable::stable64_read(offset, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        ic_cdk::api::stable::stable64_write(offset, src)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::businesslogic::{ModerationAction, ModerationTarget};
+    use quickstart_scaling_acl::{Role, RoleAssignment, RoleScope};
+
+    fn entry(id: EntryId, tag: &str, body: &str) -> BucketEntry {
+        BucketEntry {
+            id,
+            tag: tag.to_string(),
+            body: body.to_string(),
+            submitted_at: 0,
+            submitted_by: candid::Principal::anonymous(),
+            version: 0,
+            edited_at: None,
+            hidden: false,
+        }
+    }
+
+    fn ids(entries: impl Iterator<Item = BucketEntry>) -> Vec<EntryId> {
+        entries.map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn entries_and_tags() {
+        let memory = VectorMemory::default();
+        let mut store = EntryStore::open(Box::new(memory.clone()));
+
+        for id in 0..20 {
+            let tag = if id % 2 == 0 { "even" } else { "odd" };
+            store.insert(entry(id, tag, "body"), vec![]);
+        }
+        let mut tags = store.tags();
+        tags.sort();
+        assert_eq!(tags, vec!["even", "odd"]);
+        assert_eq!(ids(store.entries_from(17)), vec![17, 18, 19]);
+        assert_eq!(ids(store.tag_entries_from("odd", 14)), vec![15, 17, 19]);
+        assert_eq!(store.tag_entries_from("none", 0).count(), 0);
+
+        // Replacing an entry keeps its tag links
+        let history = vec![EntryRevision {
+            version: 0,
+            body: "body".to_string(),
+            created_at: 0,
+        }];
+        store.insert(entry(3, "odd", "edited"), history.clone());
+        assert_eq!(store.get(3).unwrap().body, "edited");
+        assert_eq!(store.get_history(3), history);
+        assert_eq!(store.tag_entries_from("odd", 0).count(), 10);
+
+        // A tag goes away with its last entry
+        for id in (0..20).step_by(2) {
+            assert!(store.remove(id).is_some());
+        }
+        assert!(store.remove(0).is_none());
+        assert_eq!(store.tags(), vec!["odd"]);
+
+        // Everything is in the memory
+        store.save_upgrade_data(b"state");
+        assert!(EntryStore::is_initialized(&memory));
+        let store = EntryStore::open(Box::new(memory));
+        assert_eq!(store.upgrade_data().unwrap(), b"state");
+        assert_eq!(store.get(3).unwrap().body, "edited");
+        assert_eq!(
+            ids(store.entries_from(0)),
+            vec![1, 3, 5, 7, 9, 11, 13, 15, 17, 19]
+        );
+    }
+
+    // An upgrade that fails leaves the stable memory as it was before
+    // pre_upgrade, and the old version carries on with its store
+    #[test]
+    fn interrupted_upgrade() {
+        let memory = VectorMemory::default();
+        let mut store = EntryStore::open(Box::new(memory.clone()));
+        for id in 0..5 {
+            store.insert(entry(id, "rabbit", "body"), vec![]);
+        }
+        store.save_upgrade_data(b"first upgrade");
+        let before = memory.snapshot();
+
+        // The next upgrade gets as far as post_upgrade, which traps
+        store.save_upgrade_data(b"second upgrade");
+        let mut store = EntryStore::open(Box::new(memory));
+        store.insert(entry(5, "fox", "body"), vec![]);
+        store.remove(0);
+
+        let mut store = EntryStore::open(Box::new(before));
+        assert_eq!(store.upgrade_data().unwrap(), b"first upgrade");
+        assert_eq!(ids(store.entries_from(0)), vec![0, 1, 2, 3, 4]);
+        assert_eq!(store.tags(), vec!["rabbit"]);
+        store.insert(entry(5, "fox", "body"), vec![]);
+        assert_eq!(
+            ids(store.tag_entries_from("rabbit", 0)),
+            vec![0, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn moderation_and_roles() {
+        let memory = VectorMemory::default();
+        let mut store = EntryStore::open(Box::new(memory.clone()));
+        let troll = Principal::from_slice(&[1]);
+        let moderator = Principal::from_slice(&[2]);
+
+        for id in 0..5 {
+            store.append_moderation_record(&ModerationRecord {
+                id,
+                actor: moderator,
+                action: ModerationAction::Hide,
+                target: ModerationTarget::Entry(id),
+                reason: "spam".to_string(),
+                timestamp: id,
+            });
+        }
+        store.set_banned(&troll, true);
+        store.set_banned(&moderator, true);
+        store.set_banned(&moderator, false);
+        let mut roles = AccessControl::default();
+        roles.grant(RoleAssignment {
+            principal: moderator,
+            role: Role::Moderator,
+            scope: RoleScope::Global,
+        });
+        store.set_roles(roles);
+
+        // Everything is in the memory
+        let store = EntryStore::open(Box::new(memory));
+        let ids: Vec<u64> = store.moderation_records_from(3).map(|r| r.id).collect();
+        assert_eq!(ids, vec![3, 4]);
+        assert!(store.is_banned(&troll));
+        assert!(!store.is_banned(&moderator));
+        assert!(store.roles().has_role(&moderator, Role::Moderator, None));
+    }
+}
@@ -1,6 +1,5 @@
 import React from 'react'
 import { quickstart_scaling_index } from "../../declarations/quickstart_scaling_index"
-import {createActor} from './bucketAgent.js'
 
 
 function PostContent() {
@@ -20,18 +19,18 @@ function PostContent() {
 
         console.log(tag, text);
 
-        const send_bucket = await quickstart_scaling_index.getUploadOrder();
-
-        console.log("Should send to " + send_bucket[0].toText())
-
-        const quickstart_scaling_bucket = createActor(send_bucket[0].toText());
-
-        const response = await quickstart_scaling_bucket.postContent(tag,text);
+        // The Index picks the bucket and tries the next one if it's full
+        const response = await quickstart_scaling_index.postContent(tag,text);
 
         console.log(response)
 
-        if (response){
-            setGreeting("Sent " + tag + " " + text + " to " + send_bucket[0].toText())
+        if ("Ok" in response){
+            setGreeting("Sent " + tag + " " + text + " to " + response.Ok.bucket.toText() + " as entry " + response.Ok.id.toString())
+        } else if ("Queued" in response){
+            // Every bucket was full, the Index writes it once a new bucket is up (see getQueuedWrite)
+            setGreeting("Queued " + tag + " " + text + " as write " + response.Queued.toString())
+        } else {
+            setGreeting("Couldn't send " + tag + " " + text + ": " + response.Err)
         }
 
         setPending(false);
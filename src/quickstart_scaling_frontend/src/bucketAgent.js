@@ -3,32 +3,146 @@ import { Actor, HttpAgent } from "@dfinity/agent";
 
   export const idlFactory = ({ IDL }) => {
     const BucketEntry = IDL.Record({
+      'id' : IDL.Nat64,
       'tag' : IDL.Text,
       'body' : IDL.Text,
       'submitted_at' : IDL.Nat64,
       'submitted_by' : IDL.Principal,
+      'version' : IDL.Nat32,
+      'edited_at' : IDL.Opt(IDL.Nat64),
+      'hidden' : IDL.Bool,
+    });
+    const EntryRevision = IDL.Record({
+      'version' : IDL.Nat32,
+      'body' : IDL.Text,
+      'created_at' : IDL.Nat64,
+    });
+    const EntryError = IDL.Variant({
+      'NotFound' : IDL.Null,
+      'NotAuthor' : IDL.Null,
+      'Forbidden' : IDL.Null,
+      'Full' : IDL.Null,
+    });
+    const EntryResult = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : EntryError });
+    const ModerationAction = IDL.Variant({
+      'Hide' : IDL.Null,
+      'Unhide' : IDL.Null,
+      'Remove' : IDL.Null,
+      'Ban' : IDL.Null,
+      'Unban' : IDL.Null,
+    });
+    const ModerationTarget = IDL.Variant({
+      'Entry' : IDL.Nat64,
+      'Principal' : IDL.Principal,
+    });
+    const ModerationRecord = IDL.Record({
+      'id' : IDL.Nat64,
+      'actor' : IDL.Principal,
+      'action' : ModerationAction,
+      'target' : ModerationTarget,
+      'reason' : IDL.Text,
+      'timestamp' : IDL.Nat64,
+    });
+    const ModerationLogPage = IDL.Record({
+      'records' : IDL.Vec(ModerationRecord),
+      'next_cursor' : IDL.Opt(IDL.Nat64),
+    });
+    const Role = IDL.Variant({
+      'Reader' : IDL.Null,
+      'Moderator' : IDL.Null,
+      'Admin' : IDL.Null,
+      'Owner' : IDL.Null,
+    });
+    const RoleScope = IDL.Variant({ 'Global' : IDL.Null, 'Tag' : IDL.Text });
+    const RoleAssignment = IDL.Record({
+      'principal' : IDL.Principal,
+      'role' : Role,
+      'scope' : RoleScope,
+    });
+    const GlobalEntryId = IDL.Record({
+      'id' : IDL.Nat64,
+      'bucket' : IDL.Principal,
+    });
+    const SlotReservation = IDL.Record({
+      'id' : IDL.Nat64,
+      'bucket' : IDL.Principal,
+      'holder' : IDL.Principal,
+      'expires_at' : IDL.Nat64,
+      'signature' : IDL.Vec(IDL.Nat8),
+    });
+    const EntriesPage = IDL.Record({
+      'entries' : IDL.Vec(BucketEntry),
+      'next_cursor' : IDL.Opt(IDL.Nat64),
     });
     const EffectiveIndex = IDL.Record({
+      'version' : IDL.Nat64,
       'tags' : IDL.Vec(IDL.Text),
       'bucket_max_entries' : IDL.Nat64,
       'current_entries' : IDL.Nat64,
+      'bytes_used' : IDL.Nat64,
+      'bytes_capacity' : IDL.Nat64,
+    });
+    const IndexSyncStatus = IDL.Record({
+      'last_error' : IDL.Opt(IDL.Text),
+      'last_error_at' : IDL.Opt(IDL.Nat64),
+      'attempts' : IDL.Nat32,
+      'next_attempt_at' : IDL.Nat64,
+      'total_failures' : IDL.Nat64,
+      'last_success' : IDL.Opt(IDL.Nat64),
     });
     const BucketMetrics = IDL.Record({
       'cycles_balance' : IDL.Nat,
       'controllers' : IDL.Vec(IDL.Principal),
       'memory_used' : IDL.Nat64,
+      'bytes_used' : IDL.Nat64,
+      'bytes_capacity' : IDL.Nat64,
       'canister_id' : IDL.Principal,
       'max_entries' : IDL.Nat64,
       'current_entries' : IDL.Nat64,
       'index_canister_id' : IDL.Principal,
       'moderators' : IDL.Vec(IDL.Principal),
+      'index_sync' : IndexSyncStatus,
+      'roles_version' : IDL.Nat64,
     });
     return IDL.Service({
+      'banPrincipal' : IDL.Func([IDL.Principal, IDL.Text], [], []),
       'getAll' : IDL.Func([], [IDL.Vec(BucketEntry)], ['query']),
+      'getAllPaged' : IDL.Func(
+          [IDL.Opt(IDL.Nat64), IDL.Nat64],
+          [EntriesPage],
+          ['query'],
+        ),
       'getBucketIndex' : IDL.Func([], [EffectiveIndex], ['query']),
       'getByTag' : IDL.Func([IDL.Text], [IDL.Vec(BucketEntry)], ['query']),
+      'getByTagPaged' : IDL.Func(
+          [IDL.Text, IDL.Opt(IDL.Nat64), IDL.Nat64],
+          [EntriesPage],
+          ['query'],
+        ),
+      'deleteContent' : IDL.Func([IDL.Nat64], [EntryResult], []),
+      'editContent' : IDL.Func([IDL.Nat64, IDL.Text], [EntryResult], []),
+      'getEntry' : IDL.Func([IDL.Nat64], [IDL.Opt(BucketEntry)], ['query']),
+      'getEntryHistory' : IDL.Func(
+          [IDL.Nat64],
+          [IDL.Vec(EntryRevision)],
+          ['query'],
+        ),
       'getMetrics' : IDL.Func([], [BucketMetrics], ['query']),
-      'postContent' : IDL.Func([IDL.Text, IDL.Text], [IDL.Bool], []),
+      'getModerationLog' : IDL.Func(
+          [IDL.Opt(IDL.Nat64), IDL.Nat64],
+          [ModerationLogPage],
+          ['query'],
+        ),
+      'getRoles' : IDL.Func([], [IDL.Vec(RoleAssignment)], ['query']),
+      'hideContent' : IDL.Func([IDL.Nat64, IDL.Text], [EntryResult], []),
+      'postContent' : IDL.Func(
+          [IDL.Text, IDL.Text, IDL.Opt(SlotReservation)],
+          [IDL.Opt(GlobalEntryId)],
+          [],
+        ),
+      'removeContent' : IDL.Func([IDL.Nat64, IDL.Text], [EntryResult], []),
+      'unbanPrincipal' : IDL.Func([IDL.Principal, IDL.Text], [], []),
+      'unhideContent' : IDL.Func([IDL.Nat64, IDL.Text], [EntryResult], []),
     });
   };
 
@@ -1,23 +1,57 @@
 use crate::businesslogic::IndexingStrategy::BalancedLoad;
+use crate::settings::{CapacityMode, IndexSyncMode};
 use crate::{Principal, RuntimeState, TimestampMillis, RUNTIME_STATE};
 use candid::{CandidType, Encode, Nat};
+use ic_cdk::api::call::CallResult;
 use ic_cdk::print;
+use quickstart_scaling_acl::{AccessControl, Role, RoleAssignment, RoleList, RoleScope};
+use quickstart_scaling_reservation::SlotReservation;
+use quickstart_scaling_tasks::{LeasedTask, RetryPolicy, TaskKind, TaskQueue};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::cell::{Ref, RefMut};
 use std::cmp::Reverse;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 //Business State
 #[derive(CandidType, Deserialize, Debug, Default)]
 pub struct BusinessState {
     bucket_indexes: HashMap<Principal, EffectiveIndex>,
+    // Parts of multi-part index updates, applied once the last part arrives
+    pending_index_updates: HashMap<Principal, IndexUpdate>,
     spawned_buckets: Vec<SpawnedBucketCanister>,
     pub(crate) global_index: GlobalIndex,
     current_buckets_free_slots: u128,
     pub(crate) planned_buckets: Vec<PlannedBucketCanister>,
+    next_planned_bucket_id: u64,
+    // Bucket spawns and role pushes
+    pub(crate) tasks: TaskQueue<IndexTask>,
+    // Canisters created by a spawn attempt that had lost its lease and that
+    // no planned bucket could take over
+    orphaned_canisters: Vec<OrphanedCanister>,
+    // Index pushes from callers that aren't one of our buckets
+    rejected_index_pushes: u64,
+    rejected_index_push_log: Vec<RejectedIndexPush>,
+    // Pull mode: where the next round starts in the bucket list, and the
+    // round that is running
+    pull_cursor: u64,
+    pull_round_started_at: Option<TimestampMillis>,
+    last_pull_round: TimestampMillis,
     indexing_strategy: IndexingStrategy,
-    content_moderators: Vec<Principal>,
-    pub(crate) push_moderators: bool,
+    // Source of truth for roles, buckets get a copy pushed to them. The
+    // version goes up with every change.
+    access_control: AccessControl,
+    roles_version: u64,
+    // The keys our buckets check upload reservations with, for the buckets
+    // that are known to have theirs
+    reservation_keys: HashMap<Principal, Vec<u8>>,
+    // Upload reservations that haven't expired yet
+    reservations: Vec<ActiveReservation>,
+    next_reservation_id: u64,
+    // Posts waiting for a bucket with room, oldest first
+    pending_writes: Vec<PendingWrite>,
+    finished_writes: Vec<FinishedWrite>,
+    next_write_id: u64,
 }
 
 #[derive(CandidType, Deserialize, Debug, Default, Clone)]
@@ -26,17 +60,83 @@ pub struct GlobalIndex {
     pub(crate) last_updated: TimestampMillis,
 }
 
+// Used to turn bytes into slots for buckets that don't have entries yet
+pub const ESTIMATED_ENTRY_BYTES: u64 = 1024;
+
 #[derive(CandidType, Default, Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct EffectiveIndex {
+    // Set by the bucket, increases with every new index it generates
+    version: u64,
     tags: Vec<String>,
     current_entries: u64,
+    // u64::MAX when the bucket only counts bytes
     bucket_max_entries: u64,
+    bytes_used: u64,
+    // Capped by the bucket's memory, whatever its capacity mode
+    bytes_capacity: u64,
+}
+
+impl EffectiveIndex {
+    // The entries the bucket can still take. The bytes left are turned into
+    // entries the size of the bucket's average entry.
+    pub fn free_slots(&self) -> u64 {
+        let free_entries = self.bucket_max_entries.saturating_sub(self.current_entries);
+        let entry_bytes = match self.current_entries {
+            0 => ESTIMATED_ENTRY_BYTES,
+            entries => (self.bytes_used / entries).max(1),
+        };
+        let free_bytes = self.bytes_capacity.saturating_sub(self.bytes_used);
+
+        free_entries.min(free_bytes / entry_bytes)
+    }
+}
+
+// What buckets send to sync their index: the full list of tags or the tags
+// added and removed since base_version. Big updates are split in parts.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct IndexUpdate {
+    pub(crate) version: u64,
+    // The version the delta was computed against, unused for full updates
+    pub(crate) base_version: u64,
+    pub(crate) full: bool,
+    pub(crate) added_tags: Vec<String>,
+    pub(crate) removed_tags: Vec<String>,
+    pub(crate) current_entries: u64,
+    pub(crate) bucket_max_entries: u64,
+    pub(crate) bytes_used: u64,
+    pub(crate) bytes_capacity: u64,
+    pub(crate) part: u32,
+    pub(crate) last_part: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum IndexSyncResult {
+    // Send the next part
+    PartAccepted,
+    // The last version applied for the bucket
+    Applied(u64),
+    // We don't have the delta's base version, or a part went missing. The
+    // bucket has to send its full index.
+    ResyncNeeded,
+    // The caller isn't one of our buckets
+    Rejected,
+}
+
+// Mirrors the bucket's GlobalEntryId: the bucket holding the entry plus the
+// entry's per-bucket sequence id.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GlobalEntryId {
+    pub(crate) bucket: Principal,
+    pub(crate) id: u64,
 }
 
-#[derive(CandidType, Debug, Deserialize)]
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
 pub enum IndexingStrategy {
     BalancedLoad,
     FillFirst,
+    // Keeps each tag on as few buckets as possible, so getIndexByTag returns
+    // fewer buckets to read from
+    TagAffinity,
 }
 
 impl Default for IndexingStrategy {
@@ -45,19 +145,175 @@ impl Default for IndexingStrategy {
     }
 }
 
-#[derive(CandidType, Deserialize, Debug, Default)]
-pub struct SpawnedBucketCanister {}
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BucketStatus {
+    // Created, the bucket wasm isn't running yet
+    Installing,
+    Active,
+    // Reported as full by the bucket's last index push
+    Full,
+    // Set by an admin: still serves reads, gets no new uploads
+    Draining,
+    // Set by an admin: out of service
+    Retired,
+}
+
+// Only the most recent rejected index pushes are kept
+pub const MAX_REJECTED_INDEX_PUSH_LOG: usize = 20;
+
+#[derive(CandidType, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct RejectedIndexPush {
+    pub(crate) caller: Principal,
+    pub(crate) rejected_at: TimestampMillis,
+}
+
+// The Index's record of a bucket it spawned
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct SpawnedBucketCanister {
+    pub(crate) canister_id: Principal,
+    pub(crate) created_at: TimestampMillis,
+    pub(crate) cycles_sent: u64,
+    // None if the install finished in an attempt that had lost its lease
+    pub(crate) wasm_version: Option<String>,
+    pub(crate) capacity: u64,
+    pub(crate) status: BucketStatus,
+    // The version of the role list the bucket acknowledged
+    pub(crate) roles_version: u64,
+}
 
 #[derive(CandidType, Deserialize, Debug, Default)]
 pub struct BucketCanisterSettings {}
 
-#[derive(CandidType, Deserialize, Debug)]
+// How long a spawn attempt may hold a planned bucket before another
+// attempt can take over (5 minutes)
+pub const SPAWN_LEASE: TimestampMillis = 300_000_000_000;
+// A role or key push that doesn't come back within a minute is retried
+pub const ROLE_PUSH_LEASE: TimestampMillis = 60_000_000_000;
+// Failed tasks are retried after 1s, 2s, 4s... up to 10 minutes, and go to
+// the dead letters after MAX_TASK_ATTEMPTS
+pub const TASK_BACKOFF: TimestampMillis = 1_000_000_000;
+pub const MAX_TASK_BACKOFF: TimestampMillis = 600_000_000_000;
+pub const MAX_TASK_ATTEMPTS: u32 = 10;
+// Tasks are run one after the other, this keeps a heartbeat short
+pub const MAX_TASKS_PER_HEARTBEAT: usize = 5;
+
+// The async work of the Index, run from the heartbeat
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IndexTask {
+    // Creates the canister of a planned bucket and installs the bucket wasm
+    SpawnBucket(u64),
+    // Sends the role assignments to a bucket
+    PushRoles(Principal),
+    // Gives a bucket installed before upload reservations existed its key
+    PushReservationKey(Principal),
+}
+
+impl TaskKind for IndexTask {
+    fn retry_policy(&self) -> RetryPolicy {
+        let lease = match self {
+            IndexTask::SpawnBucket(_) => SPAWN_LEASE,
+            IndexTask::PushRoles(_) | IndexTask::PushReservationKey(_) => ROLE_PUSH_LEASE,
+        };
+
+        RetryPolicy {
+            lease,
+            base_backoff: TASK_BACKOFF,
+            max_backoff: MAX_TASK_BACKOFF,
+            max_attempts: Some(MAX_TASK_ATTEMPTS),
+        }
+    }
+}
+
+// postContent gives up after trying this many buckets
+pub const MAX_POST_BUCKETS: usize = 3;
+
+// Posts that came in while every bucket was full wait in the Index until the
+// heartbeat can write them to a bucket. Their bodies are kept in our heap, so
+// the queue is bounded by count and by size (10MiB).
+pub const MAX_PENDING_WRITES: usize = 1000;
+pub const MAX_PENDING_WRITE_BYTES: u64 = 10 * 1024 * 1024;
+pub const MAX_WRITES_PER_FLUSH: usize = 10;
+// A flush that doesn't report back within a minute is retried
+pub const WRITE_FLUSH_LEASE: TimestampMillis = 60_000_000_000;
+// How many written or rejected queued posts getQueuedWrite remembers
+pub const MAX_FINISHED_WRITES: usize = 1000;
+
+#[derive(CandidType, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum PostResult {
+    Ok(GlobalEntryId),
+    // No bucket had room, the post was queued under this id
+    Queued(u64),
+    Err(String),
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct PendingWrite {
+    id: u64,
+    author: Principal,
+    tag: String,
+    body: String,
+    queued_at: TimestampMillis,
+    flushing_until: Option<TimestampMillis>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum QueuedWriteStatus {
+    Pending,
+    Stored(GlobalEntryId),
+    Rejected(String),
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+struct FinishedWrite {
+    id: u64,
+    author: Principal,
+    status: QueuedWriteStatus,
+}
+
+// Upload reservations are meant to be used right away, they expire after
+// 2 minutes
+pub const RESERVATION_TTL: TimestampMillis = 120_000_000_000;
+// So no one can hold all the free slots
+pub const MAX_RESERVATIONS_PER_CALLER: usize = 5;
+
+// What the Index remembers of a reservation it handed out
+#[derive(CandidType, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ActiveReservation {
+    id: u64,
+    bucket: Principal,
+    holder: Principal,
+    expires_at: TimestampMillis,
+}
+
+// A pull round that didn't finish (e.g. a trap after a call) doesn't block the
+// next ones for longer than this. 1 minute.
+pub const PULL_ROUND_LEASE: TimestampMillis = 60_000_000_000;
+
+#[derive(CandidType, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum SpawnStatus {
     New,
-    InWork(u32),
+    // The canister exists but the bucket wasm isn't installed yet
+    Created(Principal),
     Installed,
 }
 
+// What a spawn attempt has to do for its planned bucket
+#[derive(Debug, Eq, PartialEq)]
+pub enum SpawnStep {
+    Create,
+    Install(Principal),
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct OrphanedCanister {
+    pub(crate) canister_id: Principal,
+    pub(crate) created_at: TimestampMillis,
+    pub(crate) install_attempts: u32,
+    // Resumable canisters belong to a planned bucket and their install is
+    // retried; the others have to be cleaned up by hand
+    pub(crate) resumable: bool,
+}
+
 impl Default for SpawnStatus {
     fn default() -> Self {
         SpawnStatus::New
@@ -66,65 +322,359 @@ impl Default for SpawnStatus {
 
 #[derive(CandidType, Deserialize, Debug)]
 pub struct PlannedBucketCanister {
+    // Identifies the bucket in its spawn task
+    pub(crate) id: u64,
     pub(crate) canister_settings: BucketCanisterSettings,
     pub(crate) spawn_status: SpawnStatus,
     pub(crate) bucket_max_entries: u128,
+    // Kept until the install succeeds, so a failed install doesn't lose the canister
+    pub(crate) canister_id: Option<Principal>,
+    pub(crate) created_at: Option<TimestampMillis>,
+    pub(crate) install_attempts: u32,
 }
 
 impl Default for PlannedBucketCanister {
     fn default() -> Self {
         PlannedBucketCanister {
+            id: 0,
             canister_settings: Default::default(),
             spawn_status: Default::default(),
             bucket_max_entries: 20,
+            canister_id: None,
+            created_at: None,
+            install_attempts: 0,
         }
     }
 }
 
-// This way of importing creates a larger index canister binary. We could upload the bucket wasm
-// bytes after the installation, in a config update call. This would create a smaller
-// Index binary but it would require a two step process in installing the canister.
-const BUCKET_WASM: &[u8] = std::include_bytes!(
-    "../../../target/wasm32-unknown-unknown/release/quickstart_scaling_bucket-opt.wasm"
-);
-
 // This is the section that implements all our business logic, on top
 // of the business state.
 #[allow(dead_code)]
 impl BusinessState {
-    pub fn where_to_upload(&self) -> Vec<Principal> {
+    // The tag is only used by TagAffinity, the other strategies ignore it
+    pub fn where_to_upload(&self, tag: Option<&str>) -> Vec<Principal> {
         let mut free_slot_list: Vec<(Principal, u128)> = self
             .bucket_indexes
             .iter()
-            .filter(|(_k, v)| v.current_entries != v.bucket_max_entries)
-            .map(|(k, v)| (k.clone(), v.current_entries as u128))
+            .filter(|(k, _)| self.accepts_uploads(k))
+            .map(|(k, v)| (k.clone(), self.unreserved_slots(k, v) as u128))
+            .filter(|(_, slots)| *slots > 0)
             .collect();
 
+        // Buckets are compared by the room they have left once their
+        // reservations are taken out, which accounts for the size of their
+        // entries
         match self.indexing_strategy {
             IndexingStrategy::BalancedLoad => {
-                free_slot_list.sort_by_key(|k| k.1);
+                free_slot_list.sort_by_key(|k| Reverse(k.1));
             }
             IndexingStrategy::FillFirst => {
-                free_slot_list.sort_by_key(|k| Reverse(k.1));
+                free_slot_list.sort_by_key(|k| k.1);
             }
+            IndexingStrategy::TagAffinity => match tag {
+                Some(tag) => return self.tag_affinity_order(tag, free_slot_list),
+                // Without a tag there's nothing to be affine to
+                None => free_slot_list.sort_by_key(|k| Reverse(k.1)),
+            },
         }
         let can_list: Vec<Principal> = free_slot_list.iter().map(|s| s.0).collect();
         can_list
     }
 
+    // Buckets that already hold the tag come first, then the others. Both
+    // groups are ordered by walking a hash ring from the tag's position, so
+    // when a bucket fills up the tag overflows to the same next bucket for
+    // every upload, and a new bucket only takes over the tags that land
+    // right before it on the ring.
+    fn tag_affinity_order(
+        &self,
+        tag: &str,
+        free_slot_list: Vec<(Principal, u128)>,
+    ) -> Vec<Principal> {
+        let start = ring_position(tag.as_bytes());
+        let holders = self.global_index.tag_to_canisters.get(tag);
+
+        let mut candidates: Vec<(bool, u64, Principal)> = free_slot_list
+            .into_iter()
+            .map(|(canister_id, _)| {
+                let holds_tag = holders.is_some_and(|h| h.contains(&canister_id));
+                let distance = ring_position(canister_id.as_slice()).wrapping_sub(start);
+                (!holds_tag, distance, canister_id)
+            })
+            .collect();
+        candidates.sort();
+
+        candidates.into_iter().map(|c| c.2).collect()
+    }
+
     pub fn set_indexing_strategy(&mut self, strategy: IndexingStrategy) {
         self.indexing_strategy = strategy;
     }
 
-    pub fn add_bucket_index(&mut self, canister_id: Principal, effective_index: EffectiveIndex) {
+    pub fn get_indexing_strategy(&self) -> IndexingStrategy {
+        self.indexing_strategy
+    }
+
+    // Returns the last version accepted from the bucket. Pushes that aren't
+    // newer than the stored index arrived out of order and are ignored.
+    pub fn add_bucket_index(
+        &mut self,
+        canister_id: Principal,
+        effective_index: EffectiveIndex,
+    ) -> u64 {
+        if let Some(stored) = self.bucket_indexes.get(&canister_id) {
+            if stored.version >= effective_index.version {
+                return stored.version;
+            }
+        }
+        let version = effective_index.version;
+
+        // A bucket that pushes its index is running, and tells us if it's full
+        if let Some(bucket) = self
+            .spawned_buckets
+            .iter_mut()
+            .find(|b| b.canister_id == canister_id)
+        {
+            if let BucketStatus::Installing | BucketStatus::Active | BucketStatus::Full =
+                bucket.status
+            {
+                bucket.status = if effective_index.free_slots() == 0 {
+                    BucketStatus::Full
+                } else {
+                    BucketStatus::Active
+                };
+            }
+        }
+
         self.bucket_indexes.insert(canister_id, effective_index);
 
         // Once we receive a new bucket index, we should update the free slots
-        self.update_free_slots()
+        self.update_free_slots();
+
+        version
+    }
+
+    pub fn apply_index_update(
+        &mut self,
+        canister_id: Principal,
+        update: IndexUpdate,
+    ) -> IndexSyncResult {
+        let stored_version = self.get_bucket_index_version(&canister_id);
+
+        // Retries and out of order updates
+        if let Some(stored_version) = stored_version {
+            if stored_version >= update.version {
+                self.pending_index_updates.remove(&canister_id);
+                return IndexSyncResult::Applied(stored_version);
+            }
+        }
+
+        if update.part == 0 {
+            if !update.full && stored_version != Some(update.base_version) {
+                self.pending_index_updates.remove(&canister_id);
+                return IndexSyncResult::ResyncNeeded;
+            }
+            self.pending_index_updates.insert(canister_id, update);
+        } else {
+            match self.pending_index_updates.get_mut(&canister_id) {
+                Some(pending)
+                    if pending.version == update.version && pending.part + 1 == update.part =>
+                {
+                    pending.added_tags.extend(update.added_tags);
+                    pending.removed_tags.extend(update.removed_tags);
+                    pending.current_entries = update.current_entries;
+                    pending.bucket_max_entries = update.bucket_max_entries;
+                    pending.bytes_used = update.bytes_used;
+                    pending.bytes_capacity = update.bytes_capacity;
+                    pending.part = update.part;
+                    pending.last_part = update.last_part;
+                }
+                _ => {
+                    self.pending_index_updates.remove(&canister_id);
+                    return IndexSyncResult::ResyncNeeded;
+                }
+            }
+        }
+
+        if !self.pending_index_updates[&canister_id].last_part {
+            return IndexSyncResult::PartAccepted;
+        }
+        let update = self.pending_index_updates.remove(&canister_id).unwrap();
+
+        let tags = if update.full {
+            update.added_tags
+        } else {
+            let removed: HashSet<&String> = update.removed_tags.iter().collect();
+            let mut tags: Vec<String> = self.bucket_indexes[&canister_id]
+                .tags
+                .iter()
+                .filter(|t| !removed.contains(t))
+                .cloned()
+                .collect();
+            tags.extend(update.added_tags);
+            tags
+        };
+
+        IndexSyncResult::Applied(self.add_bucket_index(
+            canister_id,
+            EffectiveIndex {
+                version: update.version,
+                tags,
+                current_entries: update.current_entries,
+                bucket_max_entries: update.bucket_max_entries,
+                bytes_used: update.bytes_used,
+                bytes_capacity: update.bytes_capacity,
+            },
+        ))
+    }
+
+    // Starts a pull round if the last one is at least `interval` old. Returns
+    // the next `batch_size` buckets, going round the registry, with the index
+    // version we have for each.
+    pub fn start_pull_round(
+        &mut self,
+        now: TimestampMillis,
+        interval: TimestampMillis,
+        batch_size: u32,
+    ) -> Vec<(Principal, u64)> {
+        if let Some(started_at) = self.pull_round_started_at {
+            if now < started_at + PULL_ROUND_LEASE {
+                return vec![];
+            }
+        }
+        if now.saturating_sub(self.last_pull_round) < interval {
+            return vec![];
+        }
+
+        // Installing buckets are polled too, their first index makes them Active
+        let buckets: Vec<Principal> = self
+            .spawned_buckets
+            .iter()
+            .filter(|b| b.status != BucketStatus::Retired)
+            .map(|b| b.canister_id)
+            .collect();
+        if buckets.is_empty() {
+            return vec![];
+        }
+
+        let start = self.pull_cursor as usize % buckets.len();
+        let batch: Vec<(Principal, u64)> = buckets
+            .iter()
+            .cycle()
+            .skip(start)
+            .take((batch_size as usize).min(buckets.len()))
+            .map(|canister_id| {
+                (
+                    *canister_id,
+                    self.get_bucket_index_version(canister_id).unwrap_or(0),
+                )
+            })
+            .collect();
+
+        self.pull_cursor = (start + batch.len()) as u64;
+        self.pull_round_started_at = Some(now);
+        self.last_pull_round = now;
+        batch
+    }
+
+    pub fn finish_pull_round(&mut self) {
+        self.pull_round_started_at = None;
+    }
+
+    pub fn get_bucket_index_version(&self, canister_id: &Principal) -> Option<u64> {
+        self.bucket_indexes
+            .get(canister_id)
+            .map(|index| index.version)
     }
 
     pub fn add_spawned_bucket(&mut self, spawned_bucket: SpawnedBucketCanister) {
-        self.spawned_buckets.push(spawned_bucket);
+        if self
+            .get_spawned_bucket(&spawned_bucket.canister_id)
+            .is_none()
+        {
+            self.spawned_buckets.push(spawned_bucket);
+        }
+    }
+
+    pub fn get_spawned_bucket(&self, canister_id: &Principal) -> Option<&SpawnedBucketCanister> {
+        self.spawned_buckets
+            .iter()
+            .find(|b| b.canister_id == *canister_id)
+    }
+
+    pub fn get_spawned_buckets(&self) -> Vec<SpawnedBucketCanister> {
+        self.spawned_buckets.clone()
+    }
+
+    pub fn set_bucket_installed(&mut self, canister_id: Principal, wasm_version: Option<String>) {
+        if let Some(bucket) = self
+            .spawned_buckets
+            .iter_mut()
+            .find(|b| b.canister_id == canister_id)
+        {
+            if bucket.status == BucketStatus::Installing {
+                bucket.status = BucketStatus::Active;
+            }
+            bucket.wasm_version = wasm_version;
+        }
+    }
+
+    // Admins can only drain, retire or re-activate a bucket, Installing and
+    // Full are managed by the Index
+    pub fn set_bucket_status(
+        &mut self,
+        canister_id: Principal,
+        status: BucketStatus,
+    ) -> Result<(), String> {
+        if let BucketStatus::Installing | BucketStatus::Full = status {
+            return Err(format!("{:?} can't be set by hand", status));
+        }
+
+        let bucket = self
+            .spawned_buckets
+            .iter_mut()
+            .find(|b| b.canister_id == canister_id)
+            .ok_or(format!("Unknown bucket {}", canister_id))?;
+        bucket.status = status;
+
+        self.update_free_slots();
+        Ok(())
+    }
+
+    // Index pushes are only accepted from buckets we spawned and didn't retire
+    pub fn accepts_index_from(&self, canister_id: &Principal) -> bool {
+        match self.get_spawned_bucket(canister_id) {
+            Some(bucket) => bucket.status != BucketStatus::Retired,
+            None => false,
+        }
+    }
+
+    pub fn record_rejected_index_push(&mut self, caller: Principal, now: TimestampMillis) {
+        self.rejected_index_pushes += 1;
+
+        if self.rejected_index_push_log.len() == MAX_REJECTED_INDEX_PUSH_LOG {
+            self.rejected_index_push_log.remove(0);
+        }
+        self.rejected_index_push_log.push(RejectedIndexPush {
+            caller,
+            rejected_at: now,
+        });
+    }
+
+    pub fn get_rejected_index_pushes(&self) -> u64 {
+        self.rejected_index_pushes
+    }
+
+    pub fn get_rejected_index_push_log(&self) -> Vec<RejectedIndexPush> {
+        self.rejected_index_push_log.clone()
+    }
+
+    // Buckets we don't have a record of keep getting uploads
+    fn accepts_uploads(&self, canister_id: &Principal) -> bool {
+        !matches!(
+            self.get_spawned_bucket(canister_id).map(|b| b.status),
+            Some(BucketStatus::Draining | BucketStatus::Retired)
+        )
     }
 
     pub fn generate_index_tag_to_canisters(&self) -> HashMap<String, Vec<Principal>> {
@@ -150,8 +700,28 @@ impl BusinessState {
             .clone()
     }
 
+    // The bucket is encoded in the id itself, we only confirm that it is one of ours
+    // Any bucket that serves data, whether or not it synced its index yet
+    pub fn resolve_entry(&self, entry_id: &GlobalEntryId) -> Option<Principal> {
+        if self.get_all_buckets().contains(&entry_id.bucket) {
+            Some(entry_id.bucket)
+        } else {
+            None
+        }
+    }
+
+    // The buckets that are up and serving data, in the order they were spawned
     pub fn get_all_buckets(&self) -> Vec<Principal> {
-        self.bucket_indexes.keys().map(|key| key.clone()).collect()
+        self.spawned_buckets
+            .iter()
+            .filter(|b| {
+                matches!(
+                    b.status,
+                    BucketStatus::Active | BucketStatus::Full | BucketStatus::Draining
+                )
+            })
+            .map(|b| b.canister_id)
+            .collect()
     }
 
     pub fn get_global_index(&self) -> GlobalIndex {
@@ -186,7 +756,8 @@ impl BusinessState {
         let free_slots = self
             .bucket_indexes
             .iter()
-            .map(|(_, index)| (index.bucket_max_entries - index.current_entries) as u128)
+            .filter(|(canister_id, _)| self.accepts_uploads(canister_id))
+            .map(|(_, index)| index.free_slots() as u128)
             .sum();
 
         free_slots
@@ -200,6 +771,172 @@ impl BusinessState {
         self.current_buckets_free_slots
     }
 
+    // Reserved slots aren't free anymore, although the bucket doesn't know it
+    pub fn get_reserved_slots(&self) -> u128 {
+        self.reservations.len() as u128
+    }
+
+    fn unreserved_slots(&self, canister_id: &Principal, index: &EffectiveIndex) -> u64 {
+        let reserved = self
+            .reservations
+            .iter()
+            .filter(|r| r.bucket == *canister_id)
+            .count() as u64;
+
+        index.free_slots().saturating_sub(reserved)
+    }
+
+    // Sets aside a slot for the holder in the first bucket of the upload
+    // order. Only buckets that have their key can be reserved in. None if no
+    // bucket has an unreserved slot, or the holder has too many reservations.
+    pub fn reserve_slot(
+        &mut self,
+        holder: Principal,
+        tag: Option<&str>,
+        now: TimestampMillis,
+    ) -> Option<SlotReservation> {
+        self.prune_reservations(now);
+
+        if self
+            .reservations
+            .iter()
+            .filter(|r| r.holder == holder)
+            .count()
+            >= MAX_RESERVATIONS_PER_CALLER
+        {
+            return None;
+        }
+
+        let (bucket, key) = self
+            .where_to_upload(tag)
+            .into_iter()
+            .find_map(|b| Some((b, self.reservation_keys.get(&b)?.clone())))?;
+
+        let id = self.next_reservation_id;
+        self.next_reservation_id += 1;
+        let expires_at = now + RESERVATION_TTL;
+
+        self.reservations.push(ActiveReservation {
+            id,
+            bucket,
+            holder,
+            expires_at,
+        });
+        Some(SlotReservation::new(id, bucket, holder, expires_at, &key))
+    }
+
+    // Reservations stay counted until they expire, we don't hear about the
+    // ones that were used before the bucket's next index sync
+    pub fn prune_reservations(&mut self, now: TimestampMillis) {
+        self.reservations.retain(|r| r.expires_at > now);
+    }
+
+    pub fn record_reservation_key(&mut self, canister_id: Principal, key: Vec<u8>) {
+        self.reservation_keys.insert(canister_id, key);
+    }
+
+    pub fn has_reservation_key(&self, canister_id: &Principal) -> bool {
+        self.reservation_keys.contains_key(canister_id)
+    }
+
+    // Buckets installed before reservations existed get their key pushed
+    pub fn queue_reservation_key_pushes(&mut self) {
+        for canister_id in self.get_all_buckets() {
+            if !self.has_reservation_key(&canister_id) {
+                self.tasks.push(IndexTask::PushReservationKey(canister_id));
+            }
+        }
+    }
+
+    pub fn queue_write(
+        &mut self,
+        author: Principal,
+        tag: String,
+        body: String,
+        now: TimestampMillis,
+    ) -> Result<u64, String> {
+        let queued_bytes: u64 = self
+            .pending_writes
+            .iter()
+            .map(|w| (w.tag.len() + w.body.len()) as u64)
+            .sum();
+
+        if self.pending_writes.len() >= MAX_PENDING_WRITES
+            || queued_bytes + (tag.len() + body.len()) as u64 > MAX_PENDING_WRITE_BYTES
+        {
+            return Err("All the buckets are full and so is the write queue".to_string());
+        }
+
+        let id = self.next_write_id;
+        self.next_write_id += 1;
+        self.pending_writes.push(PendingWrite {
+            id,
+            author,
+            tag,
+            body,
+            queued_at: now,
+            flushing_until: None,
+        });
+        Ok(id)
+    }
+
+    // The oldest writes that aren't being flushed by someone else
+    pub fn lease_pending_writes(&mut self, now: TimestampMillis, max: usize) -> Vec<PendingWrite> {
+        self.pending_writes
+            .iter_mut()
+            .filter(|w| w.flushing_until.is_none_or(|until| until <= now))
+            .take(max)
+            .map(|w| {
+                w.flushing_until = Some(now + WRITE_FLUSH_LEASE);
+                w.clone()
+            })
+            .collect()
+    }
+
+    // None puts the write back in the queue
+    pub fn finish_pending_write(&mut self, id: u64, result: Option<Result<GlobalEntryId, String>>) {
+        let i = match self.pending_writes.iter().position(|w| w.id == id) {
+            Some(i) => i,
+            None => return,
+        };
+
+        let status = match result {
+            None => {
+                self.pending_writes[i].flushing_until = None;
+                return;
+            }
+            Some(Ok(location)) => QueuedWriteStatus::Stored(location),
+            Some(Err(msg)) => QueuedWriteStatus::Rejected(msg),
+        };
+
+        let write = self.pending_writes.remove(i);
+        if self.finished_writes.len() == MAX_FINISHED_WRITES {
+            self.finished_writes.remove(0);
+        }
+        self.finished_writes.push(FinishedWrite {
+            id,
+            author: write.author,
+            status,
+        });
+    }
+
+    // Only the author can follow their queued post. None if the write is
+    // unknown, or finished so long ago that we forgot it.
+    pub fn get_write_status(&self, id: u64, caller: &Principal) -> Option<QueuedWriteStatus> {
+        if let Some(write) = self.pending_writes.iter().find(|w| w.id == id) {
+            return (write.author == *caller).then_some(QueuedWriteStatus::Pending);
+        }
+
+        self.finished_writes
+            .iter()
+            .find(|w| w.id == id && w.author == *caller)
+            .map(|w| w.status.clone())
+    }
+
+    pub fn get_pending_write_count(&self) -> usize {
+        self.pending_writes.len()
+    }
+
     pub fn get_planned_slots(&self) -> u128 {
         let planned_slots = self
             .planned_buckets
@@ -210,18 +947,244 @@ impl BusinessState {
         planned_slots
     }
 
-    pub fn add_planned_bucket(&mut self) {
-        let bucket = PlannedBucketCanister::default();
+    // Every planned bucket gets a spawn task, that creates the canister and
+    // installs the bucket wasm
+    pub fn add_planned_bucket(&mut self, bucket_max_entries: u64) {
+        let id = self.next_planned_bucket_id;
+        self.next_planned_bucket_id += 1;
+
+        let bucket = PlannedBucketCanister {
+            id,
+            bucket_max_entries: bucket_max_entries as u128,
+            ..Default::default()
+        };
 
         self.planned_buckets.push(bucket);
+        self.tasks.push(IndexTask::SpawnBucket(id));
+    }
+
+    // What the spawn task of a planned bucket has left to do. None once the
+    // bucket is installed.
+    pub fn spawn_step(&self, planned_id: u64) -> Option<SpawnStep> {
+        let bucket = self.planned_buckets.iter().find(|b| b.id == planned_id)?;
+        match bucket.spawn_status {
+            SpawnStatus::New => Some(SpawnStep::Create),
+            SpawnStatus::Created(canister_id) => Some(SpawnStep::Install(canister_id)),
+            SpawnStatus::Installed => None,
+        }
+    }
+
+    // Returns false if the canister didn't go to the planned bucket the attempt
+    // was working on, because the attempt lost its lease or the bucket got a
+    // canister in the meantime. The canister is then handed to a planned bucket
+    // that doesn't have one yet, or reported as orphaned.
+    pub fn record_created_canister(
+        &mut self,
+        planned_id: u64,
+        holds_lease: bool,
+        canister_id: Principal,
+        cycles_sent: u64,
+        now: TimestampMillis,
+    ) -> bool {
+        let own_bucket = self
+            .planned_buckets
+            .iter()
+            .position(|b| b.id == planned_id && b.spawn_status == SpawnStatus::New)
+            .filter(|_| holds_lease);
+
+        let (bucket, adopted_by_own) = match own_bucket {
+            Some(i) => (i, true),
+            None => match self
+                .planned_buckets
+                .iter()
+                .position(|b| b.spawn_status == SpawnStatus::New)
+            {
+                Some(i) => (i, false),
+                None => {
+                    self.orphaned_canisters.push(OrphanedCanister {
+                        canister_id,
+                        created_at: now,
+                        install_attempts: 0,
+                        resumable: false,
+                    });
+                    return false;
+                }
+            },
+        };
+
+        let bucket = &mut self.planned_buckets[bucket];
+        bucket.canister_id = Some(canister_id);
+        bucket.created_at = Some(now);
+        bucket.spawn_status = SpawnStatus::Created(canister_id);
+        let capacity = bucket.bucket_max_entries as u64;
+
+        self.add_spawned_bucket(SpawnedBucketCanister {
+            canister_id,
+            created_at: now,
+            cycles_sent,
+            wasm_version: None,
+            capacity,
+            status: BucketStatus::Installing,
+            roles_version: 0,
+        });
+        adopted_by_own
+    }
+
+    // The slots of an installed bucket are counted in its index from now on
+    pub fn set_planned_bucket_installed(&mut self, planned_id: u64) {
+        if let Some(bucket) = self.planned_buckets.iter_mut().find(|b| b.id == planned_id) {
+            bucket.spawn_status = SpawnStatus::Installed;
+            bucket.bucket_max_entries = 0;
+        }
+    }
+
+    pub fn record_install_failure(&mut self, planned_id: u64) {
+        if let Some(bucket) = self.planned_buckets.iter_mut().find(|b| b.id == planned_id) {
+            if bucket.canister_id.is_some() {
+                bucket.install_attempts += 1;
+            }
+        }
+    }
+
+    // Every bucket that is behind gets its own push, so a bucket that can't be
+    // reached is retried on its own
+    pub fn queue_role_pushes(&mut self) {
+        for canister_id in self.get_buckets_behind_on_roles() {
+            self.tasks.push(IndexTask::PushRoles(canister_id));
+        }
+    }
+
+    // Running buckets that haven't acknowledged the current role list
+    pub fn get_buckets_behind_on_roles(&self) -> Vec<Principal> {
+        self.get_all_buckets()
+            .into_iter()
+            .filter(|canister_id| {
+                self.get_spawned_bucket(canister_id)
+                    .is_some_and(|b| b.roles_version < self.roles_version)
+            })
+            .collect()
+    }
+
+    pub fn get_role_list(&self) -> RoleList {
+        RoleList {
+            version: self.roles_version,
+            assignments: self.access_control.assignments(),
+        }
+    }
+
+    pub fn get_roles_version(&self) -> u64 {
+        self.roles_version
+    }
+
+    // Buckets keep the newest list they got, so the version they report never
+    // goes down
+    pub fn record_roles_ack(&mut self, canister_id: Principal, version: u64) {
+        if let Some(bucket) = self
+            .spawned_buckets
+            .iter_mut()
+            .find(|b| b.canister_id == canister_id)
+        {
+            bucket.roles_version = bucket.roles_version.max(version);
+        }
+    }
+
+    // Canisters that were created but don't run a bucket yet
+    pub fn get_orphaned_canisters(&self) -> Vec<OrphanedCanister> {
+        let mut orphaned: Vec<OrphanedCanister> = self
+            .planned_buckets
+            .iter()
+            .filter(|b| b.spawn_status != SpawnStatus::Installed)
+            .filter_map(|b| {
+                Some(OrphanedCanister {
+                    canister_id: b.canister_id?,
+                    created_at: b.created_at.unwrap_or_default(),
+                    install_attempts: b.install_attempts,
+                    resumable: true,
+                })
+            })
+            .collect();
+
+        orphaned.extend(self.orphaned_canisters.iter().cloned());
+        orphaned
     }
 
     pub fn add_content_moderator(&mut self, moderator: Principal) {
-        self.content_moderators.push(moderator);
+        self.grant_role(RoleAssignment {
+            principal: moderator,
+            role: Role::Moderator,
+            scope: RoleScope::Global,
+        });
     }
 
+    pub fn remove_content_moderator(&mut self, moderator: Principal) -> bool {
+        self.revoke_role(&RoleAssignment {
+            principal: moderator,
+            role: Role::Moderator,
+            scope: RoleScope::Global,
+        })
+    }
+
+    // Only the principals added through add_content_moderator, admins and
+    // tag moderators are managed through the role calls
     pub fn get_content_moderators(&self) -> Vec<Principal> {
-        self.content_moderators.clone()
+        self.access_control
+            .assignments()
+            .into_iter()
+            .filter(|a| a.role == Role::Moderator && a.scope == RoleScope::Global)
+            .map(|a| a.principal)
+            .collect()
+    }
+
+    pub fn add_admin(&mut self, admin: Principal) {
+        self.grant_role(RoleAssignment {
+            principal: admin,
+            role: Role::Admin,
+            scope: RoleScope::Global,
+        });
+    }
+
+    // Owners count as admins too
+    pub fn get_admins(&self) -> Vec<Principal> {
+        self.access_control.principals_with_role(Role::Admin)
+    }
+
+    // Every change to the role assignments gets pushed to all the buckets
+    pub fn grant_role(&mut self, assignment: RoleAssignment) -> bool {
+        let granted = self.access_control.grant(assignment);
+        if granted {
+            self.roles_version += 1;
+            self.queue_role_pushes();
+        }
+        granted
+    }
+
+    pub fn revoke_role(&mut self, assignment: &RoleAssignment) -> bool {
+        let revoked = self.access_control.revoke(assignment);
+        if revoked {
+            self.roles_version += 1;
+            self.queue_role_pushes();
+        }
+        revoked
+    }
+
+    pub fn get_roles(&self) -> Vec<RoleAssignment> {
+        self.access_control.assignments()
+    }
+
+    pub fn has_role(&self, principal: &Principal, role: Role, tag: Option<&str>) -> bool {
+        self.access_control.has_role(principal, role, tag)
+    }
+
+    // Admins manage moderators and readers, only owners can hand out or take
+    // away the admin and owner roles
+    pub fn can_manage_role(&self, principal: &Principal, role: Role) -> bool {
+        let required = if role >= Role::Admin {
+            Role::Owner
+        } else {
+            Role::Admin
+        };
+
+        self.has_role(principal, required, None)
     }
 }
 
@@ -230,95 +1193,488 @@ impl BusinessState {
 // This should probably be moved to a dedicated data structure & an impl block
 // Might need to move some things like canister settings from "global" data
 
-pub async fn spawn_bucket_loop() {
-    // spawn a new bucket if there are any in the planned queue
-    //
-    // lock planned bucket
-    let planned_bucket_lock: u32 = RUNTIME_STATE.with(|state| prep_lock_bucket(state.borrow_mut()));
+// Runs the tasks that are due, up to MAX_TASKS_PER_HEARTBEAT of them. Failed
+// tasks are retried with a backoff and end up in the dead letters (see
+// getDeadTasks) if they keep failing.
+pub(crate) async fn run_tasks() {
+    for _ in 0..MAX_TASKS_PER_HEARTBEAT {
+        let leased = match RUNTIME_STATE.with(|state| prep_lease_task(state.borrow_mut())) {
+            Some(leased) => leased,
+            None => return,
+        };
 
-    // 0 means we don't have any planned buckets to install
-    if planned_bucket_lock > 0 {
-        // prep canister create
-        let canister_create_args =
-            RUNTIME_STATE.with(|state| prep_canister_create(state.borrow_mut()));
+        let result = match leased.kind {
+            IndexTask::SpawnBucket(planned_id) => spawn_bucket(&leased, planned_id).await,
+            IndexTask::PushRoles(canister_id) => push_roles(canister_id).await,
+            IndexTask::PushReservationKey(canister_id) => push_reservation_key(canister_id).await,
+        };
 
-        // call canister create
-        let canister_id = call_canister_create(canister_create_args).await;
+        RUNTIME_STATE.with(|state| finish_task(&leased, result, state.borrow_mut()));
+    }
+}
+
+fn prep_lease_task(mut runtime_state: RefMut<RuntimeState>) -> Option<LeasedTask<IndexTask>> {
+    // Planned buckets wait until an admin uploads and activates a bucket wasm,
+    // there would be nothing to install in the new canister otherwise
+    let can_spawn = runtime_state.data.bucket_wasms.active_version().is_some();
+    let now = runtime_state.env.now();
+
+    runtime_state
+        .data
+        .business_state
+        .tasks
+        .lease_next(now, |task| match task {
+            IndexTask::SpawnBucket(_) => can_spawn,
+            IndexTask::PushRoles(_) | IndexTask::PushReservationKey(_) => true,
+        })
+}
 
-        // call_canister_create will return anonymous if it can't create a bucket
-        if canister_id != Principal::anonymous() {
+fn finish_task(
+    leased: &LeasedTask<IndexTask>,
+    result: Result<(), String>,
+    mut runtime_state: RefMut<RuntimeState>,
+) {
+    let now = runtime_state.env.now();
+    let business_state = &mut runtime_state.data.business_state;
+
+    match result {
+        Ok(()) => {
+            business_state.tasks.complete(leased);
+        }
+        Err(error) => {
+            print(format!("Task {:?} failed: {}", leased.kind, error));
+            if let IndexTask::SpawnBucket(planned_id) = leased.kind {
+                business_state.record_install_failure(planned_id);
+            }
+            business_state.tasks.fail(leased, error, now);
+        }
+    }
+}
+
+async fn spawn_bucket(leased: &LeasedTask<IndexTask>, planned_id: u64) -> Result<(), String> {
+    let step = match RUNTIME_STATE
+        .with(|state| state.borrow().data.business_state.spawn_step(planned_id))
+    {
+        Some(step) => step,
+        // Already installed, or the planned bucket is gone
+        None => return Ok(()),
+    };
+
+    let canister_id = match step {
+        SpawnStep::Create => {
+            // prep canister create
+            let canister_create_args =
+                RUNTIME_STATE.with(|state| prep_canister_create(state.borrow_mut()));
+            let cycles_sent = canister_create_args.cycles;
+
+            // call canister create
+            let canister_id = call_canister_create(canister_create_args).await;
+
+            // call_canister_create will return anonymous if it can't create a bucket
+            if canister_id == Principal::anonymous() {
+                return Err("The canister couldn't be created".to_string());
+            }
             print(format!("Created canister: {}", canister_id.to_text()));
 
-            // prep canister install
-            let canister_install_args = Encode!(&CanisterInstallSendArgs {
-                greet: "Hello from Index".to_string(),
-                controllers: vec![Principal::from_text(
-                    "l6s27-7ndcl-nowe5-xeyf7-ymdnq-dkemz-jkhfw-zr5wu-jvf2p-aupzq-2qe",
+            // From here on an install failure doesn't lose the canister
+            let adopted = RUNTIME_STATE.with(|state| {
+                let runtime_state = &mut *state.borrow_mut();
+                let now = runtime_state.env.now();
+                let business_state = &mut runtime_state.data.business_state;
+                let holds_lease = business_state.tasks.holds_lease(leased);
+                business_state.record_created_canister(
+                    planned_id,
+                    holds_lease,
+                    canister_id,
+                    cycles_sent,
+                    now,
                 )
-                .unwrap(),],
-            })
-            .unwrap();
+            });
+            if !adopted {
+                return Err("The canister went to another planned bucket".to_string());
+            }
+            canister_id
+        }
+        SpawnStep::Install(canister_id) => {
+            // An earlier attempt may have installed the code after losing its lease
+            match call_canister_has_module(canister_id).await {
+                Some(false) => canister_id,
+                Some(true) => {
+                    RUNTIME_STATE.with(|state| {
+                        let business_state = &mut state.borrow_mut().data.business_state;
+                        business_state.set_bucket_installed(canister_id, None);
+                        business_state.set_planned_bucket_installed(planned_id);
+                        // We don't know which roles or key it was installed with
+                        business_state.queue_role_pushes();
+                        business_state.queue_reservation_key_pushes();
+                    });
+                    return Ok(());
+                }
+                None => return Err("The canister status couldn't be read".to_string()),
+            }
+        }
+    };
+
+    let reservation_key = call_raw_rand()
+        .await
+        .ok_or_else(|| "No randomness for the reservation key".to_string())?;
+
+    // prep canister install
+    let install_config = RUNTIME_STATE
+        .with(|state| prep_canister_install(canister_id, reservation_key.clone(), state.borrow()));
+
+    // call canister install
+    let (install_config, wasm_version, roles_version) =
+        install_config.ok_or_else(|| "There is no active bucket wasm".to_string())?;
+    let installed = call_canister_install(install_config).await;
+    print(format!("Cannister install: {}", installed));
+
+    if !installed {
+        return Err("The bucket wasm couldn't be installed".to_string());
+    }
+
+    RUNTIME_STATE.with(|state| {
+        let business_state = &mut state.borrow_mut().data.business_state;
+        business_state.set_bucket_installed(canister_id, Some(wasm_version));
+        business_state.set_planned_bucket_installed(planned_id);
+        // The bucket got the role list with its install arguments, it only
+        // needs a push if the roles changed since
+        business_state.record_roles_ack(canister_id, roles_version);
+        business_state.queue_role_pushes();
+        business_state.record_reservation_key(canister_id, reservation_key);
+    });
+    Ok(())
+}
+
+// Pull mode: polls a batch of buckets for their index, one part at a time.
+// The next round picks up the buckets after this batch.
+pub(crate) async fn pull_bucket_indexes() {
+    let batch = RUNTIME_STATE.with(|state| prep_pull_round(state.borrow_mut()));
+    if batch.is_empty() {
+        return;
+    }
+
+    for (canister_id, known_version) in batch {
+        call_bucket_pull_index(canister_id, known_version).await;
+    }
+
+    RUNTIME_STATE.with(|state| state.borrow_mut().data.business_state.finish_pull_round());
+}
+
+fn prep_pull_round(mut runtime_state: RefMut<RuntimeState>) -> Vec<(Principal, u64)> {
+    let settings = &runtime_state.data.canister_settings;
+    if settings.index_sync_mode != IndexSyncMode::Pull {
+        return vec![];
+    }
+    let interval = settings.reindex_interval;
+    let batch_size = settings.pull_batch_size;
+    let now = runtime_state.env.now();
+
+    runtime_state
+        .data
+        .business_state
+        .start_pull_round(now, interval, batch_size)
+}
 
-            // call canister install
-            let result: bool = call_canister_install(&canister_id, canister_install_args).await;
-            print(format!("Cannister install: {}", result));
-
-            if result {
-                // set planned bucket to installed
-                RUNTIME_STATE.with(|state| {
-                    update_planned_bucket(true, planned_bucket_lock, state.borrow_mut())
-                });
-            } else {
-                RUNTIME_STATE.with(|state| {
-                    update_planned_bucket(false, planned_bucket_lock, state.borrow_mut())
-                });
+// The bucket answers with the parts of its index that are newer than
+// known_version, or None if there is nothing new
+async fn call_bucket_pull_index(canister_id: Principal, known_version: u64) {
+    let mut part: u32 = 0;
+
+    loop {
+        let result: CallResult<(Option<IndexUpdate>,)> =
+            ic_cdk::api::call::call(canister_id, "pull_bucket_index", (known_version, part)).await;
+
+        let update = match result {
+            Ok((Some(update),)) => update,
+            Ok((None,)) => return,
+            Err((code, msg)) => {
+                print(format!(
+                    "An error happened during the call: {}: {}",
+                    code as u8, msg
+                ));
+                return;
             }
+        };
+
+        let sync_result = RUNTIME_STATE.with(|state| {
+            state
+                .borrow_mut()
+                .data
+                .business_state
+                .apply_index_update(canister_id, update)
+        });
+
+        match sync_result {
+            IndexSyncResult::PartAccepted => part += 1,
+            _ => return,
+        }
+    }
+}
+
+// None if the status couldn't be read
+async fn call_canister_has_module(canister_id: Principal) -> Option<bool> {
+    // Only the part of the canister status that we need
+    #[derive(CandidType, Deserialize)]
+    struct CanisterStatus {
+        module_hash: Option<Vec<u8>>,
+    }
+
+    let result: CallResult<(CanisterStatus,)> = ic_cdk::api::call::call(
+        Principal::management_canister(),
+        "canister_status",
+        (CanisterIdRecord { canister_id },),
+    )
+    .await;
+
+    match result {
+        Ok((status,)) => Some(status.module_hash.is_some()),
+        Err((code, msg)) => {
+            print(format!(
+                "An error happened during the call: {}: {}",
+                code as u8, msg
+            ));
+            None
+        }
+    }
+}
+
+// 32 random bytes from the management canister, None if the call failed
+async fn call_raw_rand() -> Option<Vec<u8>> {
+    let result: CallResult<(Vec<u8>,)> =
+        ic_cdk::api::call::call(Principal::management_canister(), "raw_rand", ()).await;
+
+    match result {
+        Ok((bytes,)) => Some(bytes),
+        Err((code, msg)) => {
+            print(format!(
+                "An error happened during the call: {}: {}",
+                code as u8, msg
+            ));
+            None
+        }
+    }
+}
+
+// A new key every attempt, it's only recorded once the bucket has it
+async fn push_reservation_key(canister_id: Principal) -> Result<(), String> {
+    if RUNTIME_STATE.with(|state| {
+        state
+            .borrow()
+            .data
+            .business_state
+            .has_reservation_key(&canister_id)
+    }) {
+        return Ok(());
+    }
+
+    let key = call_raw_rand()
+        .await
+        .ok_or_else(|| "No randomness for the reservation key".to_string())?;
+
+    if !call_bucket_set_reservation_key(canister_id, key.clone()).await {
+        return Err("The reservation key couldn't be pushed".to_string());
+    }
+
+    RUNTIME_STATE.with(|state| {
+        state
+            .borrow_mut()
+            .data
+            .business_state
+            .record_reservation_key(canister_id, key)
+    });
+    Ok(())
+}
+
+async fn call_bucket_set_reservation_key(canister_id: Principal, key: Vec<u8>) -> bool {
+    let result: CallResult<(bool,)> =
+        ic_cdk::api::call::call(canister_id, "set_reservation_key", (key,)).await;
+
+    match result {
+        Ok((set,)) => set,
+        Err((code, msg)) => {
+            print(format!(
+                "An error happened during the call: {}: {}",
+                code as u8, msg
+            ));
+            false
+        }
+    }
+}
+
+// Forwards an entry to the buckets in upload order, on behalf of its author.
+// A full or unreachable bucket makes us try the next one, our view of the
+// buckets can be a sync behind.
+async fn forward_to_buckets(
+    author: Principal,
+    tag: &str,
+    body: &str,
+) -> Result<GlobalEntryId, ForwardError> {
+    let buckets = RUNTIME_STATE.with(|state| {
+        state
+            .borrow()
+            .data
+            .business_state
+            .where_to_upload(Some(tag))
+    });
+
+    for canister_id in buckets.into_iter().take(MAX_POST_BUCKETS) {
+        match call_bucket_post_content(canister_id, author, tag.to_string(), body.to_string()).await
+        {
+            Some(Ok(location)) => return Ok(location),
+            Some(Err(BucketPostError::Banned)) => return Err(ForwardError::Banned),
+            Some(Err(BucketPostError::Full)) | None => {
+                print(format!(
+                    "Couldn't post to {}, trying the next bucket",
+                    canister_id
+                ));
+            }
+        }
+    }
+
+    Err(ForwardError::NoRoom)
+}
+
+// Posts that none of the buckets we tried could take are queued until a
+// bucket has room
+pub(crate) async fn post_content(author: Principal, tag: String, body: String) -> PostResult {
+    match forward_to_buckets(author, &tag, &body).await {
+        Ok(location) => PostResult::Ok(location),
+        Err(ForwardError::Banned) => PostResult::Err("You are banned from posting".to_string()),
+        Err(ForwardError::NoRoom) => RUNTIME_STATE.with(|state| {
+            let runtime_state = &mut *state.borrow_mut();
+            let now = runtime_state.env.now();
+            match runtime_state
+                .data
+                .business_state
+                .queue_write(author, tag, body, now)
+            {
+                Ok(id) => PostResult::Queued(id),
+                Err(msg) => PostResult::Err(msg),
+            }
+        }),
+    }
+}
+
+// Writes out a batch of queued posts, oldest first, as long as the buckets
+// have room
+pub(crate) async fn flush_pending_writes() {
+    let writes = RUNTIME_STATE.with(|state| prep_flush_pending_writes(state.borrow_mut()));
+
+    let mut no_room = false;
+    for write in writes {
+        // No room for the previous write means none for this one, it's
+        // released for a later flush
+        let result = if no_room {
+            None
+        } else {
+            match forward_to_buckets(write.author, &write.tag, &write.body).await {
+                Ok(location) => Some(Ok(location)),
+                Err(ForwardError::Banned) => {
+                    Some(Err("The author is banned from posting".to_string()))
+                }
+                Err(ForwardError::NoRoom) => {
+                    no_room = true;
+                    None
+                }
+            }
+        };
+
+        RUNTIME_STATE.with(|state| {
+            state
+                .borrow_mut()
+                .data
+                .business_state
+                .finish_pending_write(write.id, result)
+        });
+    }
+}
+
+fn prep_flush_pending_writes(mut runtime_state: RefMut<RuntimeState>) -> Vec<PendingWrite> {
+    let now = runtime_state.env.now();
+    let business_state = &mut runtime_state.data.business_state;
+
+    if business_state.where_to_upload(None).is_empty() {
+        return vec![];
+    }
+    business_state.lease_pending_writes(now, MAX_WRITES_PER_FLUSH)
+}
+
+// What a bucket answers when we forward an entry, None if the call failed
+async fn call_bucket_post_content(
+    canister_id: Principal,
+    author: Principal,
+    tag: String,
+    body: String,
+) -> Option<Result<GlobalEntryId, BucketPostError>> {
+    let result: CallResult<(Result<GlobalEntryId, BucketPostError>,)> =
+        ic_cdk::api::call::call(canister_id, "post_content_for", (author, tag, body)).await;
+
+    match result {
+        Ok((posted,)) => Some(posted),
+        Err((code, msg)) => {
+            print(format!(
+                "An error happened during the call: {}: {}",
+                code as u8, msg
+            ));
+            None
         }
     }
 }
 
-fn update_planned_bucket(
-    installed: bool,
-    planned_bucket_lock: u32,
-    mut runtime_state: RefMut<RuntimeState>,
-) {
-    for bucket in runtime_state.data.business_state.planned_buckets.iter_mut() {
-        if let SpawnStatus::InWork(lock) = bucket.spawn_status {
-            if lock == planned_bucket_lock {
-                if installed {
-                    bucket.spawn_status = SpawnStatus::Installed;
-                    bucket.bucket_max_entries = 0;
-                } else {
-                    bucket.spawn_status = SpawnStatus::New;
-                }
-            }
+// Sends the current role list, so a retry never sends a stale one. A change
+// made during the call queues a new push, so this one is done either way once
+// the bucket acknowledged the list it got.
+async fn push_roles(canister_id: Principal) -> Result<(), String> {
+    let roles = RUNTIME_STATE.with(|state| {
+        let business_state = &state.borrow().data.business_state;
+        // An earlier push or the install already brought the bucket up to date
+        if business_state
+            .get_buckets_behind_on_roles()
+            .contains(&canister_id)
+        {
+            Some(business_state.get_role_list())
+        } else {
+            None
         }
-    }
+    });
+    let roles = match roles {
+        Some(roles) => roles,
+        None => return Ok(()),
+    };
+
+    let version = call_bucket_push_roles(canister_id, roles)
+        .await
+        .ok_or_else(|| "The roles couldn't be pushed".to_string())?;
+
+    RUNTIME_STATE.with(|state| {
+        state
+            .borrow_mut()
+            .data
+            .business_state
+            .record_roles_ack(canister_id, version)
+    });
+    Ok(())
 }
 
-async fn call_bucket_push_moderators(canister_id: Principal, moderators: Vec<Principal>) -> bool {
-    match ic_cdk::api::call::call(canister_id, "add_content_moderators", (moderators,)).await {
-        Ok(x) => x,
+// Returns the role list version the bucket has after the push
+async fn call_bucket_push_roles(canister_id: Principal, roles: RoleList) -> Option<u64> {
+    let result: CallResult<(u64,)> =
+        ic_cdk::api::call::call(canister_id, "set_roles", (roles,)).await;
+
+    match result {
+        Ok((version,)) => Some(version),
         Err((code, msg)) => {
             print(format!(
                 "An error happened during the call: {}: {}",
                 code as u8, msg
             ));
-            return false;
+            None
         }
-    };
-
-    true
+    }
 }
 
-async fn call_canister_install(canister_id: &Principal, canister_install_args: Vec<u8>) -> bool {
-    let install_config: CanisterInstall = CanisterInstall {
-        mode: InstallMode::Install,
-        canister_id: canister_id.clone(),
-        wasm_module: BUCKET_WASM.to_vec(),
-        arg: canister_install_args,
-    };
-
+pub(crate) async fn call_canister_install(install_config: CanisterInstall) -> bool {
     match ic_cdk::api::call::call(
         Principal::management_canister(),
         "install_code",
@@ -339,6 +1695,34 @@ async fn call_canister_install(canister_id: &Principal, canister_install_args: V
     true
 }
 
+pub(crate) async fn call_canister_stop(canister_id: Principal) -> bool {
+    call_management_canister("stop_canister", canister_id).await
+}
+
+pub(crate) async fn call_canister_start(canister_id: Principal) -> bool {
+    call_management_canister("start_canister", canister_id).await
+}
+
+async fn call_management_canister(method: &str, canister_id: Principal) -> bool {
+    let result: CallResult<()> = ic_cdk::api::call::call(
+        Principal::management_canister(),
+        method,
+        (CanisterIdRecord { canister_id },),
+    )
+    .await;
+
+    match result {
+        Ok(()) => true,
+        Err((code, msg)) => {
+            print(format!(
+                "An error happened during the call: {}: {}",
+                code as u8, msg
+            ));
+            false
+        }
+    }
+}
+
 async fn call_canister_create(canister_create_args: CreateCanisterArgs) -> Principal {
     print("creating bucket...");
 
@@ -379,18 +1763,17 @@ async fn call_canister_create(canister_create_args: CreateCanisterArgs) -> Princ
 
 fn prep_canister_create(runtime_state: RefMut<RuntimeState>) -> CreateCanisterArgs {
     let controller_id = runtime_state.env.canister_id();
+    let settings = &runtime_state.data.canister_settings;
+
+    // The extra bucket controllers from the init args can be used in case
+    // manual control is needed
+    let mut controllers = vec![controller_id];
+    controllers.extend(settings.bucket_controllers.iter().cloned());
 
-    // Add your own principal as a controller, in case manual control is needed
     let create_args = CreateCanisterArgs {
-        cycles: 100_000_000_000,
+        cycles: settings.cycles_per_bucket,
         settings: CreateCanisterSettings {
-            controllers: Some(vec![
-                controller_id.clone(),
-                Principal::from_text(
-                    "l6s27-7ndcl-nowe5-xeyf7-ymdnq-dkemz-jkhfw-zr5wu-jvf2p-aupzq-2qe",
-                )
-                .unwrap(),
-            ]),
+            controllers: Some(controllers),
             compute_allocation: None,
             memory_allocation: None,
             freezing_threshold: None,
@@ -400,16 +1783,39 @@ fn prep_canister_create(runtime_state: RefMut<RuntimeState>) -> CreateCanisterAr
     create_args
 }
 
-fn prep_lock_bucket(mut runtime_state: RefMut<RuntimeState>) -> u32 {
-    let bucket_lock = runtime_state.env.random_u32();
-
-    for bucket in runtime_state.data.business_state.planned_buckets.iter_mut() {
-        if let SpawnStatus::New = bucket.spawn_status {
-            bucket.spawn_status = SpawnStatus::InWork(bucket_lock);
-            return bucket_lock;
-        }
-    }
-    0
+// Returns None if no bucket wasm version is active, otherwise the install
+// config and the name of the version it installs
+fn prep_canister_install(
+    canister_id: Principal,
+    reservation_key: Vec<u8>,
+    runtime_state: Ref<RuntimeState>,
+) -> Option<(CanisterInstall, String, u64)> {
+    let settings = &runtime_state.data.canister_settings;
+    let wasm_version = runtime_state.data.bucket_wasms.active_version()?;
+    let wasm_module = runtime_state.data.bucket_wasms.active_module()?;
+    let roles = runtime_state.data.business_state.get_role_list();
+    let roles_version = roles.version;
+
+    let arg = Encode!(&CanisterInstallSendArgs {
+        greet: "Hello from Index".to_string(),
+        controllers: settings.bucket_controllers.clone(),
+        bucket_max_entries: settings.bucket_max_entries,
+        bucket_capacity_mode: settings.bucket_capacity_mode,
+        roles,
+        reservation_key,
+    })
+    .unwrap();
+
+    Some((
+        CanisterInstall {
+            mode: InstallMode::Install,
+            canister_id,
+            wasm_module,
+            arg,
+        },
+        wasm_version.name,
+        roles_version,
+    ))
 }
 
 pub(crate) fn should_spawn_buckets(runtime_state: Ref<RuntimeState>) -> bool {
@@ -422,8 +1828,13 @@ pub(crate) fn should_spawn_buckets(runtime_state: Ref<RuntimeState>) -> bool {
 
     // We need to sum the available free slots with the planned free slots,
     // so that we don't add too many buckets
-    runtime_state.data.business_state.get_planned_slots()
-        + runtime_state.data.business_state.get_free_slots()
+    // Reserved slots and queued writes are about to be used, they don't
+    // count as free
+    let business_state = &runtime_state.data.business_state;
+    business_state.get_planned_slots()
+        + business_state.get_free_slots().saturating_sub(
+            business_state.get_reserved_slots() + business_state.get_pending_write_count() as u128,
+        )
         < runtime_state.data.canister_settings.desired_free_slots
 }
 
@@ -456,6 +1867,24 @@ pub(crate) fn reindex_tag_to_canisters(mut runtime_state: RefMut<RuntimeState>)
 struct CanisterInstallSendArgs {
     greet: String,
     controllers: Vec<Principal>,
+    bucket_max_entries: u64,
+    bucket_capacity_mode: CapacityMode,
+    roles: RoleList,
+    #[serde(with = "serde_bytes")]
+    reservation_key: Vec<u8>,
+}
+
+// The bucket's PostError
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+enum BucketPostError {
+    Full,
+    Banned,
+}
+
+enum ForwardError {
+    Banned,
+    // Every bucket we tried was full or unreachable
+    NoRoom,
 }
 
 #[derive(CandidType, Clone, Deserialize, Debug)]
@@ -478,7 +1907,7 @@ pub struct CreateCanisterArgs {
 }
 
 #[derive(CandidType, Deserialize)]
-enum InstallMode {
+pub(crate) enum InstallMode {
     #[serde(rename = "install")]
     Install,
     #[serde(rename = "reinstall")]
@@ -488,13 +1917,21 @@ enum InstallMode {
 }
 
 #[derive(CandidType, Deserialize)]
-struct CanisterInstall {
-    mode: InstallMode,
-    canister_id: Principal,
+pub(crate) struct CanisterInstall {
+    pub(crate) mode: InstallMode,
+    pub(crate) canister_id: Principal,
     #[serde(with = "serde_bytes")]
-    wasm_module: Vec<u8>,
+    pub(crate) wasm_module: Vec<u8>,
     #[serde(with = "serde_bytes")]
-    arg: Vec<u8>,
+    pub(crate) arg: Vec<u8>,
+}
+
+// Where a tag or a bucket sits on the TagAffinity hash ring
+fn ring_position(bytes: &[u8]) -> u64 {
+    let hash = Sha256::digest(bytes);
+    let mut position = [0u8; 8];
+    position.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(position)
 }
 
 // Unit tests
@@ -517,6 +1954,7 @@ mod tests {
         let mut business_state = BusinessState::default();
 
         let bucket_index1 = EffectiveIndex {
+            version: 1,
             tags: vec![
                 "#rabbit".to_string(),
                 "#fox".to_string(),
@@ -524,12 +1962,15 @@ mod tests {
             ],
             current_entries: 5,
             bucket_max_entries: 20,
+            bytes_used: 0,
+            bytes_capacity: u64::MAX,
         };
         let can_id1 = Principal::from_slice(&[1]);
 
         business_state.add_bucket_index(can_id1, bucket_index1);
 
         let bucket_index2 = EffectiveIndex {
+            version: 1,
             tags: vec![
                 "#rabbit".to_string(),
                 "#cat".to_string(),
@@ -537,6 +1978,8 @@ mod tests {
             ],
             current_entries: 3,
             bucket_max_entries: 20,
+            bytes_used: 0,
+            bytes_capacity: u64::MAX,
         };
         let can_id2 = Principal::from_slice(&[2]);
 
@@ -550,6 +1993,7 @@ mod tests {
         let mut business_state = BusinessState::default();
 
         let bucket_index1 = EffectiveIndex {
+            version: 1,
             tags: vec![
                 "#rabbit".to_string(),
                 "#fox".to_string(),
@@ -557,12 +2001,15 @@ mod tests {
             ],
             current_entries: 5,
             bucket_max_entries: 20,
+            bytes_used: 0,
+            bytes_capacity: u64::MAX,
         };
         let can_id1 = Principal::from_slice(&[1]);
 
         business_state.add_bucket_index(can_id1, bucket_index1);
 
         let bucket_index2 = EffectiveIndex {
+            version: 1,
             tags: vec![
                 "#rabbit".to_string(),
                 "#cat".to_string(),
@@ -570,6 +2017,8 @@ mod tests {
             ],
             current_entries: 3,
             bucket_max_entries: 20,
+            bytes_used: 0,
+            bytes_capacity: u64::MAX,
         };
         let can_id2 = Principal::from_slice(&[2]);
 
@@ -589,6 +2038,7 @@ mod tests {
         let mut business_state = BusinessState::default();
 
         let bucket_index1 = EffectiveIndex {
+            version: 1,
             tags: vec![
                 "#rabbit".to_string(),
                 "#fox".to_string(),
@@ -596,12 +2046,15 @@ mod tests {
             ],
             current_entries: 5,
             bucket_max_entries: 20,
+            bytes_used: 0,
+            bytes_capacity: u64::MAX,
         };
         let can_id1 = Principal::from_slice(&[1]);
 
         business_state.add_bucket_index(can_id1, bucket_index1.clone());
 
         let bucket_index2 = EffectiveIndex {
+            version: 1,
             tags: vec![
                 "#rabbit".to_string(),
                 "#cat".to_string(),
@@ -609,12 +2062,15 @@ mod tests {
             ],
             current_entries: 3,
             bucket_max_entries: 20,
+            bytes_used: 0,
+            bytes_capacity: u64::MAX,
         };
         let can_id2 = Principal::from_slice(&[2]);
 
         business_state.add_bucket_index(can_id2, bucket_index2.clone());
 
         let bucket_index3 = EffectiveIndex {
+            version: 1,
             tags: vec![
                 "#rabbit".to_string(),
                 "#cat".to_string(),
@@ -622,6 +2078,8 @@ mod tests {
             ],
             current_entries: 6,
             bucket_max_entries: 20,
+            bytes_used: 0,
+            bytes_capacity: u64::MAX,
         };
         let can_id3 = Principal::from_slice(&[3]);
 
@@ -633,7 +2091,7 @@ mod tests {
 
         // 3 5 6 -> we're filling from most empty when in BalancedLoad
         assert_eq!(
-            business_state.where_to_upload(),
+            business_state.where_to_upload(None),
             vec![
                 Principal::from_text("hqgi5-iic").unwrap(),
                 Principal::from_text("uuc56-gyb").unwrap(),
@@ -645,7 +2103,7 @@ mod tests {
 
         // 6 5 3 -> we're filling from most entries first, in FillFirst
         assert_eq!(
-            business_state.where_to_upload(),
+            business_state.where_to_upload(None),
             vec![
                 Principal::from_text("jmf34-nyd").unwrap(),
                 Principal::from_text("uuc56-gyb").unwrap(),
@@ -667,32 +2125,745 @@ mod tests {
             "Indexing strategy: {:?} {:?}",
             business_state.indexing_strategy,
             business_state
-                .where_to_upload()
+                .where_to_upload(None)
                 .iter()
                 .map(|s| s.to_text())
                 .collect::<Vec<String>>()
         );
     }
-}
 
-pub(crate) async fn push_moderators() {
-    // Check if we need to push moderators first
-    if let true = RUNTIME_STATE.with(|state| state.borrow().data.business_state.push_moderators) {
-        let prep_push = RUNTIME_STATE.with(|state| prep_push_moderators(state.borrow()));
+    #[test]
+    fn tag_affinity() {
+        let mut business_state = BusinessState::default();
+        business_state.set_indexing_strategy(IndexingStrategy::TagAffinity);
+
+        let index = |tags: &[&str], current_entries: u64| EffectiveIndex {
+            version: 1,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            current_entries,
+            bucket_max_entries: 20,
+            bytes_used: 0,
+            bytes_capacity: u64::MAX,
+        };
+        let bucket1 = Principal::from_slice(&[1]);
+        let bucket2 = Principal::from_slice(&[2]);
+        let bucket3 = Principal::from_slice(&[3]);
+        business_state.add_bucket_index(bucket1, index(&["#rabbit"], 20));
+        business_state.add_bucket_index(bucket2, index(&["#fox"], 2));
+        business_state.add_bucket_index(bucket3, index(&["#rabbit", "#cat"], 15));
+        business_state.global_index.tag_to_canisters =
+            business_state.generate_index_tag_to_canisters();
+
+        // The bucket that holds the tag and has room comes first, full ones
+        // aren't listed
+        let order = business_state.where_to_upload(Some("#rabbit"));
+        assert_eq!(order[0], bucket3);
+        assert_eq!(order.len(), 2);
+        assert_eq!(business_state.where_to_upload(Some("#fox"))[0], bucket2);
+
+        // Without a tag, the emptiest bucket comes first
+        assert_eq!(business_state.where_to_upload(None), vec![bucket2, bucket3]);
+
+        // New tags go where the ring sends them, and a new bucket only takes
+        // over the tags it lands in front of
+        let tags: Vec<String> = (0..20).map(|i| format!("#tag{}", i)).collect();
+        let before: Vec<Vec<Principal>> = tags
+            .iter()
+            .map(|t| business_state.where_to_upload(Some(t)))
+            .collect();
+        for (tag, order) in tags.iter().zip(before.iter()) {
+            let start = ring_position(tag.as_bytes());
+            let next_on_ring = [bucket2, bucket3]
+                .iter()
+                .copied()
+                .min_by_key(|b| ring_position(b.as_slice()).wrapping_sub(start))
+                .unwrap();
+            assert_eq!(order[0], next_on_ring);
+        }
+        let mut first_choices: Vec<Principal> = before.iter().map(|order| order[0]).collect();
+        first_choices.sort();
+        first_choices.dedup();
+        assert_eq!(first_choices.len(), 2);
+
+        let bucket4 = Principal::from_slice(&[4]);
+        business_state.add_bucket_index(bucket4, index(&[], 0));
+        for (tag, order) in tags.iter().zip(before.iter()) {
+            let after: Vec<Principal> = business_state
+                .where_to_upload(Some(tag))
+                .into_iter()
+                .filter(|b| *b != bucket4)
+                .collect();
+            assert_eq!(&after, order);
+        }
+    }
+
+    #[test]
+    fn slot_reservations() {
+        let mut business_state = BusinessState::default();
+        let user1 = Principal::from_slice(&[1]);
+        let user2 = Principal::from_slice(&[2]);
+        let bucket1 = Principal::from_slice(&[10]);
+        let bucket2 = Principal::from_slice(&[11]);
+        let key = b"bucket key".to_vec();
+
+        business_state.add_bucket_index(
+            bucket1,
+            EffectiveIndex {
+                version: 1,
+                tags: vec![],
+                current_entries: 18,
+                bucket_max_entries: 20,
+                bytes_used: 0,
+                bytes_capacity: u64::MAX,
+            },
+        );
 
-        for canister_id in prep_push.0 {
-            let _result =
-                call_bucket_push_moderators(canister_id.clone(), prep_push.1.clone()).await;
+        // The bucket doesn't have its key yet
+        assert_eq!(business_state.reserve_slot(user1, None, 0), None);
+
+        business_state.record_reservation_key(bucket1, key.clone());
+        let reservation = business_state.reserve_slot(user1, None, 0).unwrap();
+        assert_eq!(reservation.bucket, bucket1);
+        assert_eq!(reservation.holder, user1);
+        assert_eq!(reservation.expires_at, RESERVATION_TTL);
+        assert!(reservation.verify(&key));
+
+        // Reserved slots aren't offered to anyone else
+        assert!(business_state.reserve_slot(user2, None, 0).is_some());
+        assert_eq!(business_state.reserve_slot(user2, None, 0), None);
+        assert!(business_state.where_to_upload(None).is_empty());
+        assert_eq!(business_state.get_reserved_slots(), 2);
+
+        // One caller can't hold all the slots
+        business_state.add_bucket_index(
+            bucket2,
+            EffectiveIndex {
+                version: 1,
+                tags: vec![],
+                current_entries: 0,
+                bucket_max_entries: 20,
+                bytes_used: 0,
+                bytes_capacity: u64::MAX,
+            },
+        );
+        business_state.record_reservation_key(bucket2, key);
+        for _ in 1..MAX_RESERVATIONS_PER_CALLER {
+            assert!(business_state.reserve_slot(user1, None, 0).is_some());
+        }
+        assert_eq!(business_state.reserve_slot(user1, None, 0), None);
+        assert!(business_state.reserve_slot(user2, None, 0).is_some());
+
+        // Expired reservations give their slots back
+        business_state.prune_reservations(RESERVATION_TTL);
+        assert_eq!(business_state.get_reserved_slots(), 0);
+        assert_eq!(business_state.where_to_upload(None), vec![bucket2, bucket1]);
+
+        // Buckets are ranked by the slots that aren't reserved
+        let bucket3 = Principal::from_slice(&[12]);
+        business_state.add_bucket_index(
+            bucket3,
+            EffectiveIndex {
+                version: 1,
+                tags: vec![],
+                current_entries: 2,
+                bucket_max_entries: 20,
+                bytes_used: 0,
+                bytes_capacity: u64::MAX,
+            },
+        );
+        business_state.record_reservation_key(bucket3, b"bucket key".to_vec());
+        for _ in 0..MAX_RESERVATIONS_PER_CALLER {
+            assert!(business_state.reserve_slot(user1, None, 0).is_some());
         }
+        assert!(business_state
+            .reservations
+            .iter()
+            .any(|r| r.bucket == bucket3));
+        let unreserved: Vec<u64> = business_state
+            .where_to_upload(None)
+            .iter()
+            .map(|b| business_state.unreserved_slots(b, &business_state.bucket_indexes[b]))
+            .collect();
+        assert!(unreserved.windows(2).all(|w| w[0] >= w[1]));
+    }
+
+    #[test]
+    fn byte_capacity() {
+        let mut business_state = BusinessState::default();
+        let bucket1 = Principal::from_slice(&[10]);
+        let bucket2 = Principal::from_slice(&[11]);
+        let bucket3 = Principal::from_slice(&[12]);
+        let bucket4 = Principal::from_slice(&[13]);
+        let index = |current_entries: u64, bytes_used: u64, bytes_capacity: u64| EffectiveIndex {
+            version: 1,
+            tags: vec![],
+            current_entries,
+            bucket_max_entries: 20,
+            bytes_used,
+            bytes_capacity,
+        };
 
-        // Unset push_moderators
-        RUNTIME_STATE.with(|state| state.borrow_mut().data.business_state.push_moderators = false);
+        // Entries of 1000 bytes, room for 8 more
+        business_state.add_bucket_index(bucket1, index(2, 2_000, 10_000));
+        // Entries of 100 bytes, the entry count is the limit
+        business_state.add_bucket_index(bucket2, index(2, 200, 20_000));
+        // Empty, its entries are assumed to be ESTIMATED_ENTRY_BYTES
+        business_state.add_bucket_index(bucket3, index(0, 0, 3 * ESTIMATED_ENTRY_BYTES + 10));
+        // Out of memory
+        business_state.add_bucket_index(bucket4, index(1, 500, 500));
+
+        assert_eq!(business_state.bucket_indexes[&bucket1].free_slots(), 8);
+        assert_eq!(business_state.bucket_indexes[&bucket2].free_slots(), 18);
+        assert_eq!(business_state.bucket_indexes[&bucket3].free_slots(), 3);
+        assert_eq!(business_state.get_free_slots(), 29);
+
+        assert_eq!(
+            business_state.where_to_upload(None),
+            vec![bucket2, bucket1, bucket3]
+        );
+        business_state.set_indexing_strategy(IndexingStrategy::FillFirst);
+        assert_eq!(
+            business_state.where_to_upload(None),
+            vec![bucket3, bucket1, bucket2]
+        );
     }
-}
 
-fn prep_push_moderators(runtime_state: Ref<RuntimeState>) -> (Vec<Principal>, Vec<Principal>) {
-    (
-        runtime_state.data.business_state.get_all_buckets(),
-        runtime_state.data.business_state.get_content_moderators(),
-    )
+    #[test]
+    fn pending_writes() {
+        let mut business_state = BusinessState::default();
+        let user1 = Principal::from_slice(&[1]);
+        let user2 = Principal::from_slice(&[2]);
+        let bucket = Principal::from_slice(&[10]);
+
+        let first = business_state
+            .queue_write(user1, "tag".to_string(), "first".to_string(), 0)
+            .unwrap();
+        let second = business_state
+            .queue_write(user1, "tag".to_string(), "second".to_string(), 1)
+            .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(business_state.get_pending_write_count(), 2);
+
+        // The queue is bounded by size too
+        let too_big = "x".repeat(MAX_PENDING_WRITE_BYTES as usize);
+        assert!(business_state
+            .queue_write(user2, "tag".to_string(), too_big, 2)
+            .is_err());
+
+        // Only the author sees the status
+        assert_eq!(
+            business_state.get_write_status(first, &user1),
+            Some(QueuedWriteStatus::Pending)
+        );
+        assert_eq!(business_state.get_write_status(first, &user2), None);
+
+        // Leased writes aren't handed out twice until the lease expires
+        let leased = business_state.lease_pending_writes(10, 1);
+        assert_eq!(leased.len(), 1);
+        assert_eq!(leased[0].id, first);
+        let leased = business_state.lease_pending_writes(10, MAX_WRITES_PER_FLUSH);
+        assert_eq!(leased.len(), 1);
+        assert_eq!(leased[0].id, second);
+        assert!(business_state.lease_pending_writes(10, 1).is_empty());
+        assert_eq!(
+            business_state
+                .lease_pending_writes(10 + WRITE_FLUSH_LEASE, 1)
+                .len(),
+            1
+        );
+
+        // Released writes stay queued, finished ones keep their outcome
+        business_state.finish_pending_write(second, None);
+        business_state.finish_pending_write(first, Some(Ok(GlobalEntryId { bucket, id: 3 })));
+        assert_eq!(business_state.get_pending_write_count(), 1);
+        assert_eq!(
+            business_state.get_write_status(first, &user1),
+            Some(QueuedWriteStatus::Stored(GlobalEntryId { bucket, id: 3 }))
+        );
+        assert_eq!(business_state.lease_pending_writes(20, 10)[0].id, second);
+        business_state.finish_pending_write(second, Some(Err("Banned".to_string())));
+        assert_eq!(
+            business_state.get_write_status(second, &user1),
+            Some(QueuedWriteStatus::Rejected("Banned".to_string()))
+        );
+        assert_eq!(business_state.get_pending_write_count(), 0);
+    }
+
+    #[test]
+    fn role_management() {
+        let owner = Principal::from_slice(&[1]);
+        let admin = Principal::from_slice(&[2]);
+        let moderator = Principal::from_slice(&[3]);
+        let bucket = Principal::from_slice(&[4]);
+
+        let mut business_state = BusinessState::default();
+        business_state.add_spawned_bucket(SpawnedBucketCanister {
+            canister_id: bucket,
+            created_at: 0,
+            cycles_sent: 0,
+            wasm_version: None,
+            capacity: 20,
+            status: BucketStatus::Active,
+            roles_version: 0,
+        });
+        let role_pushes = |business_state: &BusinessState| -> Vec<IndexTask> {
+            business_state
+                .tasks
+                .tasks()
+                .iter()
+                .map(|t| t.kind)
+                .collect()
+        };
+
+        business_state.grant_role(RoleAssignment {
+            principal: owner,
+            role: Role::Owner,
+            scope: RoleScope::Global,
+        });
+        business_state.grant_role(RoleAssignment {
+            principal: admin,
+            role: Role::Admin,
+            scope: RoleScope::Global,
+        });
+        // One push per bucket that hasn't been sent yet
+        assert_eq!(
+            role_pushes(&business_state),
+            vec![IndexTask::PushRoles(bucket)]
+        );
+
+        assert!(business_state.can_manage_role(&owner, Role::Admin));
+        assert!(business_state.can_manage_role(&admin, Role::Moderator));
+        assert!(!business_state.can_manage_role(&admin, Role::Admin));
+        assert!(!business_state.can_manage_role(&moderator, Role::Reader));
+
+        business_state.tasks = TaskQueue::default();
+        business_state.add_content_moderator(moderator);
+        assert_eq!(role_pushes(&business_state).len(), 1);
+        assert_eq!(business_state.get_content_moderators(), vec![moderator]);
+        assert_eq!(business_state.get_admins(), vec![owner, admin]);
+
+        business_state.tasks = TaskQueue::default();
+        assert!(business_state.remove_content_moderator(moderator));
+        assert_eq!(role_pushes(&business_state).len(), 1);
+        assert!(business_state.get_content_moderators().is_empty());
+
+        // Nothing changed, nothing to push
+        business_state.tasks = TaskQueue::default();
+        assert!(!business_state.remove_content_moderator(moderator));
+        assert!(role_pushes(&business_state).is_empty());
+
+        // Only the buckets that didn't acknowledge the current list are pushed to
+        let other_bucket = Principal::from_slice(&[5]);
+        business_state.add_spawned_bucket(SpawnedBucketCanister {
+            canister_id: other_bucket,
+            created_at: 0,
+            cycles_sent: 0,
+            wasm_version: None,
+            capacity: 20,
+            status: BucketStatus::Active,
+            roles_version: 0,
+        });
+        let version = business_state.get_roles_version();
+        assert_eq!(version, 4);
+        assert_eq!(business_state.get_role_list().version, version);
+
+        business_state.record_roles_ack(bucket, version);
+        business_state.record_roles_ack(bucket, version - 1);
+        assert_eq!(
+            business_state.get_buckets_behind_on_roles(),
+            vec![other_bucket]
+        );
+        business_state.queue_role_pushes();
+        assert_eq!(
+            role_pushes(&business_state),
+            vec![IndexTask::PushRoles(other_bucket)]
+        );
+    }
+
+    #[test]
+    fn spawn_recovery() {
+        let mut business_state = BusinessState::default();
+        business_state.add_planned_bucket(20);
+        business_state.add_planned_bucket(20);
+
+        let can_id1 = Principal::from_slice(&[1]);
+        let can_id2 = Principal::from_slice(&[2]);
+
+        let first = business_state.tasks.lease_next(0, |_| true).unwrap();
+        assert_eq!(first.kind, IndexTask::SpawnBucket(0));
+        assert_eq!(business_state.spawn_step(0), Some(SpawnStep::Create));
+        assert!(business_state.record_created_canister(0, true, can_id1, 100, 10));
+
+        // The install failed, the next attempt resumes on the same canister
+        business_state.record_install_failure(0);
+        business_state
+            .tasks
+            .fail(&first, "Install failed".to_string(), 10);
+        assert_eq!(
+            business_state.spawn_step(0),
+            Some(SpawnStep::Install(can_id1))
+        );
+
+        let second = business_state.tasks.lease_next(20, |_| true).unwrap();
+        assert_eq!(second.kind, IndexTask::SpawnBucket(1));
+        assert!(business_state.tasks.lease_next(20, |_| true).is_none());
+
+        // Both leases run out, the second attempt comes back after that
+        let retry = business_state
+            .tasks
+            .lease_next(20 + SPAWN_LEASE, |_| true)
+            .unwrap();
+        assert_eq!(retry.kind, IndexTask::SpawnBucket(0));
+        assert!(!business_state.tasks.holds_lease(&second));
+        assert!(!business_state.record_created_canister(1, false, can_id2, 100, 30 + SPAWN_LEASE));
+        assert_eq!(
+            business_state.planned_buckets[1].spawn_status,
+            SpawnStatus::Created(can_id2)
+        );
+        assert_eq!(
+            business_state.spawn_step(1),
+            Some(SpawnStep::Install(can_id2))
+        );
+
+        // Nothing left to adopt a late canister
+        let can_id3 = Principal::from_slice(&[3]);
+        assert!(!business_state.record_created_canister(1, false, can_id3, 100, 40 + SPAWN_LEASE));
+
+        let orphaned = business_state.get_orphaned_canisters();
+        assert_eq!(orphaned.len(), 3);
+        assert_eq!(orphaned[0].canister_id, can_id1);
+        assert_eq!(orphaned[0].install_attempts, 1);
+        assert!(orphaned[0].resumable);
+        assert_eq!(orphaned[2].canister_id, can_id3);
+        assert!(!orphaned[2].resumable);
+
+        // Installed buckets are done and their slots aren't planned anymore
+        business_state.set_planned_bucket_installed(0);
+        assert_eq!(business_state.spawn_step(0), None);
+        assert_eq!(business_state.get_planned_slots(), 20);
+    }
+
+    #[test]
+    fn failing_tasks_end_up_dead() {
+        let mut business_state = BusinessState::default();
+        business_state.add_planned_bucket(20);
+
+        let mut now = 0;
+        for attempt in 1..=MAX_TASK_ATTEMPTS {
+            let leased = business_state.tasks.lease_next(now, |_| true).unwrap();
+            assert_eq!(leased.attempt, attempt);
+            business_state
+                .tasks
+                .fail(&leased, "Create failed".to_string(), now);
+            now += MAX_TASK_BACKOFF;
+        }
+
+        assert!(business_state.tasks.tasks().is_empty());
+        let dead = business_state.tasks.dead_tasks();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].task.kind, IndexTask::SpawnBucket(0));
+        assert_eq!(dead[0].task.last_error, Some("Create failed".to_string()));
+
+        // The planned bucket is still there for when an admin retries it
+        assert_eq!(business_state.get_planned_slots(), 20);
+        let id = dead[0].task.id;
+        assert!(business_state.tasks.retry_dead(id));
+        assert!(business_state.tasks.lease_next(now, |_| true).is_some());
+    }
+
+    #[test]
+    fn spawned_bucket_registry() {
+        let mut business_state = BusinessState::default();
+        business_state.add_planned_bucket(10);
+
+        let can_id1 = Principal::from_slice(&[1]);
+
+        business_state.record_created_canister(0, true, can_id1, 100, 5);
+
+        let bucket = business_state.get_spawned_bucket(&can_id1).unwrap();
+        assert_eq!(bucket.status, BucketStatus::Installing);
+        assert_eq!(bucket.created_at, 5);
+        assert_eq!(bucket.cycles_sent, 100);
+        assert_eq!(bucket.capacity, 10);
+
+        // Installing buckets aren't listed yet
+        assert!(business_state.get_all_buckets().is_empty());
+
+        business_state.set_bucket_installed(can_id1, Some("v1".to_string()));
+        assert_eq!(business_state.get_all_buckets(), vec![can_id1]);
+
+        let mut bucket_index = EffectiveIndex {
+            version: 1,
+            tags: vec!["#rabbit".to_string()],
+            current_entries: 10,
+            bucket_max_entries: 10,
+            bytes_used: 0,
+            bytes_capacity: u64::MAX,
+        };
+        business_state.add_bucket_index(can_id1, bucket_index.clone());
+        assert_eq!(
+            business_state.get_spawned_bucket(&can_id1).unwrap().status,
+            BucketStatus::Full
+        );
+
+        bucket_index.version = 2;
+        bucket_index.current_entries = 4;
+        business_state.add_bucket_index(can_id1, bucket_index.clone());
+        assert_eq!(business_state.where_to_upload(None), vec![can_id1]);
+        assert_eq!(business_state.get_free_slots(), 6);
+
+        // Draining buckets get no uploads and their free slots don't count
+        assert!(business_state
+            .set_bucket_status(can_id1, BucketStatus::Full)
+            .is_err());
+        business_state
+            .set_bucket_status(can_id1, BucketStatus::Draining)
+            .unwrap();
+        assert!(business_state.where_to_upload(None).is_empty());
+        assert_eq!(business_state.get_free_slots(), 0);
+
+        // The index pushes don't override an admin's status
+        bucket_index.version = 3;
+        business_state.add_bucket_index(can_id1, bucket_index);
+        assert_eq!(
+            business_state.get_spawned_bucket(&can_id1).unwrap().status,
+            BucketStatus::Draining
+        );
+        business_state
+            .set_bucket_status(can_id1, BucketStatus::Retired)
+            .unwrap();
+        assert!(business_state.get_all_buckets().is_empty());
+        assert_eq!(business_state.get_spawned_buckets().len(), 1);
+    }
+
+    #[test]
+    fn stale_index_pushes() {
+        let mut business_state = BusinessState::default();
+        let can_id1 = Principal::from_slice(&[1]);
+
+        let index = |version: u64, current_entries: u64| EffectiveIndex {
+            version,
+            tags: vec!["#rabbit".to_string()],
+            current_entries,
+            bucket_max_entries: 20,
+            bytes_used: 0,
+            bytes_capacity: u64::MAX,
+        };
+
+        assert_eq!(business_state.get_bucket_index_version(&can_id1), None);
+        assert_eq!(business_state.add_bucket_index(can_id1, index(2, 5)), 2);
+
+        // An older push landing late doesn't overwrite the newer one
+        assert_eq!(business_state.add_bucket_index(can_id1, index(1, 3)), 2);
+        assert_eq!(business_state.add_bucket_index(can_id1, index(2, 3)), 2);
+        assert_eq!(business_state.get_free_slots(), 15);
+
+        assert_eq!(business_state.add_bucket_index(can_id1, index(3, 7)), 3);
+        assert_eq!(business_state.get_bucket_index_version(&can_id1), Some(3));
+        assert_eq!(business_state.get_free_slots(), 13);
+    }
+
+    #[test]
+    fn index_updates() {
+        let mut business_state = BusinessState::default();
+        let can_id1 = Principal::from_slice(&[1]);
+
+        let tags = |tags: &[&str]| -> Vec<String> { tags.iter().map(|t| t.to_string()).collect() };
+        let update = |version: u64, base_version: u64, full: bool| IndexUpdate {
+            version,
+            base_version,
+            full,
+            current_entries: 5,
+            bucket_max_entries: 20,
+            bytes_used: 0,
+            bytes_capacity: u64::MAX,
+            last_part: true,
+            ..Default::default()
+        };
+
+        // A delta without a base needs a full resync
+        assert_eq!(
+            business_state.apply_index_update(can_id1, update(1, 0, false)),
+            IndexSyncResult::ResyncNeeded
+        );
+
+        // Full update split in two parts
+        let mut part0 = update(1, 0, true);
+        part0.added_tags = tags(&["#rabbit", "#fox"]);
+        part0.last_part = false;
+        let mut part1 = update(1, 0, true);
+        part1.added_tags = tags(&["#dog"]);
+        part1.part = 1;
+
+        assert_eq!(
+            business_state.apply_index_update(can_id1, part0),
+            IndexSyncResult::PartAccepted
+        );
+        assert_eq!(business_state.get_bucket_index_version(&can_id1), None);
+        assert_eq!(
+            business_state.apply_index_update(can_id1, part1.clone()),
+            IndexSyncResult::Applied(1)
+        );
+
+        // Retried parts of an applied version are acknowledged again
+        assert_eq!(
+            business_state.apply_index_update(can_id1, part1),
+            IndexSyncResult::Applied(1)
+        );
+
+        let mut delta = update(2, 1, false);
+        delta.added_tags = tags(&["#cat"]);
+        delta.removed_tags = tags(&["#fox"]);
+        assert_eq!(
+            business_state.apply_index_update(can_id1, delta),
+            IndexSyncResult::Applied(2)
+        );
+        business_state.global_index.tag_to_canisters =
+            business_state.generate_index_tag_to_canisters();
+        assert!(business_state.get_index_by_tag("#fox").is_empty());
+        assert_eq!(business_state.get_index_by_tag("#cat"), vec![can_id1]);
+        assert_eq!(business_state.get_index_by_tag("#dog"), vec![can_id1]);
+
+        // A delta against a version we don't have, or a missing part
+        assert_eq!(
+            business_state.apply_index_update(can_id1, update(4, 3, false)),
+            IndexSyncResult::ResyncNeeded
+        );
+        let mut part1 = update(3, 2, false);
+        part1.part = 1;
+        assert_eq!(
+            business_state.apply_index_update(can_id1, part1),
+            IndexSyncResult::ResyncNeeded
+        );
+    }
+
+    #[test]
+    fn pull_rounds() {
+        let mut business_state = BusinessState::default();
+        let interval = 10;
+
+        assert!(business_state.start_pull_round(100, interval, 2).is_empty());
+
+        for i in 1..=3 {
+            business_state.add_spawned_bucket(SpawnedBucketCanister {
+                canister_id: Principal::from_slice(&[i]),
+                created_at: 0,
+                cycles_sent: 0,
+                wasm_version: None,
+                capacity: 20,
+                status: BucketStatus::Active,
+                roles_version: 0,
+            });
+        }
+        business_state
+            .set_bucket_status(Principal::from_slice(&[2]), BucketStatus::Retired)
+            .unwrap();
+        business_state.add_bucket_index(
+            Principal::from_slice(&[3]),
+            EffectiveIndex {
+                version: 7,
+                ..Default::default()
+            },
+        );
+
+        // Retired buckets are skipped, known versions are sent along
+        assert_eq!(
+            business_state.start_pull_round(100, interval, 1),
+            vec![(Principal::from_slice(&[1]), 0)]
+        );
+
+        // One round at a time
+        assert!(business_state.start_pull_round(200, interval, 1).is_empty());
+        business_state.finish_pull_round();
+
+        // The next round starts where the last one stopped
+        assert_eq!(
+            business_state.start_pull_round(200, interval, 5),
+            vec![
+                (Principal::from_slice(&[3]), 7),
+                (Principal::from_slice(&[1]), 0)
+            ]
+        );
+        business_state.finish_pull_round();
+
+        // Not before the interval has passed
+        assert!(business_state.start_pull_round(205, interval, 5).is_empty());
+
+        // A round that never finished is given up after its lease
+        assert_eq!(business_state.start_pull_round(210, interval, 1).len(), 1);
+        assert!(business_state.start_pull_round(220, interval, 1).is_empty());
+        assert_eq!(
+            business_state
+                .start_pull_round(210 + PULL_ROUND_LEASE, interval, 1)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn reject_unknown_index_pushes() {
+        let mut business_state = BusinessState::default();
+        business_state.add_planned_bucket(10);
+
+        let can_id1 = Principal::from_slice(&[1]);
+        let stranger = Principal::from_slice(&[2]);
+
+        assert!(!business_state.accepts_index_from(&can_id1));
+
+        business_state.record_created_canister(0, true, can_id1, 100, 5);
+        assert!(business_state.accepts_index_from(&can_id1));
+        assert!(!business_state.accepts_index_from(&stranger));
+
+        business_state
+            .set_bucket_status(can_id1, BucketStatus::Retired)
+            .unwrap();
+        assert!(!business_state.accepts_index_from(&can_id1));
+
+        for i in 0..(MAX_REJECTED_INDEX_PUSH_LOG as u64 + 5) {
+            business_state.record_rejected_index_push(stranger, i);
+        }
+        assert_eq!(
+            business_state.get_rejected_index_pushes(),
+            MAX_REJECTED_INDEX_PUSH_LOG as u64 + 5
+        );
+
+        let log = business_state.get_rejected_index_push_log();
+        assert_eq!(log.len(), MAX_REJECTED_INDEX_PUSH_LOG);
+        assert_eq!(log[0].rejected_at, 5);
+    }
+
+    #[test]
+    fn resolve_entry() {
+        let mut business_state = BusinessState::default();
+
+        let can_id1 = Principal::from_slice(&[1]);
+        business_state.add_spawned_bucket(SpawnedBucketCanister {
+            canister_id: can_id1,
+            created_at: 0,
+            cycles_sent: 0,
+            wasm_version: None,
+            capacity: 20,
+            status: BucketStatus::Installing,
+            roles_version: 0,
+        });
+
+        let known = GlobalEntryId {
+            bucket: can_id1,
+            id: 7,
+        };
+        let unknown = GlobalEntryId {
+            bucket: Principal::from_slice(&[2]),
+            id: 7,
+        };
+
+        // Buckets are known from the registry, before their first index push
+        assert_eq!(business_state.resolve_entry(&known), None);
+        business_state.set_bucket_installed(can_id1, Some("v1".to_string()));
+        assert_eq!(business_state.resolve_entry(&known), Some(can_id1));
+        assert_eq!(business_state.resolve_entry(&unknown), None);
+
+        // Buckets that pushed an index without being spawned aren't
+        business_state.add_bucket_index(unknown.bucket, EffectiveIndex::default());
+        assert_eq!(business_state.resolve_entry(&unknown), None);
+    }
 }
@@ -1,11 +1,24 @@
 mod businesslogic;
 mod env;
 mod lifetime;
+mod settings;
+mod upgrade;
+mod wasm_store;
 
-use crate::businesslogic::{BusinessState, EffectiveIndex};
+use crate::businesslogic::{
+    BucketStatus, BusinessState, GlobalEntryId, IndexSyncResult, IndexTask, IndexUpdate,
+    IndexingStrategy, OrphanedCanister, PostResult, QueuedWriteStatus, SpawnedBucketCanister,
+    ESTIMATED_ENTRY_BYTES,
+};
 use crate::env::{CanisterEnv, EmptyEnv, Environment, TimestampMillis};
+use crate::settings::{CapacityMode, IndexSettings, IndexSyncMode, SettingsChange, SettingsUpdate};
+use crate::upgrade::{UpgradeArgs, UpgradeRollout};
+use crate::wasm_store::{WasmStore, WasmVersion};
 use ic_cdk::export::candid::{CandidType, Principal};
 use ic_cdk_macros::*;
+use quickstart_scaling_acl::{Role, RoleAssignment};
+use quickstart_scaling_reservation::SlotReservation;
+use quickstart_scaling_tasks::{DeadTask, Task};
 use serde::Deserialize;
 
 use std::cell::{Ref, RefCell, RefMut};
@@ -38,6 +51,14 @@ impl Default for RuntimeState {
 struct IndexCanisterSettings {
     reindex_interval: TimestampMillis,
     desired_free_slots: u128,
+    // Added as controllers to every new bucket, next to the Index canister itself
+    bucket_controllers: Vec<Principal>,
+    cycles_per_bucket: u64,
+    bucket_max_entries: u64,
+    bucket_capacity_mode: CapacityMode,
+    index_sync_mode: IndexSyncMode,
+    // How many buckets are polled per round in Pull mode
+    pull_batch_size: u32,
 }
 
 impl Default for IndexCanisterSettings {
@@ -46,6 +67,54 @@ impl Default for IndexCanisterSettings {
             // 5 seconds
             reindex_interval: 5_000_000_000,
             desired_free_slots: 60,
+            bucket_controllers: vec![],
+            cycles_per_bucket: 100_000_000_000,
+            bucket_max_entries: 20,
+            bucket_capacity_mode: CapacityMode::Entries,
+            index_sync_mode: IndexSyncMode::Push,
+            pull_batch_size: 10,
+        }
+    }
+}
+
+impl IndexCanisterSettings {
+    // The slots a new bucket brings, in Bytes mode assuming entries of
+    // ESTIMATED_ENTRY_BYTES
+    fn bucket_slots(&self) -> u64 {
+        match self.bucket_capacity_mode {
+            CapacityMode::Entries => self.bucket_max_entries,
+            CapacityMode::Bytes(max_bytes) => (max_bytes / ESTIMATED_ENTRY_BYTES).max(1),
+        }
+    }
+}
+
+// Arguments accepted by init and post_upgrade. Settings that are left out keep
+// their current (or default) value, admins are added to the existing ones.
+#[derive(CandidType, Deserialize, Debug, Default)]
+struct IndexInitArgs {
+    admins: Vec<Principal>,
+    bucket_controllers: Vec<Principal>,
+    desired_free_slots: Option<u128>,
+    reindex_interval: Option<TimestampMillis>,
+    cycles_per_bucket: Option<u64>,
+    bucket_max_entries: Option<u64>,
+    bucket_capacity_mode: Option<CapacityMode>,
+    indexing_strategy: Option<IndexingStrategy>,
+    index_sync_mode: Option<IndexSyncMode>,
+    pull_batch_size: Option<u32>,
+}
+
+impl IndexInitArgs {
+    fn settings_update(&self) -> SettingsUpdate {
+        SettingsUpdate {
+            desired_free_slots: self.desired_free_slots,
+            reindex_interval: self.reindex_interval,
+            indexing_strategy: self.indexing_strategy,
+            cycles_per_bucket: self.cycles_per_bucket,
+            bucket_max_entries: self.bucket_max_entries,
+            bucket_capacity_mode: self.bucket_capacity_mode,
+            index_sync_mode: self.index_sync_mode,
+            pull_batch_size: self.pull_batch_size,
         }
     }
 }
@@ -54,26 +123,48 @@ impl Default for IndexCanisterSettings {
 struct Data {
     canister_settings: IndexCanisterSettings,
     business_state: BusinessState,
+    settings_history: Vec<SettingsChange>,
+    bucket_wasms: WasmStore,
+    // The last bucket upgrade rollout, running or not
+    bucket_upgrade: Option<UpgradeRollout>,
 }
 
 // MAIN FUNCTIONALITY
 // Inter canister calls are named with snake case
-#[update(name = "add_bucket_index")]
-fn add_bucket_index(bucket_index: EffectiveIndex) -> bool {
-    RUNTIME_STATE.with(|state| add_bucket_index_impl(bucket_index, state.borrow_mut()))
+// Buckets send the tags added and removed since the last version we applied,
+// or their full index when we don't have that version. Once the update is
+// applied the bucket gets back the version we are at.
+#[update(name = "sync_bucket_index")]
+fn sync_bucket_index(update: IndexUpdate) -> IndexSyncResult {
+    RUNTIME_STATE.with(|state| sync_bucket_index_impl(update, state.borrow_mut()))
 }
 
-fn add_bucket_index_impl(
-    bucket_index: EffectiveIndex,
+// Only buckets spawned by this Index can push their index, anyone else could
+// inject fake tags or put itself first in the upload order
+fn sync_bucket_index_impl(
+    update: IndexUpdate,
     mut runtime_state: RefMut<RuntimeState>,
-) -> bool {
+) -> IndexSyncResult {
     let caller = runtime_state.env.caller();
-    runtime_state
+
+    if !runtime_state
         .data
         .business_state
-        .add_bucket_index(caller, bucket_index);
+        .accepts_index_from(&caller)
+    {
+        ic_cdk::print(format!("Rejected bucket index push from {}", caller));
+        let now = runtime_state.env.now();
+        runtime_state
+            .data
+            .business_state
+            .record_rejected_index_push(caller, now);
+        return IndexSyncResult::Rejected;
+    }
 
-    true
+    runtime_state
+        .data
+        .business_state
+        .apply_index_update(caller, update)
 }
 
 // Client facing calls are camelCase
@@ -90,7 +181,17 @@ Cycles: {}\n
 Free Slots: {}\n
 Desired Free Slots: {}\n
 Planned Slots: {}\n
+Reserved Slots: {}\n
+Pending Writes: {}\n
+Queued Tasks: {}\n
+Dead Tasks: {}\n
+Roles Version: {}\n
+Buckets Behind On Roles: {:?}\n
+Index Sync Mode: {:?}\n
+Active Bucket Wasm: {}\n
 All Buckets: {:?}\n
+Rejected Index Pushes: {}\n
+Last Rejected Callers: {:?}\n
 Memory: {}\n
 Caller: {}\n",
         runtime_state.env.canister_id(),
@@ -98,13 +199,42 @@ Caller: {}\n",
         runtime_state.data.business_state.get_free_slots(),
         runtime_state.data.canister_settings.desired_free_slots,
         runtime_state.data.business_state.get_planned_slots(),
+        runtime_state.data.business_state.get_reserved_slots(),
+        runtime_state.data.business_state.get_pending_write_count(),
+        runtime_state.data.business_state.tasks.tasks().len(),
+        runtime_state.data.business_state.tasks.dead_tasks().len(),
+        runtime_state.data.business_state.get_roles_version(),
         runtime_state
             .data
             .business_state
-            .get_all_buckets()
+            .get_buckets_behind_on_roles()
             .iter()
             .map(|b| b.to_text())
             .collect::<Vec<String>>(),
+        runtime_state.data.canister_settings.index_sync_mode,
+        runtime_state
+            .data
+            .bucket_wasms
+            .active_version()
+            .map_or("none".to_string(), |v| v.name),
+        runtime_state
+            .data
+            .business_state
+            .get_spawned_buckets()
+            .iter()
+            .map(|b| format!("{} ({:?})", b.canister_id.to_text(), b.status))
+            .collect::<Vec<String>>(),
+        runtime_state
+            .data
+            .business_state
+            .get_rejected_index_pushes(),
+        runtime_state
+            .data
+            .business_state
+            .get_rejected_index_push_log()
+            .iter()
+            .map(|r| r.caller.to_text())
+            .collect::<Vec<String>>(),
         runtime_state.env.memory_used(),
         runtime_state.env.caller().to_text(),
     )
@@ -131,8 +261,35 @@ fn get_index_by_tag_impl(tag: String, runtime_state: Ref<RuntimeState>) -> Vec<P
     runtime_state.data.business_state.get_index_by_tag(&tag)
 }
 
+// Tells a client which bucket holds an entry returned by a bucket's postContent.
+// Returns None if the bucket in the id is not known to this index.
+#[query(name = "resolveEntry")]
+fn resolve_entry(entry_id: GlobalEntryId) -> Option<Principal> {
+    RUNTIME_STATE.with(|state| resolve_entry_impl(entry_id, state.borrow()))
+}
+
+fn resolve_entry_impl(
+    entry_id: GlobalEntryId,
+    runtime_state: Ref<RuntimeState>,
+) -> Option<Principal> {
+    runtime_state.data.business_state.resolve_entry(&entry_id)
+}
+
+// The last index version the Index accepted from a bucket
+#[query(name = "getBucketIndexVersion")]
+fn get_bucket_index_version(bucket: Principal) -> Option<u64> {
+    RUNTIME_STATE.with(|state| {
+        state
+            .borrow()
+            .data
+            .business_state
+            .get_bucket_index_version(&bucket)
+    })
+}
+
 // Useful for demo purposes; could also be used by a client to "randomly" upload data
 // to any canister, if this is something that works for their case.
+// Lists the spawned buckets that are running and not retired.
 #[query(name = "getAllIndexes")]
 fn get_all_indexes() -> Vec<Principal> {
     RUNTIME_STATE.with(|state| get_all_indexes_impl(state.borrow()))
@@ -142,29 +299,384 @@ fn get_all_indexes_impl(runtime_state: Ref<RuntimeState>) -> Vec<Principal> {
     runtime_state.data.business_state.get_all_buckets()
 }
 
+// The buckets to try, best first. The tag only matters with the TagAffinity
+// strategy, callers that don't know it yet can leave it out.
 #[query(name = "getUploadOrder")]
-fn get_upload_order() -> Vec<Principal> {
-    RUNTIME_STATE.with(|state| get_upload_order_impl(state.borrow()))
+fn get_upload_order(tag: Option<String>) -> Vec<Principal> {
+    RUNTIME_STATE.with(|state| get_upload_order_impl(tag, state.borrow()))
+}
+
+fn get_upload_order_impl(tag: Option<String>, runtime_state: Ref<RuntimeState>) -> Vec<Principal> {
+    runtime_state
+        .data
+        .business_state
+        .where_to_upload(tag.as_deref())
+}
+
+// Stores the entry in a bucket picked by the indexing strategy, under the
+// caller's principal, and returns where it was stored. Saves clients from
+// calling getUploadOrder and retrying buckets themselves. When every bucket
+// is full the post is queued and written once a new bucket is installed,
+// see getQueuedWrite.
+#[update(name = "postContent")]
+async fn post_content(tag: String, body: String) -> PostResult {
+    let caller = RUNTIME_STATE.with(|state| state.borrow().env.caller());
+
+    businesslogic::post_content(caller, tag, body).await
 }
 
-fn get_upload_order_impl(runtime_state: Ref<RuntimeState>) -> Vec<Principal> {
-    runtime_state.data.business_state.where_to_upload()
+// Only the author of the post can see its status
+#[query(name = "getQueuedWrite")]
+fn get_queued_write(id: u64) -> Option<QueuedWriteStatus> {
+    RUNTIME_STATE.with(|state| get_queued_write_impl(id, state.borrow()))
+}
+
+fn get_queued_write_impl(id: u64, runtime_state: Ref<RuntimeState>) -> Option<QueuedWriteStatus> {
+    let caller = runtime_state.env.caller();
+
+    runtime_state
+        .data
+        .business_state
+        .get_write_status(id, &caller)
+}
+
+// Sets aside a slot in the first bucket of the upload order for the caller.
+// Pass the reservation to the bucket's postContent, which accepts it even if
+// the bucket filled up in the meantime. Reservations expire after
+// RESERVATION_TTL; None if no bucket has a free slot right now.
+#[update(name = "reserveUpload")]
+fn reserve_upload(tag: Option<String>) -> Option<SlotReservation> {
+    RUNTIME_STATE.with(|state| reserve_upload_impl(tag, state.borrow_mut()))
+}
+
+fn reserve_upload_impl(
+    tag: Option<String>,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Option<SlotReservation> {
+    let caller = runtime_state.env.caller();
+    let now = runtime_state.env.now();
+
+    runtime_state
+        .data
+        .business_state
+        .reserve_slot(caller, tag.as_deref(), now)
 }
 
-#[update(name = "addContentModerator")]
+#[update(name = "addContentModerator", guard = "is_admin")]
 fn add_content_moderator(moderator: Principal) {
     RUNTIME_STATE.with(|state| add_content_moderator_impl(moderator, state.borrow_mut()))
 }
 
+// Grants a global Moderator role. Every time the roles change we push the
+// full list to all the buckets. This can obviously be optimized based on the
+// app's needs.
 fn add_content_moderator_impl(moderator: Principal, mut runtime_state: RefMut<RuntimeState>) {
     runtime_state
         .data
         .business_state
         .add_content_moderator(moderator);
+}
+
+// Removing a moderator is propagated to the buckets the same way as adding one
+#[update(name = "removeContentModerator", guard = "is_admin")]
+fn remove_content_moderator(moderator: Principal) -> bool {
+    RUNTIME_STATE.with(|state| remove_content_moderator_impl(moderator, state.borrow_mut()))
+}
+
+fn remove_content_moderator_impl(
+    moderator: Principal,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> bool {
+    runtime_state
+        .data
+        .business_state
+        .remove_content_moderator(moderator)
+}
+
+#[query(name = "listContentModerators", guard = "is_admin")]
+fn list_content_moderators() -> Vec<Principal> {
+    RUNTIME_STATE.with(|state| state.borrow().data.business_state.get_content_moderators())
+}
+
+#[update(name = "grantRole", guard = "is_admin")]
+fn grant_role(assignment: RoleAssignment) -> Result<(), String> {
+    RUNTIME_STATE.with(|state| grant_role_impl(assignment, state.borrow_mut()))
+}
+
+fn grant_role_impl(
+    assignment: RoleAssignment,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Result<(), String> {
+    let caller = runtime_state.env.caller();
+    if !runtime_state
+        .data
+        .business_state
+        .can_manage_role(&caller, assignment.role)
+    {
+        return Err("Only owners can manage admins and owners".to_string());
+    }
+
+    runtime_state.data.business_state.grant_role(assignment);
+    Ok(())
+}
 
-    // Every time we get a new moderator we push the vec to all the buckets
-    // This can obviously be optimized based on the app's needs
-    runtime_state.data.business_state.push_moderators = true;
+#[update(name = "revokeRole", guard = "is_admin")]
+fn revoke_role(assignment: RoleAssignment) -> Result<(), String> {
+    RUNTIME_STATE.with(|state| revoke_role_impl(assignment, state.borrow_mut()))
+}
+
+fn revoke_role_impl(
+    assignment: RoleAssignment,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Result<(), String> {
+    let caller = runtime_state.env.caller();
+    if !runtime_state
+        .data
+        .business_state
+        .can_manage_role(&caller, assignment.role)
+    {
+        return Err("Only owners can manage admins and owners".to_string());
+    }
+
+    runtime_state.data.business_state.revoke_role(&assignment);
+    Ok(())
+}
+
+#[query(name = "listRoles", guard = "is_admin")]
+fn list_roles() -> Vec<RoleAssignment> {
+    RUNTIME_STATE.with(|state| state.borrow().data.business_state.get_roles())
+}
+
+// ADMINISTRATION
+#[query(name = "getSettings", guard = "is_admin")]
+fn get_settings() -> IndexSettings {
+    RUNTIME_STATE.with(|state| settings::get_settings(&state.borrow().data))
+}
+
+// Validates the whole update before applying any of it. Returns the new settings.
+#[update(name = "updateSettings", guard = "is_admin")]
+fn update_settings(update: SettingsUpdate) -> Result<IndexSettings, String> {
+    RUNTIME_STATE.with(|state| update_settings_impl(update, state.borrow_mut()))
+}
+
+fn update_settings_impl(
+    update: SettingsUpdate,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Result<IndexSettings, String> {
+    let caller = runtime_state.env.caller();
+    let now = runtime_state.env.now();
+
+    settings::update_settings(update, caller, now, &mut runtime_state.data)
+}
+
+#[query(name = "getSettingsHistory", guard = "is_admin")]
+fn get_settings_history() -> Vec<SettingsChange> {
+    RUNTIME_STATE.with(|state| state.borrow().data.settings_history.clone())
+}
+
+// The bucket wasm is uploaded after the Index is installed, in chunks small
+// enough to fit in an update call. The upload is started with the SHA-256 of
+// the whole module, which is checked when the upload is committed.
+#[update(name = "startBucketWasmUpload", guard = "is_admin")]
+fn start_bucket_wasm_upload(name: String, sha256: Vec<u8>) -> Result<(), String> {
+    RUNTIME_STATE.with(|state| {
+        state
+            .borrow_mut()
+            .data
+            .bucket_wasms
+            .start_upload(name, sha256)
+    })
+}
+
+// Returns the number of bytes received so far
+#[update(name = "uploadBucketWasmChunk", guard = "is_admin")]
+fn upload_bucket_wasm_chunk(name: String, chunk: Vec<u8>) -> Result<u64, String> {
+    RUNTIME_STATE.with(|state| {
+        state
+            .borrow_mut()
+            .data
+            .bucket_wasms
+            .append_chunk(&name, chunk)
+    })
+}
+
+#[update(name = "commitBucketWasmUpload", guard = "is_admin")]
+fn commit_bucket_wasm_upload(name: String) -> Result<WasmVersion, String> {
+    RUNTIME_STATE.with(|state| commit_bucket_wasm_upload_impl(name, state.borrow_mut()))
+}
+
+fn commit_bucket_wasm_upload_impl(
+    name: String,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Result<WasmVersion, String> {
+    let caller = runtime_state.env.caller();
+    let now = runtime_state.env.now();
+
+    runtime_state
+        .data
+        .bucket_wasms
+        .commit_upload(&name, caller, now)
+}
+
+// New buckets are installed with the active version. Buckets can't be spawned
+// until a version is active.
+#[update(name = "setActiveBucketWasm", guard = "is_admin")]
+fn set_active_bucket_wasm(name: String) -> Result<(), String> {
+    RUNTIME_STATE.with(|state| state.borrow_mut().data.bucket_wasms.set_active(&name))
+}
+
+#[update(name = "removeBucketWasm", guard = "is_admin")]
+fn remove_bucket_wasm(name: String) -> Result<(), String> {
+    RUNTIME_STATE.with(|state| remove_bucket_wasm_impl(name, state.borrow_mut()))
+}
+
+fn remove_bucket_wasm_impl(
+    name: String,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Result<(), String> {
+    if let Some(rollout) = &runtime_state.data.bucket_upgrade {
+        if rollout.is_running() && rollout.wasm_version == name {
+            return Err("The version is being rolled out".to_string());
+        }
+    }
+
+    runtime_state.data.bucket_wasms.remove_version(&name)
+}
+
+#[query(name = "listBucketWasms", guard = "is_admin")]
+fn list_bucket_wasms() -> Vec<WasmVersion> {
+    RUNTIME_STATE.with(|state| state.borrow().data.bucket_wasms.versions())
+}
+
+#[query(name = "getActiveBucketWasm", guard = "is_admin")]
+fn get_active_bucket_wasm() -> Option<WasmVersion> {
+    RUNTIME_STATE.with(|state| state.borrow().data.bucket_wasms.active_version())
+}
+
+// Upgrades every known bucket to an uploaded wasm version, in batches run from
+// the heartbeat. The first bucket is a canary: if it fails its health check
+// after the upgrade the rollout stops before touching any other bucket.
+#[update(name = "startBucketUpgrade", guard = "is_admin")]
+fn start_bucket_upgrade(args: UpgradeArgs) -> Result<(), String> {
+    RUNTIME_STATE.with(|state| start_bucket_upgrade_impl(args, state.borrow_mut()))
+}
+
+fn start_bucket_upgrade_impl(
+    args: UpgradeArgs,
+    mut runtime_state: RefMut<RuntimeState>,
+) -> Result<(), String> {
+    let caller = runtime_state.env.caller();
+    let now = runtime_state.env.now();
+    let data = &mut runtime_state.data;
+
+    if let Some(rollout) = &data.bucket_upgrade {
+        if rollout.is_running() {
+            return Err("A rollout is already running".to_string());
+        }
+    }
+    if data.bucket_wasms.module(&args.wasm_version).is_none() {
+        return Err(format!("Unknown version {}", args.wasm_version));
+    }
+
+    let buckets = data.business_state.get_all_buckets();
+    data.bucket_upgrade = Some(UpgradeRollout::new(args, buckets, caller, now)?);
+
+    Ok(())
+}
+
+// A bucket that is being upgraded when the rollout is cancelled still finishes
+// its upgrade, the rest of its batch is left alone
+#[update(name = "cancelBucketUpgrade", guard = "is_admin")]
+fn cancel_bucket_upgrade() -> Result<(), String> {
+    RUNTIME_STATE.with(|state| {
+        let runtime_state = &mut *state.borrow_mut();
+        let now = runtime_state.env.now();
+
+        match runtime_state.data.bucket_upgrade.as_mut() {
+            Some(rollout) => rollout.cancel(now),
+            None => Err("There is no rollout to cancel".to_string()),
+        }
+    })
+}
+
+#[query(name = "getBucketUpgradeStatus", guard = "is_admin")]
+fn get_bucket_upgrade_status() -> Option<UpgradeRollout> {
+    RUNTIME_STATE.with(|state| state.borrow().data.bucket_upgrade.clone())
+}
+
+// Every bucket the Index spawned, whatever its status
+#[query(name = "getSpawnedBuckets", guard = "is_admin")]
+fn get_spawned_buckets() -> Vec<SpawnedBucketCanister> {
+    RUNTIME_STATE.with(|state| state.borrow().data.business_state.get_spawned_buckets())
+}
+
+// Draining buckets get no new uploads, retired ones are taken out of service.
+// Setting a bucket back to Active undoes either.
+#[update(name = "setBucketStatus", guard = "is_admin")]
+fn set_bucket_status(canister_id: Principal, status: BucketStatus) -> Result<(), String> {
+    RUNTIME_STATE.with(|state| {
+        state
+            .borrow_mut()
+            .data
+            .business_state
+            .set_bucket_status(canister_id, status)
+    })
+}
+
+// Canisters the Index created that don't run a bucket yet. Resumable ones get
+// their install retried by the heartbeat, the rest need manual cleanup.
+#[query(name = "getOrphanedCanisters", guard = "is_admin")]
+fn get_orphaned_canisters() -> Vec<OrphanedCanister> {
+    RUNTIME_STATE.with(|state| state.borrow().data.business_state.get_orphaned_canisters())
+}
+
+// The bucket spawns and role pushes waiting to be run or being run
+#[query(name = "getTasks", guard = "is_admin")]
+fn get_tasks() -> Vec<Task<IndexTask>> {
+    RUNTIME_STATE.with(|state| state.borrow().data.business_state.tasks.tasks().to_vec())
+}
+
+// Tasks that failed MAX_TASK_ATTEMPTS times, with their last error
+#[query(name = "getDeadTasks", guard = "is_admin")]
+fn get_dead_tasks() -> Vec<DeadTask<IndexTask>> {
+    RUNTIME_STATE.with(|state| {
+        state
+            .borrow()
+            .data
+            .business_state
+            .tasks
+            .dead_tasks()
+            .to_vec()
+    })
+}
+
+// Puts a dead task back in the queue, e.g. once the cause of its failures is fixed
+#[update(name = "retryDeadTask", guard = "is_admin")]
+fn retry_dead_task(task_id: u64) -> bool {
+    RUNTIME_STATE.with(|state| {
+        state
+            .borrow_mut()
+            .data
+            .business_state
+            .tasks
+            .retry_dead(task_id)
+    })
+}
+
+// Guards:
+// Admin (or Owner) for the whole system
+fn is_admin() -> Result<(), String> {
+    RUNTIME_STATE.with(|state| {
+        if state.borrow().data.business_state.has_role(
+            &state.borrow().env.caller(),
+            Role::Admin,
+            None,
+        ) {
+            Ok(())
+        } else {
+            Err("You are not an admin".to_string())
+        }
+    })
 }
 
 // Make sure we can accept cycles from Bucket canisters
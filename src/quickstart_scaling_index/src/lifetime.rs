@@ -1,16 +1,37 @@
-use crate::{businesslogic, CanisterEnv, Data, RuntimeState, RUNTIME_STATE};
+use crate::{
+    businesslogic, settings, upgrade, CanisterEnv, Data, IndexInitArgs, RuntimeState, RUNTIME_STATE,
+};
 use ic_cdk::print;
 use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade};
+use quickstart_scaling_acl::{Role, RoleAssignment, RoleScope};
+use std::cell::RefMut;
 
 #[init]
 fn init() {
     let env = Box::new(CanisterEnv::new());
     let data = Data::default();
-    let runtime_state = RuntimeState { env, data };
+    let mut runtime_state = RuntimeState { env, data };
 
     ic_cdk::print(format!("{}", ic_cdk::api::caller()));
 
-    RUNTIME_STATE.with(|state| *state.borrow_mut() = runtime_state);
+    // Whoever installs the Index canister owns the app
+    runtime_state
+        .data
+        .business_state
+        .grant_role(RoleAssignment {
+            principal: ic_cdk::api::caller(),
+            role: Role::Owner,
+            scope: RoleScope::Global,
+        });
+
+    let call_arg = ic_cdk::api::call::arg_data::<(Option<IndexInitArgs>,)>().0;
+
+    ic_cdk::print(format!("{:?}", call_arg));
+
+    RUNTIME_STATE.with(|state| {
+        *state.borrow_mut() = runtime_state;
+        apply_init_args(call_arg.unwrap_or_default(), state.borrow_mut());
+    });
 }
 
 #[pre_upgrade]
@@ -24,11 +45,57 @@ fn post_upgrade() {
     let (data,): (Data,) = ic_cdk::storage::stable_restore().unwrap();
     let runtime_state = RuntimeState { env, data };
 
-    RUNTIME_STATE.with(|state| *state.borrow_mut() = runtime_state);
+    // Upgrades can optionally change the settings too
+    let call_arg = ic_cdk::api::call::arg_data::<(Option<IndexInitArgs>,)>().0;
+
+    RUNTIME_STATE.with(|state| {
+        *state.borrow_mut() = runtime_state;
+        if let Some(args) = call_arg {
+            apply_init_args(args, state.borrow_mut());
+        }
+
+        // Buckets spawned by an older Index don't have a reservation key yet
+        state
+            .borrow_mut()
+            .data
+            .business_state
+            .queue_reservation_key_pushes();
+    });
+}
+
+// Init args go through the same validation and history as updateSettings.
+// Invalid arguments make the install (or upgrade) fail.
+fn apply_init_args(args: IndexInitArgs, mut runtime_state: RefMut<RuntimeState>) {
+    let caller = runtime_state.env.caller();
+    let now = runtime_state.env.now();
+
+    if let Err(msg) =
+        settings::update_settings(args.settings_update(), caller, now, &mut runtime_state.data)
+    {
+        ic_cdk::trap(&format!("Invalid init args: {}", msg));
+    }
+
+    let bucket_controllers = &mut runtime_state.data.canister_settings.bucket_controllers;
+    for controller in args.bucket_controllers {
+        if !bucket_controllers.contains(&controller) {
+            bucket_controllers.push(controller);
+        }
+    }
+
+    for admin in args.admins {
+        runtime_state.data.business_state.add_admin(admin);
+    }
 }
 
 #[heartbeat]
 async fn heartbeat() {
+    // Expired upload reservations free their slots
+    RUNTIME_STATE.with(|state| {
+        let runtime_state = &mut *state.borrow_mut();
+        let now = runtime_state.env.now();
+        runtime_state.data.business_state.prune_reservations(now);
+    });
+
     // re-index global_index tag2can
     RUNTIME_STATE.with(|state| businesslogic::reindex_tag_to_canisters(state.borrow_mut()));
 
@@ -36,15 +103,26 @@ async fn heartbeat() {
     if let true = RUNTIME_STATE.with(|state| businesslogic::should_spawn_buckets(state.borrow())) {
         // add spawn task
         print("plan to spawn a new bucket");
-        RUNTIME_STATE.with(|state| state.borrow_mut().data.business_state.add_planned_bucket());
+        RUNTIME_STATE.with(|state| {
+            let bucket_slots = state.borrow().data.canister_settings.bucket_slots();
+            state
+                .borrow_mut()
+                .data
+                .business_state
+                .add_planned_bucket(bucket_slots)
+        });
     }
 
-    // spawn new buckets if needed. Note that the name "loop" here is a bit of a misnomer
-    // as we aren't looping in the function that we are calling, but we can think of this
-    // pattern as a loop that runs every heartbeat, and we get to visit it once per heartbeat
-    businesslogic::spawn_bucket_loop().await;
+    // spawn new buckets and push roles. Tasks that got stuck are picked up again
+    // once their lease expires, failed ones are retried with a backoff
+    businesslogic::run_tasks().await;
+
+    // Posts queued while every bucket was full go to the buckets with room
+    businesslogic::flush_pending_writes().await;
 
-    // TODO: add a module that checks for unfinished planned bucket installs and removes them
+    // Pull mode: fetch the index of a batch of buckets
+    businesslogic::pull_bucket_indexes().await;
 
-    businesslogic::push_moderators().await;
+    // one batch of a running bucket upgrade rollout
+    upgrade::upgrade_loop().await;
 }
@@ -0,0 +1,241 @@
+use crate::businesslogic::IndexingStrategy;
+use crate::{Data, Principal, TimestampMillis};
+use candid::CandidType;
+use serde::Deserialize;
+
+// Anything lower would have the Index and Buckets re-index on almost every heartbeat
+pub const MIN_REINDEX_INTERVAL: TimestampMillis = 1_000_000_000;
+// Creating a canister costs 100B cycles, anything below that can't spawn a bucket
+pub const MIN_CYCLES_PER_BUCKET: u64 = 100_000_000_000;
+
+// How bucket indexes reach the Index. In Push mode every bucket sends its index
+// from its heartbeat. In Pull mode the Index polls pull_batch_size buckets every
+// reindex_interval, and buckets don't need a heartbeat.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IndexSyncMode {
+    Push,
+    Pull,
+}
+
+// How buckets decide they're full. Entries caps the number of entries at
+// bucket_max_entries, Bytes caps the size of the entries whatever their number.
+// Either way buckets stop before they run out of memory.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CapacityMode {
+    Entries,
+    Bytes(u64),
+}
+
+// The Index settings that admins can change at runtime
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct IndexSettings {
+    pub(crate) desired_free_slots: u128,
+    pub(crate) reindex_interval: TimestampMillis,
+    pub(crate) indexing_strategy: IndexingStrategy,
+    pub(crate) cycles_per_bucket: u64,
+    pub(crate) bucket_max_entries: u64,
+    pub(crate) bucket_capacity_mode: CapacityMode,
+    pub(crate) index_sync_mode: IndexSyncMode,
+    pub(crate) pull_batch_size: u32,
+}
+
+// Fields left as None keep their current value
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct SettingsUpdate {
+    pub(crate) desired_free_slots: Option<u128>,
+    pub(crate) reindex_interval: Option<TimestampMillis>,
+    pub(crate) indexing_strategy: Option<IndexingStrategy>,
+    pub(crate) cycles_per_bucket: Option<u64>,
+    pub(crate) bucket_max_entries: Option<u64>,
+    pub(crate) bucket_capacity_mode: Option<CapacityMode>,
+    pub(crate) index_sync_mode: Option<IndexSyncMode>,
+    pub(crate) pull_batch_size: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SettingsChange {
+    pub(crate) changed_at: TimestampMillis,
+    pub(crate) changed_by: Principal,
+    pub(crate) before: IndexSettings,
+    pub(crate) after: IndexSettings,
+}
+
+impl SettingsUpdate {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.desired_free_slots == Some(0) {
+            return Err("desired_free_slots must be greater than 0".to_string());
+        }
+        if let Some(reindex_interval) = self.reindex_interval {
+            if reindex_interval < MIN_REINDEX_INTERVAL {
+                return Err(format!(
+                    "reindex_interval must be at least {}",
+                    MIN_REINDEX_INTERVAL
+                ));
+            }
+        }
+        if let Some(cycles_per_bucket) = self.cycles_per_bucket {
+            if cycles_per_bucket < MIN_CYCLES_PER_BUCKET {
+                return Err(format!(
+                    "cycles_per_bucket must be at least {}",
+                    MIN_CYCLES_PER_BUCKET
+                ));
+            }
+        }
+        if self.bucket_max_entries == Some(0) {
+            return Err("bucket_max_entries must be greater than 0".to_string());
+        }
+        if self.bucket_capacity_mode == Some(CapacityMode::Bytes(0)) {
+            return Err("The bucket capacity must be greater than 0 bytes".to_string());
+        }
+        if self.pull_batch_size == Some(0) {
+            return Err("pull_batch_size must be greater than 0".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn get_settings(data: &Data) -> IndexSettings {
+    IndexSettings {
+        desired_free_slots: data.canister_settings.desired_free_slots,
+        reindex_interval: data.canister_settings.reindex_interval,
+        indexing_strategy: data.business_state.get_indexing_strategy(),
+        cycles_per_bucket: data.canister_settings.cycles_per_bucket,
+        bucket_max_entries: data.canister_settings.bucket_max_entries,
+        bucket_capacity_mode: data.canister_settings.bucket_capacity_mode,
+        index_sync_mode: data.canister_settings.index_sync_mode,
+        pull_batch_size: data.canister_settings.pull_batch_size,
+    }
+}
+
+// Validates and applies the update, then records it in the settings history.
+// Nothing is changed if validation fails. A new bucket_max_entries or
+// bucket_capacity_mode only applies to buckets spawned after the change.
+pub(crate) fn update_settings(
+    update: SettingsUpdate,
+    changed_by: Principal,
+    now: TimestampMillis,
+    data: &mut Data,
+) -> Result<IndexSettings, String> {
+    update.validate()?;
+
+    let before = get_settings(data);
+    let settings = &mut data.canister_settings;
+
+    if let Some(desired_free_slots) = update.desired_free_slots {
+        settings.desired_free_slots = desired_free_slots;
+    }
+    if let Some(reindex_interval) = update.reindex_interval {
+        settings.reindex_interval = reindex_interval;
+    }
+    if let Some(cycles_per_bucket) = update.cycles_per_bucket {
+        settings.cycles_per_bucket = cycles_per_bucket;
+    }
+    if let Some(bucket_max_entries) = update.bucket_max_entries {
+        settings.bucket_max_entries = bucket_max_entries;
+    }
+    if let Some(bucket_capacity_mode) = update.bucket_capacity_mode {
+        settings.bucket_capacity_mode = bucket_capacity_mode;
+    }
+    if let Some(index_sync_mode) = update.index_sync_mode {
+        settings.index_sync_mode = index_sync_mode;
+    }
+    if let Some(pull_batch_size) = update.pull_batch_size {
+        settings.pull_batch_size = pull_batch_size;
+    }
+    if let Some(strategy) = update.indexing_strategy {
+        data.business_state.set_indexing_strategy(strategy);
+    }
+
+    let after = get_settings(data);
+    if after != before {
+        data.settings_history.push(SettingsChange {
+            changed_at: now,
+            changed_by,
+            before,
+            after: after.clone(),
+        });
+    }
+
+    Ok(after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        let mut data = Data::default();
+        let admin = Principal::from_slice(&[1]);
+
+        let update = SettingsUpdate {
+            desired_free_slots: Some(100),
+            reindex_interval: Some(10),
+            ..Default::default()
+        };
+
+        assert!(update_settings(update, admin, 0, &mut data).is_err());
+
+        // Nothing was applied or recorded
+        assert_eq!(data.canister_settings.desired_free_slots, 60);
+        assert!(data.settings_history.is_empty());
+
+        for update in [
+            SettingsUpdate {
+                desired_free_slots: Some(0),
+                ..Default::default()
+            },
+            SettingsUpdate {
+                cycles_per_bucket: Some(1),
+                ..Default::default()
+            },
+            SettingsUpdate {
+                bucket_max_entries: Some(0),
+                ..Default::default()
+            },
+            SettingsUpdate {
+                pull_batch_size: Some(0),
+                ..Default::default()
+            },
+            SettingsUpdate {
+                bucket_capacity_mode: Some(CapacityMode::Bytes(0)),
+                ..Default::default()
+            },
+        ] {
+            assert!(update.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn history() {
+        let mut data = Data::default();
+        let admin = Principal::from_slice(&[1]);
+
+        let update = SettingsUpdate {
+            desired_free_slots: Some(100),
+            indexing_strategy: Some(IndexingStrategy::FillFirst),
+            ..Default::default()
+        };
+        let after = update_settings(update, admin, 42, &mut data).unwrap();
+
+        assert_eq!(after.desired_free_slots, 100);
+        assert_eq!(after.indexing_strategy, IndexingStrategy::FillFirst);
+        assert_eq!(get_settings(&data), after);
+
+        assert_eq!(data.settings_history.len(), 1);
+        let change = &data.settings_history[0];
+        assert_eq!(change.changed_at, 42);
+        assert_eq!(change.changed_by, admin);
+        assert_eq!(change.before.desired_free_slots, 60);
+        assert_eq!(
+            change.before.indexing_strategy,
+            IndexingStrategy::BalancedLoad
+        );
+        assert_eq!(change.after, after);
+
+        // Updates that don't change anything aren't recorded
+        update_settings(SettingsUpdate::default(), admin, 43, &mut data).unwrap();
+        assert_eq!(data.settings_history.len(), 1);
+    }
+}
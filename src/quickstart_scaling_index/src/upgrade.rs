@@ -0,0 +1,593 @@
+use crate::businesslogic::{
+    call_canister_install, call_canister_start, call_canister_stop, CanisterInstall, InstallMode,
+};
+use crate::{Principal, RuntimeState, TimestampMillis, RUNTIME_STATE};
+use candid::{CandidType, Encode};
+use ic_cdk::api::call::CallResult;
+use ic_cdk::print;
+use quickstart_scaling_tasks::Lease;
+use serde::Deserialize;
+use std::cell::RefMut;
+
+pub const DEFAULT_BATCH_SIZE: u32 = 5;
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+// How long a batch owns each of its buckets. A batch that doesn't report back
+// in time (e.g. it trapped after an await) counts as a failed attempt.
+pub const UPGRADE_LEASE_PER_BUCKET: TimestampMillis = 300_000_000_000;
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct UpgradeArgs {
+    pub(crate) wasm_version: String,
+    pub(crate) batch_size: Option<u32>,
+    pub(crate) max_attempts: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum RolloutStatus {
+    Running,
+    Completed,
+    // The canary failed, no other bucket was touched
+    Halted(String),
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum BucketUpgradeStatus {
+    Pending,
+    // Leased by the batch that is upgrading it
+    Upgrading(Lease),
+    Upgraded,
+    // Retried until the bucket runs out of attempts
+    Failed,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BucketUpgrade {
+    pub(crate) canister_id: Principal,
+    pub(crate) status: BucketUpgradeStatus,
+    pub(crate) attempts: u32,
+    pub(crate) last_error: Option<String>,
+    pub(crate) upgraded_at: Option<TimestampMillis>,
+}
+
+#[derive(Debug)]
+pub enum UpgradeFailure {
+    Upgrade(String),
+    HealthCheck(String),
+}
+
+// Upgrades all the buckets to one wasm version, a batch per heartbeat.
+// The first bucket is the canary and is upgraded on its own; the rest only
+// follow once it passed its health check.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UpgradeRollout {
+    pub(crate) wasm_version: String,
+    pub(crate) started_at: TimestampMillis,
+    pub(crate) started_by: Principal,
+    pub(crate) finished_at: Option<TimestampMillis>,
+    pub(crate) batch_size: u32,
+    pub(crate) max_attempts: u32,
+    pub(crate) status: RolloutStatus,
+    // Also the id of the next batch's lease
+    pub(crate) batches_started: u64,
+    pub(crate) buckets: Vec<BucketUpgrade>,
+}
+
+impl UpgradeRollout {
+    pub fn new(
+        args: UpgradeArgs,
+        buckets: Vec<Principal>,
+        started_by: Principal,
+        now: TimestampMillis,
+    ) -> Result<Self, String> {
+        let batch_size = args.batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+        let max_attempts = args.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS);
+
+        if batch_size == 0 || max_attempts == 0 {
+            return Err("batch_size and max_attempts must be greater than 0".to_string());
+        }
+        if buckets.is_empty() {
+            return Err("There are no buckets to upgrade".to_string());
+        }
+
+        Ok(UpgradeRollout {
+            wasm_version: args.wasm_version,
+            started_at: now,
+            started_by,
+            finished_at: None,
+            batch_size,
+            max_attempts,
+            status: RolloutStatus::Running,
+            batches_started: 0,
+            buckets: buckets
+                .into_iter()
+                .map(|canister_id| BucketUpgrade {
+                    canister_id,
+                    status: BucketUpgradeStatus::Pending,
+                    attempts: 0,
+                    last_error: None,
+                    upgraded_at: None,
+                })
+                .collect(),
+        })
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.status == RolloutStatus::Running
+    }
+
+    // Leases and returns the next buckets to upgrade, with the id of the
+    // lease. Nothing is returned while a batch is still in flight. Buckets
+    // whose lease expired count as failed first.
+    pub fn next_batch(&mut self, now: TimestampMillis) -> Option<(u64, Vec<Principal>)> {
+        self.expire_leases(now);
+
+        if !self.is_running()
+            || self
+                .buckets
+                .iter()
+                .any(|b| matches!(b.status, BucketUpgradeStatus::Upgrading(_)))
+        {
+            return None;
+        }
+
+        let batch_size = match self.buckets[0].status {
+            BucketUpgradeStatus::Upgraded => self.batch_size as usize,
+            _ => 1,
+        };
+        let max_attempts = self.max_attempts;
+        let lease = Lease {
+            id: self.batches_started,
+            expires_at: now + UPGRADE_LEASE_PER_BUCKET * batch_size as u64,
+        };
+
+        let batch: Vec<Principal> = self
+            .buckets
+            .iter_mut()
+            .filter(|b| match b.status {
+                BucketUpgradeStatus::Pending => true,
+                BucketUpgradeStatus::Failed => b.attempts < max_attempts,
+                _ => false,
+            })
+            .take(batch_size)
+            .map(|b| {
+                b.status = BucketUpgradeStatus::Upgrading(lease.clone());
+                b.canister_id
+            })
+            .collect();
+
+        if batch.is_empty() {
+            self.finish(RolloutStatus::Completed, now);
+            return None;
+        }
+
+        self.batches_started += 1;
+        Some((lease.id, batch))
+    }
+
+    // Results from a batch that no longer holds the lease are ignored
+    pub fn record_result(
+        &mut self,
+        canister_id: Principal,
+        lease_id: u64,
+        result: Result<(), UpgradeFailure>,
+        now: TimestampMillis,
+    ) {
+        let is_canary = self.buckets[0].canister_id == canister_id;
+        let max_attempts = self.max_attempts;
+
+        if !self.holds_lease(canister_id, lease_id) {
+            return;
+        }
+        let bucket = match self
+            .buckets
+            .iter_mut()
+            .find(|b| b.canister_id == canister_id)
+        {
+            Some(bucket) => bucket,
+            None => return,
+        };
+
+        bucket.attempts += 1;
+        let halt = match result {
+            Ok(()) => {
+                bucket.status = BucketUpgradeStatus::Upgraded;
+                bucket.upgraded_at = Some(now);
+                None
+            }
+            Err(failure) => {
+                bucket.status = BucketUpgradeStatus::Failed;
+                let (msg, unhealthy) = match failure {
+                    UpgradeFailure::Upgrade(msg) => (msg, false),
+                    UpgradeFailure::HealthCheck(msg) => (msg, true),
+                };
+                bucket.last_error = Some(msg.clone());
+
+                if is_canary && (unhealthy || bucket.attempts >= max_attempts) {
+                    Some(format!("Canary {} failed: {}", canister_id, msg))
+                } else {
+                    None
+                }
+            }
+        };
+
+        if let Some(reason) = halt {
+            self.finish(RolloutStatus::Halted(reason), now);
+        }
+    }
+
+    pub fn holds_lease(&self, canister_id: Principal, lease_id: u64) -> bool {
+        self.buckets.iter().any(|b| {
+            b.canister_id == canister_id
+                && matches!(&b.status, BucketUpgradeStatus::Upgrading(lease) if lease.id == lease_id)
+        })
+    }
+
+    pub fn cancel(&mut self, now: TimestampMillis) -> Result<(), String> {
+        if !self.is_running() {
+            return Err("The rollout is not running".to_string());
+        }
+        self.finish(RolloutStatus::Cancelled, now);
+        Ok(())
+    }
+
+    fn expire_leases(&mut self, now: TimestampMillis) {
+        let expired: Vec<(Principal, u64)> = self
+            .buckets
+            .iter()
+            .filter_map(|b| match &b.status {
+                BucketUpgradeStatus::Upgrading(lease) if lease.expires_at <= now => {
+                    Some((b.canister_id, lease.id))
+                }
+                _ => None,
+            })
+            .collect();
+
+        for (canister_id, lease_id) in expired {
+            let failure = UpgradeFailure::Upgrade("The upgrade timed out".to_string());
+            self.record_result(canister_id, lease_id, Err(failure), now);
+        }
+    }
+
+    fn finish(&mut self, status: RolloutStatus, now: TimestampMillis) {
+        // Results of the buckets still in flight will be ignored
+        for bucket in self.buckets.iter_mut() {
+            if let BucketUpgradeStatus::Upgrading(_) = bucket.status {
+                bucket.status = BucketUpgradeStatus::Failed;
+            }
+        }
+        self.status = status;
+        self.finished_at = Some(now);
+    }
+}
+
+// Called every heartbeat, upgrades one batch of buckets if a rollout is running
+pub async fn upgrade_loop() {
+    let batch = match RUNTIME_STATE.with(|state| prep_upgrade_batch(state.borrow_mut())) {
+        Some(batch) => batch,
+        None => return,
+    };
+
+    for &canister_id in batch.buckets.iter() {
+        // A batch that lost its lease leaves the rest of its buckets alone
+        let holds_lease = RUNTIME_STATE.with(|state| {
+            state
+                .borrow()
+                .data
+                .bucket_upgrade
+                .as_ref()
+                .is_some_and(|rollout| {
+                    rollout.started_at == batch.rollout_started_at
+                        && rollout.holds_lease(canister_id, batch.lease_id)
+                })
+        });
+        if !holds_lease {
+            continue;
+        }
+
+        let result = upgrade_bucket(
+            canister_id,
+            batch.wasm_module.clone(),
+            batch.index_canister_id,
+        )
+        .await;
+        print(format!("Bucket upgrade {}: {:?}", canister_id, result));
+
+        RUNTIME_STATE.with(|state| {
+            let runtime_state = &mut *state.borrow_mut();
+            let now = runtime_state.env.now();
+
+            // The bucket runs the new version even if the rollout moved on
+            if result.is_ok() {
+                runtime_state
+                    .data
+                    .business_state
+                    .set_bucket_installed(canister_id, Some(batch.wasm_version.clone()));
+            }
+            if let Some(rollout) = runtime_state.data.bucket_upgrade.as_mut() {
+                if rollout.started_at == batch.rollout_started_at {
+                    rollout.record_result(canister_id, batch.lease_id, result, now);
+                }
+            }
+        });
+    }
+}
+
+struct UpgradeBatch {
+    // Identifies the rollout, its lease ids start again at 0
+    rollout_started_at: TimestampMillis,
+    lease_id: u64,
+    buckets: Vec<Principal>,
+    wasm_version: String,
+    wasm_module: Vec<u8>,
+    index_canister_id: Principal,
+}
+
+fn prep_upgrade_batch(mut runtime_state: RefMut<RuntimeState>) -> Option<UpgradeBatch> {
+    let now = runtime_state.env.now();
+    let index_canister_id = runtime_state.env.canister_id();
+    let data = &mut runtime_state.data;
+
+    let rollout = data.bucket_upgrade.as_mut()?;
+    if !rollout.is_running() {
+        return None;
+    }
+
+    let batch = rollout.next_batch(now);
+
+    // New buckets get the same version as the rest once the rollout is done
+    if rollout.status == RolloutStatus::Completed {
+        let _ = data.bucket_wasms.set_active(&rollout.wasm_version);
+        return None;
+    }
+    let (lease_id, buckets) = batch?;
+
+    match data.bucket_wasms.module(&rollout.wasm_version) {
+        Some(wasm_module) => Some(UpgradeBatch {
+            rollout_started_at: rollout.started_at,
+            lease_id,
+            buckets,
+            wasm_version: rollout.wasm_version.clone(),
+            wasm_module,
+            index_canister_id,
+        }),
+        None => {
+            rollout.finish(
+                RolloutStatus::Halted("The wasm version was removed".to_string()),
+                now,
+            );
+            None
+        }
+    }
+}
+
+// The bucket is stopped for the install so no call runs while its state is
+// being saved and restored, and started again whatever the outcome
+async fn upgrade_bucket(
+    canister_id: Principal,
+    wasm_module: Vec<u8>,
+    index_canister_id: Principal,
+) -> Result<(), UpgradeFailure> {
+    // A bucket that can't answer before the upgrade is still upgraded, the
+    // new version may fix it
+    let before = call_bucket_health(canister_id).await.ok();
+
+    if !call_canister_stop(canister_id).await {
+        call_canister_start(canister_id).await;
+        return Err(UpgradeFailure::Upgrade("stop_canister failed".to_string()));
+    }
+
+    let install_config = CanisterInstall {
+        mode: InstallMode::Upgrade,
+        canister_id,
+        wasm_module,
+        arg: Encode!().unwrap(),
+    };
+    let installed = call_canister_install(install_config).await;
+
+    if !call_canister_start(canister_id).await {
+        return Err(UpgradeFailure::Upgrade("start_canister failed".to_string()));
+    }
+    if !installed {
+        return Err(UpgradeFailure::Upgrade("install_code failed".to_string()));
+    }
+
+    check_bucket_health(canister_id, index_canister_id, before)
+        .await
+        .map_err(UpgradeFailure::HealthCheck)
+}
+
+// Only the part of the bucket's metrics that we check
+#[derive(CandidType, Deserialize, Debug)]
+struct BucketHealth {
+    canister_id: Principal,
+    index_canister_id: Principal,
+    current_entries: u64,
+    roles_version: u64,
+}
+
+// A bucket is healthy if it answers getMetrics, still knows its Index, and
+// kept its entries and roles through the upgrade. Posts and role pushes can
+// land between the first check and the stop, so only a lost state fails.
+async fn check_bucket_health(
+    canister_id: Principal,
+    index_canister_id: Principal,
+    before: Option<BucketHealth>,
+) -> Result<(), String> {
+    let health = call_bucket_health(canister_id).await?;
+
+    if health.canister_id != canister_id {
+        return Err(format!("Unexpected canister id {}", health.canister_id));
+    }
+    if health.index_canister_id != index_canister_id {
+        return Err(format!(
+            "The bucket reports {} as its Index",
+            health.index_canister_id
+        ));
+    }
+    if let Some(before) = before {
+        if before.current_entries > 0 && health.current_entries == 0 {
+            return Err(format!(
+                "The bucket had {} entries before the upgrade and none after",
+                before.current_entries
+            ));
+        }
+        if health.roles_version < before.roles_version {
+            return Err(format!(
+                "The bucket had role list version {} before the upgrade and {} after",
+                before.roles_version, health.roles_version
+            ));
+        }
+    }
+    Ok(())
+}
+
+async fn call_bucket_health(canister_id: Principal) -> Result<BucketHealth, String> {
+    let result: CallResult<(BucketHealth,)> =
+        ic_cdk::api::call::call(canister_id, "getMetrics", ()).await;
+
+    match result {
+        Ok((health,)) => Ok(health),
+        Err((code, msg)) => Err(format!("getMetrics failed: {}: {}", code as u8, msg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rollout(buckets: u8, batch_size: u32) -> UpgradeRollout {
+        let args = UpgradeArgs {
+            wasm_version: "v2".to_string(),
+            batch_size: Some(batch_size),
+            max_attempts: Some(2),
+        };
+        let buckets = (1..=buckets).map(|i| Principal::from_slice(&[i])).collect();
+
+        UpgradeRollout::new(args, buckets, Principal::anonymous(), 0).unwrap()
+    }
+
+    #[test]
+    fn canary_then_batches() {
+        let mut rollout = rollout(5, 3);
+
+        // The canary goes first, on its own
+        let (lease, batch) = rollout.next_batch(0).unwrap();
+        assert_eq!(batch, vec![Principal::from_slice(&[1])]);
+        assert!(rollout.next_batch(0).is_none());
+
+        rollout.record_result(batch[0], lease, Ok(()), 10);
+        assert_eq!(rollout.buckets[0].upgraded_at, Some(10));
+
+        let (lease, batch) = rollout.next_batch(10).unwrap();
+        assert_eq!(batch.len(), 3);
+
+        // Failed buckets are retried in a later batch
+        rollout.record_result(batch[0], lease, Ok(()), 20);
+        rollout.record_result(batch[1], lease, Ok(()), 20);
+        rollout.record_result(
+            batch[2],
+            lease,
+            Err(UpgradeFailure::Upgrade("out of cycles".to_string())),
+            20,
+        );
+
+        let (lease, retry) = rollout.next_batch(20).unwrap();
+        assert_eq!(retry, vec![batch[2], Principal::from_slice(&[5])]);
+        for canister_id in retry {
+            rollout.record_result(
+                canister_id,
+                lease,
+                Err(UpgradeFailure::HealthCheck("trapped".to_string())),
+                30,
+            );
+        }
+
+        // Bucket 4 ran out of attempts, bucket 5 gets another try
+        let (lease, batch) = rollout.next_batch(30).unwrap();
+        assert_eq!(batch, vec![Principal::from_slice(&[5])]);
+        rollout.record_result(batch[0], lease, Ok(()), 40);
+
+        assert!(rollout.next_batch(50).is_none());
+        assert_eq!(rollout.status, RolloutStatus::Completed);
+        assert_eq!(rollout.finished_at, Some(50));
+        assert_eq!(rollout.batches_started, 4);
+        assert_eq!(rollout.buckets[3].status, BucketUpgradeStatus::Failed);
+        assert_eq!(rollout.buckets[3].attempts, 2);
+        assert_eq!(rollout.buckets[3].last_error, Some("trapped".to_string()));
+    }
+
+    #[test]
+    fn unhealthy_canary_halts() {
+        let mut rollout = rollout(3, 3);
+
+        let (lease, batch) = rollout.next_batch(0).unwrap();
+        rollout.record_result(
+            batch[0],
+            lease,
+            Err(UpgradeFailure::HealthCheck("trapped".to_string())),
+            10,
+        );
+
+        assert!(matches!(rollout.status, RolloutStatus::Halted(_)));
+        assert!(rollout.next_batch(10).is_none());
+        assert!(rollout.buckets[1..]
+            .iter()
+            .all(|b| b.status == BucketUpgradeStatus::Pending));
+    }
+
+    #[test]
+    fn stale_results_are_ignored() {
+        let mut rollout = rollout(2, 3);
+
+        let (lease, batch) = rollout.next_batch(0).unwrap();
+        rollout.record_result(batch[0], lease + 1, Ok(()), 10);
+        assert!(rollout.holds_lease(batch[0], lease));
+
+        rollout.cancel(20).unwrap();
+        assert!(!rollout.holds_lease(batch[0], lease));
+        rollout.record_result(batch[0], lease, Ok(()), 30);
+        assert_eq!(rollout.status, RolloutStatus::Cancelled);
+        assert_eq!(rollout.buckets[0].status, BucketUpgradeStatus::Failed);
+        assert!(rollout.cancel(40).is_err());
+    }
+
+    #[test]
+    fn expired_leases_fail_the_batch() {
+        let mut rollout = rollout(3, 2);
+
+        let (lease, batch) = rollout.next_batch(0).unwrap();
+        rollout.record_result(batch[0], lease, Ok(()), 10);
+
+        // The batch traps or loses its callback and never reports back
+        let (stuck, batch) = rollout.next_batch(10).unwrap();
+        let expires_at = 10 + 2 * UPGRADE_LEASE_PER_BUCKET;
+        assert!(rollout.next_batch(expires_at - 1).is_none());
+
+        // Its buckets are retried by the next batch
+        let (lease, retry) = rollout.next_batch(expires_at).unwrap();
+        assert_eq!(retry, batch);
+        assert_ne!(lease, stuck);
+        assert_eq!(rollout.buckets[1].attempts, 1);
+        assert_eq!(
+            rollout.buckets[1].last_error,
+            Some("The upgrade timed out".to_string())
+        );
+
+        // The stuck batch can't report anymore
+        assert!(!rollout.holds_lease(batch[0], stuck));
+        rollout.record_result(batch[0], stuck, Ok(()), expires_at);
+        assert!(rollout.holds_lease(batch[0], lease));
+        assert_eq!(rollout.buckets[1].upgraded_at, None);
+    }
+
+    #[test]
+    fn canary_timing_out_halts() {
+        let mut rollout = rollout(3, 3);
+        rollout.max_attempts = 1;
+
+        rollout.next_batch(0).unwrap();
+        assert!(rollout.next_batch(UPGRADE_LEASE_PER_BUCKET).is_none());
+        assert!(matches!(rollout.status, RolloutStatus::Halted(_)));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{Principal, TimestampMillis};
+use candid::CandidType;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// install_code messages are limited to 2MiB, a bigger module couldn't be installed anyway
+pub const MAX_WASM_SIZE: usize = 2 * 1024 * 1024;
+
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct WasmVersion {
+    pub(crate) name: String,
+    pub(crate) sha256: Vec<u8>,
+    pub(crate) size: u64,
+    pub(crate) uploaded_at: TimestampMillis,
+    pub(crate) uploaded_by: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+struct StoredWasm {
+    version: WasmVersion,
+    #[serde(with = "serde_bytes")]
+    module: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct WasmUpload {
+    expected_sha256: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    module: Vec<u8>,
+}
+
+// Bucket wasm modules uploaded by admins after the Index is installed.
+// A module is uploaded in chunks and only becomes a version once its SHA-256
+// matches the hash given when the upload was started. New buckets are
+// installed with the active version.
+#[derive(CandidType, Deserialize, Default)]
+pub struct WasmStore {
+    versions: Vec<StoredWasm>,
+    uploads: HashMap<String, WasmUpload>,
+    active: Option<String>,
+}
+
+impl WasmStore {
+    // Starting an upload for a name that is already being uploaded discards
+    // the chunks received so far
+    pub fn start_upload(&mut self, name: String, expected_sha256: Vec<u8>) -> Result<(), String> {
+        if name.is_empty() {
+            return Err("The version name can't be empty".to_string());
+        }
+        if self.find(&name).is_some() {
+            return Err(format!("Version {} already exists", name));
+        }
+        if expected_sha256.len() != 32 {
+            return Err("The SHA-256 hash must be 32 bytes long".to_string());
+        }
+
+        self.uploads.insert(
+            name,
+            WasmUpload {
+                expected_sha256,
+                module: vec![],
+            },
+        );
+        Ok(())
+    }
+
+    // Returns the number of bytes received so far
+    pub fn append_chunk(&mut self, name: &str, chunk: Vec<u8>) -> Result<u64, String> {
+        let upload = self
+            .uploads
+            .get_mut(name)
+            .ok_or(format!("No upload in progress for {}", name))?;
+
+        if upload.module.len() + chunk.len() > MAX_WASM_SIZE {
+            self.uploads.remove(name);
+            return Err(format!(
+                "The module is larger than {} bytes, upload cancelled",
+                MAX_WASM_SIZE
+            ));
+        }
+
+        upload.module.extend(chunk);
+        Ok(upload.module.len() as u64)
+    }
+
+    // A failed hash check cancels the upload, it has to be started again
+    pub fn commit_upload(
+        &mut self,
+        name: &str,
+        uploaded_by: Principal,
+        now: TimestampMillis,
+    ) -> Result<WasmVersion, String> {
+        let upload = self
+            .uploads
+            .remove(name)
+            .ok_or(format!("No upload in progress for {}", name))?;
+
+        if upload.module.is_empty() {
+            return Err("The module is empty, upload cancelled".to_string());
+        }
+
+        let sha256 = Sha256::digest(&upload.module).to_vec();
+        if sha256 != upload.expected_sha256 {
+            return Err("SHA-256 mismatch, upload cancelled".to_string());
+        }
+
+        let version = WasmVersion {
+            name: name.to_string(),
+            sha256,
+            size: upload.module.len() as u64,
+            uploaded_at: now,
+            uploaded_by,
+        };
+        self.versions.push(StoredWasm {
+            version: version.clone(),
+            module: upload.module,
+        });
+
+        Ok(version)
+    }
+
+    pub fn set_active(&mut self, name: &str) -> Result<(), String> {
+        if self.find(name).is_none() {
+            return Err(format!("Unknown version {}", name));
+        }
+        self.active = Some(name.to_string());
+        Ok(())
+    }
+
+    // The active version can't be removed, another one has to be activated first
+    pub fn remove_version(&mut self, name: &str) -> Result<(), String> {
+        if self.active.as_deref() == Some(name) {
+            return Err("The active version can't be removed".to_string());
+        }
+        let before = self.versions.len();
+        self.versions.retain(|w| w.version.name != name);
+        if before == self.versions.len() {
+            return Err(format!("Unknown version {}", name));
+        }
+        Ok(())
+    }
+
+    pub fn versions(&self) -> Vec<WasmVersion> {
+        self.versions.iter().map(|w| w.version.clone()).collect()
+    }
+
+    pub fn active_version(&self) -> Option<WasmVersion> {
+        self.active_wasm().map(|w| w.version.clone())
+    }
+
+    pub fn active_module(&self) -> Option<Vec<u8>> {
+        self.active_wasm().map(|w| w.module.clone())
+    }
+
+    pub fn module(&self, name: &str) -> Option<Vec<u8>> {
+        self.find(name).map(|w| w.module.clone())
+    }
+
+    fn active_wasm(&self) -> Option<&StoredWasm> {
+        self.active.as_deref().and_then(|name| self.find(name))
+    }
+
+    fn find(&self, name: &str) -> Option<&StoredWasm> {
+        self.versions.iter().find(|w| w.version.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(store: &mut WasmStore, name: &str, module: &[u8]) -> Result<WasmVersion, String> {
+        let admin = Principal::from_slice(&[1]);
+        let sha256 = Sha256::digest(module).to_vec();
+
+        store.start_upload(name.to_string(), sha256)?;
+        for chunk in module.chunks(3) {
+            store.append_chunk(name, chunk.to_vec())?;
+        }
+        store.commit_upload(name, admin, 42)
+    }
+
+    #[test]
+    fn chunked_upload() {
+        let mut store = WasmStore::default();
+        let module = b"\0asm fake bucket module".to_vec();
+
+        let version = upload(&mut store, "v1", &module).unwrap();
+        assert_eq!(version.size, module.len() as u64);
+        assert_eq!(version.uploaded_at, 42);
+        assert_eq!(store.versions(), vec![version.clone()]);
+
+        // Nothing is installed until a version is marked active
+        assert!(store.active_module().is_none());
+        store.set_active("v1").unwrap();
+        assert_eq!(store.active_module(), Some(module.clone()));
+        assert_eq!(store.active_version(), Some(version));
+
+        // Names are unique
+        assert!(upload(&mut store, "v1", &module).is_err());
+        assert!(store.set_active("v2").is_err());
+    }
+
+    #[test]
+    fn hash_mismatch() {
+        let mut store = WasmStore::default();
+
+        assert!(store.start_upload("v1".to_string(), vec![0; 31]).is_err());
+
+        store.start_upload("v1".to_string(), vec![0; 32]).unwrap();
+        assert_eq!(store.append_chunk("v1", vec![1, 2, 3]), Ok(3));
+        assert!(store
+            .commit_upload("v1", Principal::anonymous(), 0)
+            .is_err());
+
+        // The upload was cancelled and no version was stored
+        assert!(store.append_chunk("v1", vec![4]).is_err());
+        assert!(store.versions().is_empty());
+    }
+
+    #[test]
+    fn versions() {
+        let mut store = WasmStore::default();
+
+        upload(&mut store, "v1", b"first").unwrap();
+        upload(&mut store, "v2", b"second").unwrap();
+        store.set_active("v2").unwrap();
+
+        assert!(store.remove_version("v2").is_err());
+        assert!(store.remove_version("v3").is_err());
+        store.remove_version("v1").unwrap();
+
+        assert_eq!(store.versions().len(), 1);
+        assert_eq!(store.active_module(), Some(b"second".to_vec()));
+    }
+}
@@ -0,0 +1,82 @@
+use candid::{CandidType, Principal};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+// Same as the canisters' TimestampMillis, IC time in nanoseconds
+pub type TimestampMillis = u64;
+
+// An upload slot the Index set aside in a bucket for one caller. It's signed
+// with a key that only the Index and that bucket know, so the bucket can check
+// it without asking the Index.
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct SlotReservation {
+    pub id: u64,
+    pub bucket: Principal,
+    pub holder: Principal,
+    pub expires_at: TimestampMillis,
+    pub signature: Vec<u8>,
+}
+
+impl SlotReservation {
+    pub fn new(
+        id: u64,
+        bucket: Principal,
+        holder: Principal,
+        expires_at: TimestampMillis,
+        key: &[u8],
+    ) -> Self {
+        let mut reservation = SlotReservation {
+            id,
+            bucket,
+            holder,
+            expires_at,
+            signature: vec![],
+        };
+        reservation.signature = reservation.mac(key).finalize().into_bytes().to_vec();
+        reservation
+    }
+
+    pub fn verify(&self, key: &[u8]) -> bool {
+        self.mac(key).verify(&self.signature).is_ok()
+    }
+
+    fn mac(&self, key: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(&self.id.to_be_bytes());
+        mac.update(&self.expires_at.to_be_bytes());
+        // Principals are at most 29 bytes, the length keeps the fields apart
+        mac.update(&[self.bucket.as_slice().len() as u8]);
+        mac.update(self.bucket.as_slice());
+        mac.update(self.holder.as_slice());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures() {
+        let bucket = Principal::from_slice(&[1]);
+        let holder = Principal::from_slice(&[2]);
+        let key = b"bucket key".to_vec();
+
+        let reservation = SlotReservation::new(7, bucket, holder, 100, &key);
+        assert!(reservation.verify(&key));
+        assert!(!reservation.verify(b"another key"));
+
+        // Any change to the reservation breaks the signature
+        let forged = SlotReservation {
+            expires_at: 200,
+            ..reservation.clone()
+        };
+        assert!(!forged.verify(&key));
+        let forged = SlotReservation {
+            holder: bucket,
+            ..reservation
+        };
+        assert!(!forged.verify(&key));
+    }
+}
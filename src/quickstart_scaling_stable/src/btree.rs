@@ -0,0 +1,531 @@
+use crate::heap::Heap;
+use std::convert::TryInto;
+
+// Minimum degree: nodes other than the root hold between B - 1 and 2B - 1 keys
+const B: usize = 6;
+const MAX_KEYS: usize = 2 * B - 1;
+
+// An ordered map from fixed size keys to u64 values, with its nodes in a
+// Heap. Keys are compared as bytes, so integers should be stored big endian
+// to keep their order. Values are usually the address of a chunk that holds
+// the actual data.
+//
+// The map itself is only the slot of its root in the heap header and the key
+// size. Every operation takes the heap it lives in, so several maps can share
+// one heap.
+#[derive(Clone, Copy, Debug)]
+pub struct BTreeMap {
+    root_slot: usize,
+    key_size: usize,
+}
+
+#[derive(Debug)]
+struct Node {
+    ptr: u64,
+    leaf: bool,
+    keys: Vec<Vec<u8>>,
+    values: Vec<u64>,
+    // Empty for leaves
+    children: Vec<u64>,
+}
+
+impl BTreeMap {
+    pub const fn new(root_slot: usize, key_size: usize) -> Self {
+        BTreeMap {
+            root_slot,
+            key_size,
+        }
+    }
+
+    pub fn get(&self, heap: &Heap, key: &[u8]) -> Option<u64> {
+        self.check_key(key);
+        let mut ptr = heap.root(self.root_slot);
+
+        while ptr != 0 {
+            let node = self.load(heap, ptr);
+            match node.search(key) {
+                Ok(i) => return Some(node.values[i]),
+                Err(_) if node.leaf => return None,
+                Err(i) => ptr = node.children[i],
+            }
+        }
+        None
+    }
+
+    pub fn contains_key(&self, heap: &Heap, key: &[u8]) -> bool {
+        self.get(heap, key).is_some()
+    }
+
+    // Returns the previous value of the key, if any
+    pub fn insert(&self, heap: &mut Heap, key: &[u8], value: u64) -> Option<u64> {
+        self.check_key(key);
+
+        if let Some(previous) = self.replace(heap, key, value) {
+            return Some(previous);
+        }
+
+        let root_ptr = heap.root(self.root_slot);
+        if root_ptr == 0 {
+            let root = Node {
+                ptr: 0,
+                leaf: true,
+                keys: vec![key.to_vec()],
+                values: vec![value],
+                children: vec![],
+            };
+            let ptr = self.alloc(heap, &root);
+            heap.set_root(self.root_slot, ptr);
+            return None;
+        }
+
+        // Full nodes are split on the way down, so there's always room for
+        // the key that a split moves up
+        let mut root = self.load(heap, root_ptr);
+        if root.keys.len() == MAX_KEYS {
+            let mut new_root = Node {
+                ptr: 0,
+                leaf: false,
+                keys: vec![],
+                values: vec![],
+                children: vec![root_ptr],
+            };
+            new_root.ptr = self.alloc(heap, &new_root);
+            heap.set_root(self.root_slot, new_root.ptr);
+            self.split_child(heap, &mut new_root, 0);
+            root = new_root;
+        }
+        self.insert_non_full(heap, root, key, value);
+        None
+    }
+
+    pub fn remove(&self, heap: &mut Heap, key: &[u8]) -> Option<u64> {
+        self.check_key(key);
+
+        let root_ptr = heap.root(self.root_slot);
+        if root_ptr == 0 {
+            return None;
+        }
+
+        let removed = self.remove_from(heap, self.load(heap, root_ptr), key);
+
+        // The root loses its last key when its children were merged
+        let root = self.load(heap, root_ptr);
+        if root.keys.is_empty() {
+            let new_root = root.children.first().copied().unwrap_or(0);
+            heap.free(root_ptr);
+            heap.set_root(self.root_slot, new_root);
+        }
+        removed
+    }
+
+    // The entries with a key of at least `from`, in key order
+    pub fn range<'a>(&self, heap: &'a Heap, from: &[u8]) -> Iter<'a> {
+        self.check_key(from);
+
+        let mut iter = Iter {
+            map: *self,
+            heap,
+            stack: vec![],
+        };
+
+        let mut ptr = heap.root(self.root_slot);
+        while ptr != 0 {
+            let node = self.load(heap, ptr);
+            let i = node.keys.partition_point(|k| k.as_slice() < from);
+            ptr = if node.leaf { 0 } else { node.children[i] };
+            iter.stack.push((node, i));
+        }
+        iter
+    }
+
+    pub fn iter<'a>(&self, heap: &'a Heap) -> Iter<'a> {
+        self.range(heap, &vec![0; self.key_size])
+    }
+
+    fn replace(&self, heap: &mut Heap, key: &[u8], value: u64) -> Option<u64> {
+        let mut ptr = heap.root(self.root_slot);
+
+        while ptr != 0 {
+            let mut node = self.load(heap, ptr);
+            match node.search(key) {
+                Ok(i) => {
+                    let previous = std::mem::replace(&mut node.values[i], value);
+                    self.save(heap, &node);
+                    return Some(previous);
+                }
+                Err(_) if node.leaf => return None,
+                Err(i) => ptr = node.children[i],
+            }
+        }
+        None
+    }
+
+    fn insert_non_full(&self, heap: &mut Heap, mut node: Node, key: &[u8], value: u64) {
+        loop {
+            let mut i = node.search(key).unwrap_err();
+
+            if node.leaf {
+                node.keys.insert(i, key.to_vec());
+                node.values.insert(i, value);
+                self.save(heap, &node);
+                return;
+            }
+
+            if self.load(heap, node.children[i]).keys.len() == MAX_KEYS {
+                self.split_child(heap, &mut node, i);
+                if key > node.keys[i].as_slice() {
+                    i += 1;
+                }
+            }
+            node = self.load(heap, node.children[i]);
+        }
+    }
+
+    // Moves the upper half of the full child i to a new node, and its median
+    // key up to the parent
+    fn split_child(&self, heap: &mut Heap, parent: &mut Node, i: usize) {
+        let mut child = self.load(heap, parent.children[i]);
+
+        let mut right = Node {
+            ptr: 0,
+            leaf: child.leaf,
+            keys: child.keys.split_off(B),
+            values: child.values.split_off(B),
+            children: if child.leaf {
+                vec![]
+            } else {
+                child.children.split_off(B)
+            },
+        };
+        let median_key = child.keys.pop().unwrap();
+        let median_value = child.values.pop().unwrap();
+        right.ptr = self.alloc(heap, &right);
+
+        parent.keys.insert(i, median_key);
+        parent.values.insert(i, median_value);
+        parent.children.insert(i + 1, right.ptr);
+
+        self.save(heap, &child);
+        self.save(heap, parent);
+    }
+
+    // Every node we go down to has at least B keys, so removing a key from it
+    // never leaves it with too few
+    fn remove_from(&self, heap: &mut Heap, mut node: Node, key: &[u8]) -> Option<u64> {
+        loop {
+            match node.search(key) {
+                Ok(i) if node.leaf => {
+                    node.keys.remove(i);
+                    let value = node.values.remove(i);
+                    self.save(heap, &node);
+                    return Some(value);
+                }
+                Ok(i) => {
+                    let value = node.values[i];
+                    let left = self.load(heap, node.children[i]);
+                    let right = self.load(heap, node.children[i + 1]);
+
+                    // The key is replaced by its predecessor or successor,
+                    // which is then removed from the child it came from
+                    if left.keys.len() >= B {
+                        let (k, v) = self.last(heap, left.ptr);
+                        node.keys[i] = k.clone();
+                        node.values[i] = v;
+                        self.save(heap, &node);
+                        self.remove_from(heap, left, &k);
+                    } else if right.keys.len() >= B {
+                        let (k, v) = self.first(heap, right.ptr);
+                        node.keys[i] = k.clone();
+                        node.values[i] = v;
+                        self.save(heap, &node);
+                        self.remove_from(heap, right, &k);
+                    } else {
+                        let merged = self.merge_children(heap, &mut node, i);
+                        self.remove_from(heap, merged, key);
+                    }
+                    return Some(value);
+                }
+                Err(_) if node.leaf => return None,
+                Err(i) => {
+                    node = self.fill_child(heap, &mut node, i);
+                }
+            }
+        }
+    }
+
+    // Makes sure child i has at least B keys, by borrowing a key from a
+    // sibling or merging it with one. Returns the node to go down to.
+    fn fill_child(&self, heap: &mut Heap, parent: &mut Node, i: usize) -> Node {
+        let mut child = self.load(heap, parent.children[i]);
+        if child.keys.len() >= B {
+            return child;
+        }
+
+        if i > 0 {
+            let mut left = self.load(heap, parent.children[i - 1]);
+            if left.keys.len() >= B {
+                child.keys.insert(0, parent.keys[i - 1].clone());
+                child.values.insert(0, parent.values[i - 1]);
+                parent.keys[i - 1] = left.keys.pop().unwrap();
+                parent.values[i - 1] = left.values.pop().unwrap();
+                if !left.leaf {
+                    child.children.insert(0, left.children.pop().unwrap());
+                }
+                self.save(heap, &left);
+                self.save(heap, &child);
+                self.save(heap, parent);
+                return child;
+            }
+        }
+
+        if i < parent.keys.len() {
+            let mut right = self.load(heap, parent.children[i + 1]);
+            if right.keys.len() >= B {
+                child.keys.push(parent.keys[i].clone());
+                child.values.push(parent.values[i]);
+                parent.keys[i] = right.keys.remove(0);
+                parent.values[i] = right.values.remove(0);
+                if !right.leaf {
+                    child.children.push(right.children.remove(0));
+                }
+                self.save(heap, &right);
+                self.save(heap, &child);
+                self.save(heap, parent);
+                return child;
+            }
+        }
+
+        if i < parent.keys.len() {
+            self.merge_children(heap, parent, i)
+        } else {
+            self.merge_children(heap, parent, i - 1)
+        }
+    }
+
+    // Merges child i + 1 and the key between them into child i
+    fn merge_children(&self, heap: &mut Heap, parent: &mut Node, i: usize) -> Node {
+        let mut left = self.load(heap, parent.children[i]);
+        let right = self.load(heap, parent.children[i + 1]);
+
+        left.keys.push(parent.keys.remove(i));
+        left.values.push(parent.values.remove(i));
+        left.keys.extend(right.keys);
+        left.values.extend(right.values);
+        left.children.extend(right.children);
+        parent.children.remove(i + 1);
+
+        heap.free(right.ptr);
+        self.save(heap, &left);
+        self.save(heap, parent);
+        left
+    }
+
+    fn first(&self, heap: &Heap, mut ptr: u64) -> (Vec<u8>, u64) {
+        loop {
+            let node = self.load(heap, ptr);
+            if node.leaf {
+                return (node.keys[0].clone(), node.values[0]);
+            }
+            ptr = node.children[0];
+        }
+    }
+
+    fn last(&self, heap: &Heap, mut ptr: u64) -> (Vec<u8>, u64) {
+        loop {
+            let node = self.load(heap, ptr);
+            if node.leaf {
+                let i = node.keys.len() - 1;
+                return (node.keys[i].clone(), node.values[i]);
+            }
+            ptr = *node.children.last().unwrap();
+        }
+    }
+
+    // Nodes are always given room for MAX_KEYS, so they can be rewritten in
+    // place
+    fn node_capacity(&self) -> u64 {
+        (2 + MAX_KEYS * (self.key_size + 8) + (MAX_KEYS + 1) * 8) as u64
+    }
+
+    fn alloc(&self, heap: &mut Heap, node: &Node) -> u64 {
+        heap.alloc_with_capacity(&self.encode(node), self.node_capacity())
+    }
+
+    fn save(&self, heap: &mut Heap, node: &Node) {
+        heap.write(node.ptr, &self.encode(node));
+    }
+
+    // Leaf flag, number of keys, keys, values, children
+    fn encode(&self, node: &Node) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.node_capacity() as usize);
+        bytes.push(node.leaf as u8);
+        bytes.push(node.keys.len() as u8);
+        for key in node.keys.iter() {
+            bytes.extend_from_slice(key);
+        }
+        for value in node.values.iter().chain(node.children.iter()) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn load(&self, heap: &Heap, ptr: u64) -> Node {
+        let bytes = heap.read(ptr);
+        let leaf = bytes[0] == 1;
+        let len = bytes[1] as usize;
+
+        let (keys, rest) = bytes[2..].split_at(len * self.key_size);
+        let mut numbers = rest
+            .chunks_exact(8)
+            .map(|n| u64::from_le_bytes(n.try_into().unwrap()));
+
+        Node {
+            ptr,
+            leaf,
+            keys: keys
+                .chunks_exact(self.key_size)
+                .map(|k| k.to_vec())
+                .collect(),
+            values: numbers.by_ref().take(len).collect(),
+            children: numbers.collect(),
+        }
+    }
+
+    fn check_key(&self, key: &[u8]) {
+        assert_eq!(key.len(), self.key_size, "Wrong key size");
+    }
+}
+
+impl Node {
+    fn search(&self, key: &[u8]) -> Result<usize, usize> {
+        self.keys.binary_search_by(|k| k.as_slice().cmp(key))
+    }
+}
+
+pub struct Iter<'a> {
+    map: BTreeMap,
+    heap: &'a Heap,
+    // The nodes from the root to the current one, with the index of the next
+    // key to return in each. An inner node's next key comes after the child
+    // we went down to.
+    stack: Vec<(Node, usize)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (Vec<u8>, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, i) = self.stack.last_mut()?;
+            if *i >= node.keys.len() {
+                self.stack.pop();
+                continue;
+            }
+
+            let item = (node.keys[*i].clone(), node.values[*i]);
+            *i += 1;
+
+            // Everything in the next child comes before the next key
+            if !node.leaf {
+                let mut ptr = node.children[*i];
+                while ptr != 0 {
+                    let child = self.map.load(self.heap, ptr);
+                    ptr = if child.leaf { 0 } else { child.children[0] };
+                    self.stack.push((child, 0));
+                }
+            }
+            return Some(item);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::VectorMemory;
+
+    fn key(n: u64) -> Vec<u8> {
+        n.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let mut heap = Heap::open(Box::new(VectorMemory::default()));
+        let map = BTreeMap::new(0, 8);
+        let mut expected = std::collections::BTreeMap::new();
+
+        // Enough keys for a few levels, in a scrambled order
+        let mut n: u64 = 1;
+        for _ in 0..2000 {
+            n = n
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let k = n % 1000;
+            assert_eq!(map.insert(&mut heap, &key(k), n), expected.insert(k, n));
+        }
+        for k in 0..1000 {
+            assert_eq!(map.get(&heap, &key(k)), expected.get(&k).copied());
+        }
+
+        for k in (0..1000).step_by(3).chain((0..1000).step_by(7)) {
+            assert_eq!(map.remove(&mut heap, &key(k)), expected.remove(&k));
+        }
+        let all: Vec<(u64, u64)> = map
+            .iter(&heap)
+            .map(|(k, v)| (u64::from_be_bytes(k.try_into().unwrap()), v))
+            .collect();
+        assert_eq!(all, expected.clone().into_iter().collect::<Vec<_>>());
+
+        for k in 0..1000 {
+            assert_eq!(map.remove(&mut heap, &key(k)), expected.remove(&k));
+        }
+        assert_eq!(heap.root(0), 0);
+        assert_eq!(map.iter(&heap).count(), 0);
+    }
+
+    #[test]
+    fn range() {
+        let mut heap = Heap::open(Box::new(VectorMemory::default()));
+        let map = BTreeMap::new(1, 8);
+        for k in (0..300).map(|k| k * 2) {
+            map.insert(&mut heap, &key(k), k);
+        }
+
+        let from = |k: u64| -> Vec<u64> { map.range(&heap, &key(k)).map(|(_, v)| v).collect() };
+        assert_eq!(from(0).len(), 300);
+        assert_eq!(from(101)[..3], [102, 104, 106]);
+        assert_eq!(from(598), vec![598]);
+        assert!(from(599).is_empty());
+    }
+
+    // The map is only its nodes in the heap, a heap opened again at any point
+    // has the same map
+    #[test]
+    fn reopened_after_every_change() {
+        let memory = VectorMemory::default();
+        let map = BTreeMap::new(2, 8);
+        let mut expected = std::collections::BTreeMap::new();
+
+        let mut n: u64 = 1;
+        for _ in 0..500 {
+            n = n
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let k = n % 200;
+
+            let mut heap = Heap::open(Box::new(memory.clone()));
+            if n.is_multiple_of(3) {
+                assert_eq!(map.remove(&mut heap, &key(k)), expected.remove(&k));
+            } else {
+                assert_eq!(map.insert(&mut heap, &key(k), n), expected.insert(k, n));
+            }
+
+            let heap = Heap::open(Box::new(memory.clone()));
+            let all: Vec<(u64, u64)> = map
+                .iter(&heap)
+                .map(|(k, v)| (u64::from_be_bytes(k.try_into().unwrap()), v))
+                .collect();
+            assert_eq!(all, expected.clone().into_iter().collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,557 @@
+use crate::memory::{Memory, WASM_PAGE_SIZE};
+use std::convert::TryInto;
+
+// A heap set up by another version is never mistaken for ours
+const MAGIC: &[u8; 8] = b"QSHEAP02";
+
+// Small chunks come in power of two sizes, from 64 bytes to 64KiB. Freed
+// chunks go to the free list of their size and are handed out again before
+// the heap grows.
+const MIN_CLASS: u32 = 6;
+const MAX_CLASS: u32 = 16;
+const CLASSES: usize = (MAX_CLASS - MIN_CLASS + 1) as usize;
+
+// Larger chunks take whole pages. Freed ones are kept in a single list in
+// address order, so neighbours are merged and the first one that fits is
+// handed out, split if it's bigger than needed. They only hold entries above
+// 64KiB, so the list stays short.
+const LARGE_CHUNK_SIZE: u64 = WASM_PAGE_SIZE;
+
+// Pointers the structures built on the heap keep in the header, e.g. the
+// root of a B-tree
+pub const ROOTS: usize = 16;
+
+// Header layout: magic, end of the used memory, free lists of the small
+// chunks, free list of the large chunks, roots, checksum of the rest. It's
+// kept twice, so a header that was written over can be restored from the
+// other copy.
+const HEADER_LEN: usize = 8 * (3 + CLASSES + ROOTS) + 8;
+const HEADER_COPIES: [u64; 2] = [0, 256];
+const HEADER_SIZE: u64 = 512;
+
+// Every chunk starts with its size and the length of its data
+const CHUNK_HEADER_SIZE: u64 = 16;
+
+// An allocator over a Memory. Chunks are addressed by their offset, 0 is
+// never a chunk so it can stand for "none". Everything, including the
+// allocator's own state, is kept in the memory, so a heap opened again after
+// an upgrade is exactly as it was left.
+//
+// A canister call that traps leaves the stable memory as it was before the
+// call, so operations never have to be undone halfway.
+pub struct Heap {
+    memory: Box<dyn Memory>,
+    header: Header,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Header {
+    bump: u64,
+    free_lists: [u64; CLASSES],
+    large_free_list: u64,
+    roots: [u64; ROOTS],
+}
+
+impl Heap {
+    // True if the memory holds a heap, even one whose header was damaged
+    pub fn is_initialized(memory: &dyn Memory) -> bool {
+        if memory.size() == 0 {
+            return false;
+        }
+        HEADER_COPIES.iter().any(|&offset| {
+            let mut magic = [0; 8];
+            memory.read(offset, &mut magic);
+            &magic == MAGIC
+        })
+    }
+
+    // Sets up an empty heap in `memory`. Anything already in the memory is
+    // overwritten.
+    pub fn new(memory: Box<dyn Memory>) -> Heap {
+        let mut heap = Heap {
+            memory,
+            header: Header {
+                bump: HEADER_SIZE,
+                ..Default::default()
+            },
+        };
+        heap.ensure_size(HEADER_SIZE);
+        heap.memory.write(0, &[0; HEADER_SIZE as usize]);
+        heap.save_header();
+        heap
+    }
+
+    // Opens the heap in `memory`, or sets up an empty one if the memory is
+    // empty. Panics if the memory holds anything else, or if both copies of
+    // the header are damaged, so a canister upgrade that can't read the heap
+    // fails instead of losing its content.
+    pub fn open(memory: Box<dyn Memory>) -> Heap {
+        if memory.size() == 0 {
+            return Heap::new(memory);
+        }
+
+        let header = HEADER_COPIES
+            .iter()
+            .find_map(|&offset| {
+                let mut bytes = [0; HEADER_LEN];
+                memory.read(offset, &mut bytes);
+                Header::decode(&bytes)
+            })
+            .expect("The memory doesn't hold a heap, or its header is damaged");
+
+        // Repairs the other copy if it was damaged
+        let mut heap = Heap { memory, header };
+        heap.save_header();
+        heap
+    }
+
+    // Copies `data` to a new chunk and returns its address
+    pub fn alloc(&mut self, data: &[u8]) -> u64 {
+        self.alloc_with_capacity(data, data.len() as u64)
+    }
+
+    // Same as alloc, with room for `capacity` bytes so the chunk can be
+    // rewritten with more data later
+    pub fn alloc_with_capacity(&mut self, data: &[u8], capacity: u64) -> u64 {
+        let size = chunk_size(capacity.max(data.len() as u64) + CHUNK_HEADER_SIZE);
+
+        let ptr = if size > 1 << MAX_CLASS {
+            self.alloc_large(size)
+        } else {
+            let class = size.trailing_zeros();
+            match self.header.free_lists[class_index(class)] {
+                0 => self.bump(size),
+                ptr => {
+                    // The next free chunk is kept where the data goes
+                    self.header.free_lists[class_index(class)] = self.next_free(ptr);
+                    ptr
+                }
+            }
+        };
+        self.save_header();
+
+        self.write_u64(ptr, size);
+        self.write(ptr, data);
+        ptr
+    }
+
+    // Replaces the data of a chunk, which must have room for it
+    pub fn write(&mut self, ptr: u64, data: &[u8]) {
+        assert!(
+            data.len() as u64 + CHUNK_HEADER_SIZE <= self.read_u64(ptr),
+            "The data doesn't fit in the chunk"
+        );
+
+        self.write_u64(ptr + 8, data.len() as u64);
+        self.memory.write(ptr + CHUNK_HEADER_SIZE, data);
+    }
+
+    pub fn read(&self, ptr: u64) -> Vec<u8> {
+        let len = self.read_u64(ptr + 8);
+        let mut data = vec![0; len as usize];
+        self.memory.read(ptr + CHUNK_HEADER_SIZE, &mut data);
+        data
+    }
+
+    pub fn free(&mut self, ptr: u64) {
+        let size = self.read_u64(ptr);
+
+        if size > 1 << MAX_CLASS {
+            self.free_large(ptr, size);
+        } else {
+            let free_list = &mut self.header.free_lists[class_index(size.trailing_zeros())];
+            let next = std::mem::replace(free_list, ptr);
+            self.set_next_free(ptr, next);
+        }
+        self.save_header();
+    }
+
+    pub fn root(&self, slot: usize) -> u64 {
+        self.header.roots[slot]
+    }
+
+    pub fn set_root(&mut self, slot: usize, value: u64) {
+        self.header.roots[slot] = value;
+        self.save_header();
+    }
+
+    // Bytes of memory taken by the heap, free chunks included
+    pub fn size(&self) -> u64 {
+        self.header.bump
+    }
+
+    fn bump(&mut self, size: u64) -> u64 {
+        let ptr = self.header.bump;
+        self.ensure_size(ptr + size);
+        self.header.bump = ptr + size;
+        ptr
+    }
+
+    fn alloc_large(&mut self, size: u64) -> u64 {
+        let mut previous = 0;
+        let mut ptr = self.header.large_free_list;
+
+        while ptr != 0 {
+            let free_size = self.read_u64(ptr);
+            if free_size >= size {
+                // The rest of the chunk takes its place in the list
+                let next = if free_size > size {
+                    let rest = ptr + size;
+                    self.write_u64(rest, free_size - size);
+                    self.set_next_free(rest, self.next_free(ptr));
+                    rest
+                } else {
+                    self.next_free(ptr)
+                };
+                self.set_next_large_free(previous, next);
+                return ptr;
+            }
+            previous = ptr;
+            ptr = self.next_free(ptr);
+        }
+        self.bump(size)
+    }
+
+    fn free_large(&mut self, mut ptr: u64, mut size: u64) {
+        let mut previous = 0;
+        let mut next = self.header.large_free_list;
+        while next != 0 && next < ptr {
+            previous = next;
+            next = self.next_free(next);
+        }
+
+        if next != 0 && ptr + size == next {
+            size += self.read_u64(next);
+            next = self.next_free(next);
+        }
+        if previous != 0 && previous + self.read_u64(previous) == ptr {
+            size += self.read_u64(previous);
+            ptr = previous;
+        } else {
+            self.set_next_large_free(previous, ptr);
+        }
+
+        // A free chunk at the end goes back to the unused memory, where
+        // chunks of any size can take it
+        if ptr + size == self.header.bump {
+            self.header.bump = ptr;
+            let before = if ptr == previous {
+                self.previous_large_free(ptr)
+            } else {
+                previous
+            };
+            self.set_next_large_free(before, 0);
+        } else {
+            self.write_u64(ptr, size);
+            self.set_next_free(ptr, next);
+        }
+    }
+
+    fn previous_large_free(&self, ptr: u64) -> u64 {
+        let mut previous = 0;
+        let mut current = self.header.large_free_list;
+        while current != ptr {
+            previous = current;
+            current = self.next_free(current);
+        }
+        previous
+    }
+
+    // Links the free chunk `previous`, or the head of the list if it's 0, to
+    // `next`
+    fn set_next_large_free(&mut self, previous: u64, next: u64) {
+        if previous == 0 {
+            self.header.large_free_list = next;
+        } else {
+            self.set_next_free(previous, next);
+        }
+    }
+
+    fn next_free(&self, ptr: u64) -> u64 {
+        self.read_u64(ptr + CHUNK_HEADER_SIZE)
+    }
+
+    fn set_next_free(&mut self, ptr: u64, next: u64) {
+        self.write_u64(ptr + CHUNK_HEADER_SIZE, next);
+    }
+
+    fn save_header(&mut self) {
+        let bytes = self.header.encode();
+        for &offset in HEADER_COPIES.iter() {
+            self.memory.write(offset, &bytes);
+        }
+    }
+
+    fn ensure_size(&mut self, bytes: u64) {
+        let pages = bytes.div_ceil(WASM_PAGE_SIZE);
+        let size = self.memory.size();
+
+        if pages > size && self.memory.grow(pages - size) < 0 {
+            panic!("Out of stable memory");
+        }
+    }
+
+    fn read_u64(&self, offset: u64) -> u64 {
+        let mut bytes = [0; 8];
+        self.memory.read(offset, &mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn write_u64(&mut self, offset: u64, value: u64) {
+        self.memory.write(offset, &value.to_le_bytes());
+    }
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(MAGIC);
+        let numbers = std::iter::once(&self.bump)
+            .chain(self.free_lists.iter())
+            .chain(std::iter::once(&self.large_free_list))
+            .chain(self.roots.iter());
+        for n in numbers {
+            bytes.extend_from_slice(&n.to_le_bytes());
+        }
+        bytes.extend_from_slice(&checksum(&bytes).to_le_bytes());
+        bytes
+    }
+
+    // None if it isn't a header, or a damaged one
+    fn decode(bytes: &[u8; HEADER_LEN]) -> Option<Header> {
+        let (content, sum) = bytes.split_at(HEADER_LEN - 8);
+        if &content[..8] != MAGIC || checksum(content).to_le_bytes() != sum {
+            return None;
+        }
+
+        let mut numbers = content[8..]
+            .chunks_exact(8)
+            .map(|n| u64::from_le_bytes(n.try_into().unwrap()));
+        let mut header = Header {
+            bump: numbers.next()?,
+            ..Default::default()
+        };
+        for free_list in header.free_lists.iter_mut() {
+            *free_list = numbers.next()?;
+        }
+        header.large_free_list = numbers.next()?;
+        for root in header.roots.iter_mut() {
+            *root = numbers.next()?;
+        }
+        Some(header)
+    }
+}
+
+// FNV-1a
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// The next power of two for small chunks, whole pages for large ones
+fn chunk_size(bytes: u64) -> u64 {
+    if bytes > 1 << MAX_CLASS {
+        bytes.div_ceil(LARGE_CHUNK_SIZE) * LARGE_CHUNK_SIZE
+    } else {
+        bytes.next_power_of_two().max(1 << MIN_CLASS)
+    }
+}
+
+fn class_index(class: u32) -> usize {
+    (class - MIN_CLASS) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::VectorMemory;
+    use std::collections::HashMap;
+
+    #[test]
+    fn alloc_and_free() {
+        let memory = VectorMemory::default();
+        let mut heap = Heap::open(Box::new(memory.clone()));
+
+        let a = heap.alloc(b"rabbit");
+        let b = heap.alloc(&[7; 100]);
+        assert_eq!(heap.read(a), b"rabbit");
+        assert_eq!(heap.read(b), vec![7; 100]);
+        assert_eq!(heap.size(), HEADER_SIZE + 64 + 128);
+
+        // Freed chunks are reused for data of the same size class
+        heap.free(a);
+        let c = heap.alloc(b"fox");
+        assert_eq!(c, a);
+        assert_eq!(heap.read(c), b"fox");
+        heap.write(c, b"badger");
+        assert_eq!(heap.read(c), b"badger");
+
+        // Everything is in the memory
+        heap.set_root(3, b);
+        let heap = Heap::open(Box::new(memory));
+        assert_eq!(heap.root(3), b);
+        assert_eq!(heap.read(b), vec![7; 100]);
+        assert_eq!(heap.read(c), b"badger");
+    }
+
+    #[test]
+    fn large_chunks() {
+        let mut heap = Heap::open(Box::new(VectorMemory::default()));
+        let small = heap.alloc(b"rabbit");
+        let start = heap.size();
+
+        // Rounded up to pages, not to the next power of two
+        let a = heap.alloc(&[1; 300_000]);
+        let b = heap.alloc(&[2; 100_000]);
+        let c = heap.alloc(&[3; 100_000]);
+        let end = heap.size();
+        assert_eq!(end - start, 9 * WASM_PAGE_SIZE);
+
+        // A smaller chunk takes the start of a free one
+        heap.free(a);
+        let d = heap.alloc(&[4; 100_000]);
+        assert_eq!(d, a);
+
+        // Free neighbours are merged, so the space of several chunks can be
+        // handed out at once
+        heap.free(b);
+        let e = heap.alloc(&[5; 300_000]);
+        assert_eq!(e, d + 2 * WASM_PAGE_SIZE);
+        assert_eq!(heap.size(), end);
+
+        // The chunk at the end goes back to the unused memory, and the free
+        // chunks before it with it
+        heap.free(e);
+        heap.free(c);
+        assert_eq!(heap.size(), a + 2 * WASM_PAGE_SIZE);
+        heap.free(d);
+        assert_eq!(heap.size(), start);
+        assert_eq!(heap.header.large_free_list, 0);
+
+        let f = heap.alloc(&[6; 64]);
+        assert_eq!(f, start);
+        assert_eq!(heap.read(small), b"rabbit");
+        assert_eq!(heap.read(f), vec![6; 64]);
+    }
+
+    // Every change is written to the memory as it's made, so a heap opened
+    // again at any point, as after an upgrade, is the same
+    #[test]
+    fn reopened_after_every_change() {
+        let memory = VectorMemory::default();
+        let mut heap = Heap::open(Box::new(memory.clone()));
+        let mut expected: HashMap<u64, Vec<u8>> = HashMap::new();
+
+        let mut n: u64 = 1;
+        for i in 0..300 {
+            n = n
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+
+            if n.is_multiple_of(3) && !expected.is_empty() {
+                let ptr = *expected
+                    .keys()
+                    .nth((n >> 8) as usize % expected.len())
+                    .unwrap();
+                heap.free(ptr);
+                expected.remove(&ptr);
+            } else {
+                // Mostly small chunks, with a large one now and then
+                let len = if n.is_multiple_of(17) {
+                    70_000 + n % 50_000
+                } else {
+                    n % 3000
+                };
+                let data = vec![i as u8; len as usize];
+                let ptr = heap.alloc(&data);
+                assert!(expected.insert(ptr, data).is_none());
+            }
+            heap.set_root(0, i);
+
+            heap = Heap::open(Box::new(memory.clone()));
+            assert_eq!(heap.root(0), i);
+            for (ptr, data) in expected.iter() {
+                assert_eq!(&heap.read(*ptr), data);
+            }
+        }
+    }
+
+    // A call that traps leaves the memory as it was before the call, e.g.
+    // when post_upgrade fails and the canister goes back to its old version
+    #[test]
+    fn interrupted_call() {
+        let memory = VectorMemory::default();
+        let mut heap = Heap::open(Box::new(memory.clone()));
+        let a = heap.alloc(b"rabbit");
+        heap.set_root(0, a);
+        let before = memory.snapshot();
+
+        heap.free(a);
+        let b = heap.alloc(&[1; 100_000]);
+        heap.set_root(0, b);
+
+        let mut heap = Heap::open(Box::new(before));
+        assert_eq!(heap.root(0), a);
+        assert_eq!(heap.read(a), b"rabbit");
+        let c = heap.alloc(b"fox");
+        assert_ne!(c, a);
+        assert_eq!(heap.read(a), b"rabbit");
+    }
+
+    #[test]
+    fn damaged_header() {
+        let memory = VectorMemory::default();
+        let mut heap = Heap::open(Box::new(memory.clone()));
+        let a = heap.alloc(b"rabbit");
+        heap.set_root(0, a);
+
+        // The other copy takes over, and the damaged one is repaired
+        memory.write(8, &[0xff; 16]);
+        let heap = Heap::open(Box::new(memory.clone()));
+        assert_eq!(heap.root(0), a);
+        assert_eq!(heap.read(a), b"rabbit");
+        let mut bytes = [0; HEADER_LEN];
+        memory.read(0, &mut bytes);
+        assert_eq!(Header::decode(&bytes), Some(heap.header.clone()));
+
+        memory.write(HEADER_COPIES[1] + 8, &[0xff; 16]);
+        let heap = Heap::open(Box::new(memory));
+        assert_eq!(heap.root(0), a);
+    }
+
+    #[test]
+    #[should_panic(expected = "its header is damaged")]
+    fn both_headers_damaged() {
+        let memory = VectorMemory::default();
+        let mut heap = Heap::open(Box::new(memory.clone()));
+        heap.alloc(b"rabbit");
+
+        memory.write(8, &[0xff; 16]);
+        memory.write(HEADER_COPIES[1] + 8, &[0xff; 16]);
+        assert!(Heap::is_initialized(&memory));
+        Heap::open(Box::new(memory));
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't hold a heap")]
+    fn opening_other_data() {
+        let memory = VectorMemory::default();
+        memory.grow(1);
+        memory.write(0, &[1; 1024]);
+        Heap::open(Box::new(memory));
+    }
+
+    #[test]
+    fn overwrites_other_data() {
+        let memory = VectorMemory::default();
+        memory.grow(1);
+        memory.write(0, &[1; 1024]);
+        assert!(!Heap::is_initialized(&memory));
+
+        let heap = Heap::new(Box::new(memory.clone()));
+        assert!(Heap::is_initialized(&memory));
+        assert_eq!(heap.root(0), 0);
+        assert_eq!(heap.size(), HEADER_SIZE);
+    }
+}
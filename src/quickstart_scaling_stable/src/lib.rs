@@ -0,0 +1,10 @@
+// Data structures that live in stable memory instead of the heap. They are
+// read and written in place, so nothing has to be serialized on upgrades and
+// their size isn't bounded by the wasm heap.
+mod btree;
+mod heap;
+mod memory;
+
+pub use btree::{BTreeMap, Iter};
+pub use heap::Heap;
+pub use memory::{Memory, VectorMemory, WASM_PAGE_SIZE};
@@ -0,0 +1,51 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const WASM_PAGE_SIZE: u64 = 65536;
+
+// Byte addressed memory that grows by wasm pages, like a canister's stable
+// memory
+pub trait Memory {
+    // In pages
+    fn size(&self) -> u64;
+    // Returns the previous size, or -1 if the memory can't grow
+    fn grow(&self, pages: u64) -> i64;
+    fn read(&self, offset: u64, dst: &mut [u8]);
+    fn write(&self, offset: u64, src: &[u8]);
+}
+
+// Memory on the heap, for tests. Clones share the same bytes, so a clone can
+// be opened again to see what survives an upgrade.
+#[derive(Clone, Default)]
+pub struct VectorMemory(Rc<RefCell<Vec<u8>>>);
+
+impl VectorMemory {
+    // A copy that doesn't share the bytes, to go back to the memory as it was
+    // before a call that traps
+    pub fn snapshot(&self) -> VectorMemory {
+        VectorMemory(Rc::new(RefCell::new(self.0.borrow().clone())))
+    }
+}
+
+impl Memory for VectorMemory {
+    fn size(&self) -> u64 {
+        self.0.borrow().len() as u64 / WASM_PAGE_SIZE
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        let size = self.size();
+        let mut bytes = self.0.borrow_mut();
+        bytes.resize(((size + pages) * WASM_PAGE_SIZE) as usize, 0);
+        size as i64
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let start = offset as usize;
+        dst.copy_from_slice(&self.0.borrow()[start..start + dst.len()]);
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        let start = offset as usize;
+        self.0.borrow_mut()[start..start + src.len()].copy_from_slice(src);
+    }
+}
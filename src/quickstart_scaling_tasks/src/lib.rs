@@ -0,0 +1,361 @@
+use candid::CandidType;
+use serde::Deserialize;
+
+// Same as the canisters' TimestampMillis, IC time in nanoseconds
+pub type TimestampMillis = u64;
+
+// Only the most recent dead tasks are kept
+pub const MAX_DEAD_TASKS: usize = 100;
+
+// How a kind of task is retried
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    // How long an attempt owns the task. An attempt that doesn't report back
+    // in time (e.g. it trapped after an await) counts as failed.
+    pub lease: TimestampMillis,
+    // The wait after the first failure, doubled after every other one
+    pub base_backoff: TimestampMillis,
+    pub max_backoff: TimestampMillis,
+    // Failed tasks go to the dead letters after this many attempts. None
+    // retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl RetryPolicy {
+    pub fn backoff(&self, attempts: u32) -> TimestampMillis {
+        let doublings = attempts.saturating_sub(1).min(32);
+        self.base_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff)
+    }
+}
+
+// Implemented by the task enum of each canister
+pub trait TaskKind: Clone + PartialEq {
+    fn retry_policy(&self) -> RetryPolicy;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Lease {
+    pub id: u64,
+    pub expires_at: TimestampMillis,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Task<T> {
+    pub id: u64,
+    pub kind: T,
+    // Attempts started so far
+    pub attempts: u32,
+    pub next_attempt_at: TimestampMillis,
+    pub lease: Option<Lease>,
+    pub last_error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct DeadTask<T> {
+    pub task: Task<T>,
+    pub died_at: TimestampMillis,
+}
+
+// What an attempt gets when it takes a task. Its result is only recorded
+// while the lease is still its own.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LeasedTask<T> {
+    pub task_id: u64,
+    pub lease_id: u64,
+    pub kind: T,
+    pub attempt: u32,
+}
+
+// Async work that has to survive traps, lost calls and upgrades. Tasks are
+// leased by one attempt at a time, failed attempts are retried with an
+// exponential backoff, and tasks that keep failing end up in the dead letters
+// where an admin can look at them and retry them.
+//
+// The queue is plain data, it's persisted across upgrades with the rest of
+// the canister state.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TaskQueue<T> {
+    tasks: Vec<Task<T>>,
+    dead_tasks: Vec<DeadTask<T>>,
+    next_task_id: u64,
+    next_lease_id: u64,
+}
+
+impl<T> Default for TaskQueue<T> {
+    fn default() -> Self {
+        TaskQueue {
+            tasks: vec![],
+            dead_tasks: vec![],
+            next_task_id: 0,
+            next_lease_id: 0,
+        }
+    }
+}
+
+impl<T: TaskKind> TaskQueue<T> {
+    // A task that is already waiting for an attempt isn't added twice. One
+    // that is being worked on is, its attempt may have started before the
+    // reason for the new task. The new task isn't leased before that attempt
+    // is over.
+    pub fn push(&mut self, kind: T) -> u64 {
+        if let Some(task) = self
+            .tasks
+            .iter()
+            .find(|t| t.kind == kind && t.lease.is_none())
+        {
+            return task.id;
+        }
+
+        let id = self.next_task_id;
+        self.next_task_id += 1;
+        self.tasks.push(Task {
+            id,
+            kind,
+            attempts: 0,
+            next_attempt_at: 0,
+            lease: None,
+            last_error: None,
+        });
+        id
+    }
+
+    // Leases the oldest task that is due and accepted by `filter`, unless a
+    // task of the same kind is leased already. Leases that expired are
+    // counted as failed attempts first.
+    pub fn lease_next(
+        &mut self,
+        now: TimestampMillis,
+        filter: impl Fn(&T) -> bool,
+    ) -> Option<LeasedTask<T>> {
+        self.expire_leases(now);
+
+        let lease_id = self.next_lease_id;
+        let leased_kinds: Vec<T> = self
+            .tasks
+            .iter()
+            .filter(|t| t.lease.is_some())
+            .map(|t| t.kind.clone())
+            .collect();
+        let task = self.tasks.iter_mut().find(|t| {
+            t.lease.is_none()
+                && t.next_attempt_at <= now
+                && !leased_kinds.contains(&t.kind)
+                && filter(&t.kind)
+        })?;
+
+        task.attempts += 1;
+        task.lease = Some(Lease {
+            id: lease_id,
+            expires_at: now + task.kind.retry_policy().lease,
+        });
+        self.next_lease_id += 1;
+
+        Some(LeasedTask {
+            task_id: task.id,
+            lease_id,
+            kind: task.kind.clone(),
+            attempt: task.attempts,
+        })
+    }
+
+    pub fn holds_lease(&self, leased: &LeasedTask<T>) -> bool {
+        self.position(leased).is_some()
+    }
+
+    // Returns false if the lease was lost, the task then belongs to another attempt
+    pub fn complete(&mut self, leased: &LeasedTask<T>) -> bool {
+        match self.position(leased) {
+            Some(i) => {
+                self.tasks.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Schedules the next attempt, or moves the task to the dead letters when it
+    // has no attempts left. Returns false if the lease was lost.
+    pub fn fail(&mut self, leased: &LeasedTask<T>, error: String, now: TimestampMillis) -> bool {
+        match self.position(leased) {
+            Some(i) => {
+                self.fail_task(i, error, now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn tasks(&self) -> &[Task<T>] {
+        &self.tasks
+    }
+
+    pub fn dead_tasks(&self) -> &[DeadTask<T>] {
+        &self.dead_tasks
+    }
+
+    pub fn find(&self, kind: &T) -> Option<&Task<T>> {
+        self.tasks.iter().find(|t| &t.kind == kind)
+    }
+
+    // Puts a dead task back in the queue with a fresh set of attempts
+    pub fn retry_dead(&mut self, task_id: u64) -> bool {
+        match self.dead_tasks.iter().position(|d| d.task.id == task_id) {
+            Some(i) => {
+                let mut task = self.dead_tasks.remove(i).task;
+                task.attempts = 0;
+                task.next_attempt_at = 0;
+                task.lease = None;
+                self.tasks.push(task);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn position(&self, leased: &LeasedTask<T>) -> Option<usize> {
+        self.tasks.iter().position(|t| {
+            t.id == leased.task_id && t.lease.as_ref().map(|l| l.id) == Some(leased.lease_id)
+        })
+    }
+
+    fn expire_leases(&mut self, now: TimestampMillis) {
+        let expired: Vec<u64> = self
+            .tasks
+            .iter()
+            .filter(|t| matches!(&t.lease, Some(lease) if lease.expires_at <= now))
+            .map(|t| t.id)
+            .collect();
+
+        for id in expired {
+            if let Some(i) = self.tasks.iter().position(|t| t.id == id) {
+                self.fail_task(i, "The lease expired".to_string(), now);
+            }
+        }
+    }
+
+    fn fail_task(&mut self, i: usize, error: String, now: TimestampMillis) {
+        let task = &mut self.tasks[i];
+        let policy = task.kind.retry_policy();
+
+        task.lease = None;
+        task.last_error = Some(error);
+
+        if policy.max_attempts.is_some_and(|max| task.attempts >= max) {
+            let task = self.tasks.remove(i);
+            if self.dead_tasks.len() == MAX_DEAD_TASKS {
+                self.dead_tasks.remove(0);
+            }
+            self.dead_tasks.push(DeadTask { task, died_at: now });
+        } else {
+            task.next_attempt_at = now + policy.backoff(task.attempts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum TestTask {
+        Retried,
+        Once,
+    }
+
+    impl TaskKind for TestTask {
+        fn retry_policy(&self) -> RetryPolicy {
+            RetryPolicy {
+                lease: 100,
+                base_backoff: 10,
+                max_backoff: 25,
+                max_attempts: match self {
+                    TestTask::Retried => None,
+                    TestTask::Once => Some(1),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn lease_and_complete() {
+        let mut queue = TaskQueue::default();
+
+        let id = queue.push(TestTask::Retried);
+        assert_eq!(queue.push(TestTask::Retried), id);
+
+        let leased = queue.lease_next(0, |_| true).unwrap();
+        assert_eq!(leased.task_id, id);
+        assert_eq!(leased.attempt, 1);
+        assert!(queue.lease_next(0, |_| true).is_none());
+
+        // A leased task doesn't absorb new ones, they wait for its attempt
+        let next = queue.push(TestTask::Retried);
+        assert_ne!(next, id);
+        assert!(queue.lease_next(0, |_| true).is_none());
+        assert!(queue.lease_next(0, |k| *k == TestTask::Once).is_none());
+
+        // Other kinds aren't held up
+        queue.push(TestTask::Once);
+        assert_eq!(queue.lease_next(0, |_| true).unwrap().kind, TestTask::Once);
+
+        assert!(queue.complete(&leased));
+        assert!(!queue.complete(&leased));
+        assert_eq!(queue.lease_next(0, |_| true).unwrap().task_id, next);
+    }
+
+    #[test]
+    fn backoff_and_expired_leases() {
+        let mut queue = TaskQueue::default();
+        queue.push(TestTask::Retried);
+
+        let leased = queue.lease_next(0, |_| true).unwrap();
+        assert!(queue.fail(&leased, "Call failed".to_string(), 5));
+        assert_eq!(queue.tasks()[0].next_attempt_at, 15);
+        assert!(queue.lease_next(14, |_| true).is_none());
+
+        let leased = queue.lease_next(15, |_| true).unwrap();
+        queue.fail(&leased, "Call failed".to_string(), 20);
+        assert_eq!(queue.tasks()[0].next_attempt_at, 40);
+
+        // Capped
+        let stuck = queue.lease_next(40, |_| true).unwrap();
+        assert_eq!(stuck.attempt, 3);
+
+        // The lease expires, the task is retried and the stuck attempt can't
+        // report anymore
+        assert!(queue.lease_next(139, |_| true).is_none());
+        assert!(queue.lease_next(140, |_| true).is_none());
+        assert_eq!(
+            queue.tasks()[0].last_error,
+            Some("The lease expired".to_string())
+        );
+        assert_eq!(queue.tasks()[0].next_attempt_at, 165);
+
+        let leased = queue.lease_next(165, |_| true).unwrap();
+        assert!(!queue.holds_lease(&stuck));
+        assert!(!queue.complete(&stuck));
+        assert!(queue.holds_lease(&leased));
+        assert!(queue.complete(&leased));
+        assert!(queue.tasks().is_empty());
+    }
+
+    #[test]
+    fn dead_letters() {
+        let mut queue = TaskQueue::default();
+        let id = queue.push(TestTask::Once);
+
+        let leased = queue.lease_next(0, |_| true).unwrap();
+        queue.fail(&leased, "Call failed".to_string(), 5);
+        assert!(queue.tasks().is_empty());
+        assert_eq!(queue.dead_tasks().len(), 1);
+        assert_eq!(queue.dead_tasks()[0].died_at, 5);
+        assert_eq!(queue.dead_tasks()[0].task.id, id);
+
+        assert!(!queue.retry_dead(id + 1));
+        assert!(queue.retry_dead(id));
+        assert!(queue.dead_tasks().is_empty());
+        assert_eq!(queue.lease_next(5, |_| true).unwrap().attempt, 1);
+    }
+}
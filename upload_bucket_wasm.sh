@@ -0,0 +1,30 @@
+#!/bin/bash
+# Uploads the Bucket wasm built by first_time.sh to the Index canister and
+# makes it the active version. Usage: ./upload_bucket_wasm.sh <version name> [--no-activate]
+
+set -e
+
+NAME=${1:?Usage: ./upload_bucket_wasm.sh <version name>}
+WASM=target/wasm32-unknown-unknown/release/quickstart_scaling_bucket-opt.wasm
+# Small enough to keep the dfx command line under the argument size limit
+CHUNK_SIZE=100000
+
+# Candid blob literals are written as escaped hex bytes, e.g. "\de\ad"
+to_blob() {
+    xxd -p | tr -d '\n' | sed 's/../\\&/g'
+}
+
+SHA256=$(sha256sum $WASM | cut -d' ' -f1 | xxd -r -p | to_blob)
+dfx canister call quickstart_scaling_index startBucketWasmUpload "(\"$NAME\", blob \"$SHA256\")"
+
+SIZE=$(stat -c%s $WASM)
+CHUNKS=$(( (SIZE + CHUNK_SIZE - 1) / CHUNK_SIZE ))
+for ((i = 0; i < CHUNKS; i++)); do
+    CHUNK=$(dd if=$WASM bs=$CHUNK_SIZE skip=$i count=1 2>/dev/null | to_blob)
+    dfx canister call quickstart_scaling_index uploadBucketWasmChunk "(\"$NAME\", blob \"$CHUNK\")"
+done
+
+dfx canister call quickstart_scaling_index commitBucketWasmUpload "(\"$NAME\")"
+if [ "$2" != "--no-activate" ]; then
+    dfx canister call quickstart_scaling_index setActiveBucketWasm "(\"$NAME\")"
+fi